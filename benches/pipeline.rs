@@ -0,0 +1,157 @@
+// benches/pipeline.rs
+// Throughput of the write stage: decode N Transfer logs and insert them into a
+// fresh in-memory DB, as `indexer::write_logs` does for every fetched range;
+// plus the two DB choices under it, batched inserts and cached statements.
+// Run with `cargo bench --bench pipeline`.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use polygon_indexer::parser::ValueLayout;
+use polygon_indexer::{config, db, indexer, parser, rpc};
+use rust_decimal::Decimal;
+use rusqlite::Connection;
+use std::hint::black_box;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+const TOKEN: &str = "0x0000000000000000000000000000000000001010";
+const EXCHANGE: &str = "0xe7804c37c13166fF0b37F5aE0BB07A3aEbb6e245";
+const SIZES: [u64; 3] = [100, 1_000, 10_000];
+
+/// `n` inflows of 1.5 tokens (18 decimals) into `EXCHANGE`, ten per block
+fn inflow_logs(n: u64) -> Vec<rpc::Log> {
+    (0..n)
+        .map(|i| rpc::Log {
+            address: TOKEN.to_string(),
+            topics: vec![
+                rpc::TRANSFER_TOPIC.to_string(),
+                format!("0x{:064x}", 0xabc + i % 100),
+                format!("0x{:0>64}", EXCHANGE.trim_start_matches("0x")),
+            ],
+            data: format!("0x{:064x}", 1_500_000_000_000_000_000u128),
+            block_number_hex: format!("0x{:x}", 1_000 + i / 10),
+            tx_hash: format!("0x{:064x}", i),
+            log_index_hex: format!("0x{:x}", i % 10),
+            removed: false,
+            block_timestamp: Some(1_700_000_000 + i / 10 * 2),
+        })
+        .collect()
+}
+
+fn fresh_db() -> Mutex<Connection> {
+    let mut conn = Connection::open_in_memory().unwrap();
+    db::run_migrations(&mut conn).unwrap();
+    Mutex::new(conn)
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for n in SIZES {
+        let logs = inflow_logs(n);
+        group.throughput(Throughput::Elements(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &logs, |b, logs| {
+            b.iter(|| logs.iter().filter_map(|log| parser::decode_log(log, ValueLayout::Auto)).count())
+        });
+    }
+    group.finish();
+}
+
+fn decode_and_insert(c: &mut Criterion) {
+    let mut cfg = config::Config::defaults();
+    cfg.exchange_set = [EXCHANGE.parse().unwrap()].into_iter().collect();
+    let (events, _) = broadcast::channel(1);
+
+    let mut group = c.benchmark_group("decode_and_insert");
+    for n in SIZES {
+        let logs = inflow_logs(n);
+        group.throughput(Throughput::Elements(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &logs, |b, logs| {
+            b.iter_batched(
+                || (fresh_db(), logs.clone()),
+                |(conn, logs)| {
+                    let (written, _) = indexer::write_logs(&cfg, &conn, &events, TOKEN, 18.into(), logs).unwrap();
+                    assert_eq!(written as u64, n);
+                    black_box(conn)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn new_transfer(tx_hash: &str) -> db::NewTransfer<'_> {
+    db::NewTransfer {
+        chain_id: 137,
+        block_number: 100,
+        tx_hash,
+        log_index: 0,
+        token_address: TOKEN,
+        from: "0xfrom",
+        to: "0xto",
+        amount: Decimal::ONE,
+        direction: "IN",
+        exchange_address: Some("0xto"),
+        raw_amount: "1000000000000000000",
+        decimals: 18,
+        decimals_assumed: false,
+        block_timestamp: None,
+    }
+}
+
+/// `db::record_transfer` per row vs one `db::TransferWriter` for the batch
+fn insert(c: &mut Criterion) {
+    const N: u64 = 10_000;
+    let hashes: Vec<String> = (0..N).map(|i| format!("0x{:064x}", i)).collect();
+
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Elements(N));
+    group.bench_function("record_transfer", |b| {
+        b.iter_batched(
+            fresh_db,
+            |conn| {
+                let mut db = conn.lock().unwrap();
+                let tx = db.transaction().unwrap();
+                for h in &hashes {
+                    db::record_transfer(&tx, &new_transfer(h)).unwrap();
+                }
+                tx.commit().unwrap();
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("transfer_writer", |b| {
+        b.iter_batched(
+            fresh_db,
+            |conn| {
+                let mut db = conn.lock().unwrap();
+                let tx = db.transaction().unwrap();
+                let mut writer = db::TransferWriter::new(&tx).unwrap();
+                for h in &hashes {
+                    writer.push(&new_transfer(h)).unwrap();
+                }
+                drop(writer);
+                tx.commit().unwrap();
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Preparing an API read query per request vs the connection's statement cache
+fn statement_cache(c: &mut Criterion) {
+    const SQL: &str = "SELECT tx_hash, block_number, amount FROM transfers
+                       WHERE LOWER(token_address) = ?1 ORDER BY amount_num DESC LIMIT ?2";
+    let conn = fresh_db().into_inner().unwrap();
+    for i in 0..100 {
+        db::record_transfer(&conn, &new_transfer(&format!("0x{:064x}", i))).unwrap();
+    }
+    let rows = |stmt: &mut rusqlite::Statement| stmt.query_map((TOKEN, 10), |r| r.get::<_, String>(0)).unwrap().count();
+
+    let mut group = c.benchmark_group("statement_cache");
+    group.bench_function("prepare", |b| b.iter(|| rows(&mut conn.prepare(SQL).unwrap())));
+    group.bench_function("prepare_cached", |b| b.iter(|| rows(&mut conn.prepare_cached(SQL).unwrap())));
+    group.finish();
+}
+
+criterion_group!(benches, decode, decode_and_insert, insert, statement_cache);
+criterion_main!(benches);
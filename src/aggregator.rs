@@ -1,54 +1,480 @@
 use rusqlite::{Connection, params};
-use eyre::Result;
+use crate::error::{IndexerError, Result};
 use rust_decimal::Decimal;
 use rust_decimal::prelude::FromStr;
-use tracing::info;
+use std::collections::BTreeMap;
+use tracing::{field, info, instrument, warn, Span};
+use crate::config::NetflowUnits;
+use crate::db;
+use crate::models::NetFlowDrift;
 
-pub fn update_netflows(conn: &Connection) -> Result<()> {
-    // Calculate inflows and outflows per token
-    let mut stmt = conn.prepare(
-        "
-        SELECT 
-            token_address,
-            COALESCE(SUM(CASE WHEN direction = 'IN' THEN CAST(amount AS REAL) ELSE 0 END), 0) as inflow,
-            COALESCE(SUM(CASE WHEN direction = 'OUT' THEN CAST(amount AS REAL) ELSE 0 END), 0) as outflow,
-            MAX(block_number) as last_block
-        FROM transfers
-        GROUP BY token_address
+/// Differences below this are rounding noise, not drift
+pub const VERIFY_EPSILON: Decimal = Decimal::from_parts(1, 0, 0, false, 9); // 1e-9
+
+/// Refresh every token's cached netflow from `transfers` (plus retention
+/// baselines) in `units`. Returns the new `(token, net)` pairs for alerting.
+pub fn update_netflows(conn: &Connection, units: NetflowUnits) -> Result<Vec<(String, Decimal)>> {
+    let tokens: Vec<String> = conn
+        .prepare("SELECT DISTINCT token_address FROM transfers WHERE chain_id = ?1")?
+        .query_map([chain(conn)?], |r| r.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    update_token_netflows(conn, units, &tokens)
+}
+
+/// The chain every aggregate is summed for (CHAIN_ID, as recorded at
+/// startup); other chains' transfers in the same DB never count
+fn chain(conn: &Connection) -> Result<i64> {
+    db::to_sql_int(db::chain_id(conn)?)
+}
+
+/// Run `f` inside a SAVEPOINT, so a refresh's rewrites land together or not
+/// at all, whether or not the caller already opened a transaction
+fn atomically<T>(conn: &Connection, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    conn.execute_batch("SAVEPOINT netflows")?;
+    match f() {
+        Ok(value) => {
+            conn.execute_batch("RELEASE netflows")?;
+            Ok(value)
+        }
+        Err(e) => {
+            if let Err(rollback) = conn.execute_batch("ROLLBACK TO netflows; RELEASE netflows") {
+                warn!("Netflow refresh rollback failed: {}", rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Refresh the cached netflows of `tokens` only (the ones a batch wrote), so
+/// each batch reads just those tokens' transfers. Returns the new
+/// `(token, net)` pairs for alerting.
+#[instrument(name = "update_netflows", skip_all, fields(units = units.as_str(), tokens = field::Empty))]
+pub fn update_token_netflows(conn: &Connection, units: NetflowUnits, tokens: &[String]) -> Result<Vec<(String, Decimal)>> {
+    let updated = atomically(conn, || refresh_tokens(conn, units, tokens))?;
+    Span::current().record("tokens", updated.len());
+    Ok(updated)
+}
+
+fn refresh_tokens(conn: &Connection, units: NetflowUnits, tokens: &[String]) -> Result<Vec<(String, Decimal)>> {
+    let chain = chain(conn)?;
+
+    let mut updated = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        // summed in Decimal on top of whatever retention has already deleted
+        let Some((inflow, outflow, last_block)) = exact_totals(conn, units, chain, Some(token))?.remove(token) else { continue };
+        let net = upsert_netflow(conn, chain, token, inflow, outflow, last_block, units)?;
+
+        info!("💾 Updated netflow for {} => {}", token, net);
+        updated.push((token.clone(), net));
+    }
+    update_exchange_netflows(conn, units, chain, Some(tokens))?;
+
+    Ok(updated)
+}
+
+/// Store `token`'s totals for `chain`, returning the net written
+fn upsert_netflow(
+    conn: &Connection,
+    chain: i64,
+    token: &str,
+    inflow: Decimal,
+    outflow: Decimal,
+    last_block: i64,
+    units: NetflowUnits,
+) -> Result<Decimal> {
+    let net = inflow - outflow;
+    conn.prepare_cached(
         "
-    )?;
+        INSERT INTO netflows (token_address, cumulative_net, inflow_total, outflow_total, last_block, updated_at, units, chain_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, datetime('now'), ?6, ?7)
+        ON CONFLICT(token_address) DO UPDATE SET
+            cumulative_net = excluded.cumulative_net,
+            inflow_total = excluded.inflow_total,
+            outflow_total = excluded.outflow_total,
+            last_block = excluded.last_block,
+            updated_at = excluded.updated_at,
+            units = excluded.units,
+            chain_id = excluded.chain_id
+        ",
+    )?
+    .execute(params![token, net.to_string(), inflow.to_string(), outflow.to_string(), last_block, units.as_str(), chain])?;
+    Ok(net)
+}
 
-    let rows = stmt.query_map([], |row| {
-        let token_address: String = row.get(0)?;
-        let inflow_f64: f64 = row.get(1).unwrap_or(0.0);
-        let outflow_f64: f64 = row.get(2).unwrap_or(0.0);
-        let last_block: i64 = row.get(3).unwrap_or(0);
+/// Recompute every token's netflow from scratch using exact `Decimal`
+/// summation over `transfers` (no f64 round-trip), upserting `netflows`.
+/// Rows cached for another chain (CHAIN_ID changed) are dropped.
+/// Returns the new `(token, net)` pairs.
+pub fn recompute_full(conn: &Connection, units: NetflowUnits) -> Result<Vec<(String, Decimal)>> {
+    atomically(conn, || {
+        let chain = chain(conn)?;
+        conn.execute("DELETE FROM netflows WHERE chain_id != ?1", [chain])?;
+        conn.execute("DELETE FROM netflows_by_exchange WHERE chain_id != ?1", [chain])?;
+        let totals = exact_totals(conn, units, chain, None)?;
 
-        // Convert f64 → Decimal for precision
-        let inflow = Decimal::from_str(&inflow_f64.to_string()).unwrap_or(Decimal::ZERO);
-        let outflow = Decimal::from_str(&outflow_f64.to_string()).unwrap_or(Decimal::ZERO);
+        let mut result = Vec::with_capacity(totals.len());
+        for (token, (inflow, outflow, last_block)) in totals {
+            let net = upsert_netflow(conn, chain, &token, inflow, outflow, last_block, units)?;
+            result.push((token, net));
+        }
+        update_exchange_netflows(conn, units, chain, None)?;
 
-        let net = inflow - outflow;
+        Ok(result)
+    })
+}
 
-        Ok((token_address, net, last_block))
-    })?;
+/// Rebuild `netflows_by_exchange` for `tokens` (every token when None): each
+/// token's IN/OUT transfers grouped by the exchange wallet they matched,
+/// summed exactly like the token's own netflow on top of that exchange's
+/// retention baseline. Pairs that no longer match any transfer (e.g. after the exchange
+/// set changed) are dropped. Returns the pair count.
+fn update_exchange_netflows(
+    conn: &Connection,
+    units: NetflowUnits,
+    chain: i64,
+    tokens: Option<&[String]>,
+) -> Result<usize> {
+    let in_scope = |token: &str| tokens.is_none_or(|tokens| tokens.iter().any(|t| t == token));
 
-    for row in rows {
-        let (token, net, last_block) = row?;
-        conn.execute(
+    // seeded with pruned history
+    let mut totals: ExchangeTotals = load_exchange_baselines(conn, units)?
+        .into_iter()
+        .filter(|((token, _), _)| in_scope(token))
+        .map(|(pair, (inflow, outflow))| (pair, (inflow, outflow, 0, 0)))
+        .collect();
+    let scoped: Vec<Option<&str>> = match tokens {
+        Some(tokens) => tokens.iter().map(|t| Some(t.as_str())).collect(),
+        None => vec![None],
+    };
+    for token in scoped {
+        for ((token, exchange), (inflow, outflow, transfers, last_block)) in exchange_totals(conn, units, chain, token)? {
+            let (base_in, base_out, _, _) = totals.get(&(token.clone(), exchange.clone())).copied().unwrap_or_default();
+            let sums = (add_amount(base_in, inflow, &token)?, add_amount(base_out, outflow, &token)?, transfers, last_block);
+            totals.insert((token, exchange), sums);
+        }
+    }
+
+    for ((token, exchange), (inflow, outflow, transfers, last_block)) in &totals {
+        conn.prepare_cached(
             "
-            INSERT INTO netflows (token_address, cumulative_net, last_block, updated_at)
-            VALUES (?1, ?2, ?3, datetime('now'))
-            ON CONFLICT(token_address) DO UPDATE SET
+            INSERT INTO netflows_by_exchange (token_address, exchange_address, cumulative_net, inflow_total,
+                                              outflow_total, transfers, last_block, updated_at, units, chain_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, datetime('now'), ?8, ?9)
+            ON CONFLICT(token_address, exchange_address) DO UPDATE SET
                 cumulative_net = excluded.cumulative_net,
+                inflow_total = excluded.inflow_total,
+                outflow_total = excluded.outflow_total,
+                transfers = excluded.transfers,
                 last_block = excluded.last_block,
-                updated_at = excluded.updated_at
+                updated_at = excluded.updated_at,
+                units = excluded.units,
+                chain_id = excluded.chain_id
             ",
-            params![token, net.to_string(), last_block],
+        )?
+        .execute(params![
+            token,
+            exchange,
+            (inflow - outflow).to_string(),
+            inflow.to_string(),
+            outflow.to_string(),
+            transfers,
+            last_block,
+            units.as_str(),
+            chain
+        ])?;
+    }
+
+    let stored: Vec<(String, String)> = conn
+        .prepare("SELECT token_address, exchange_address FROM netflows_by_exchange")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for pair in stored.iter().filter(|pair| in_scope(&pair.0) && !totals.contains_key(*pair)) {
+        conn.execute(
+            "DELETE FROM netflows_by_exchange WHERE token_address = ?1 AND exchange_address = ?2",
+            params![pair.0, pair.1],
         )?;
+    }
 
-        info!("💾 Updated netflow for {} => {}", token, net);
+    Ok(totals.len())
+}
+
+/// (token, exchange) → (inflow, outflow, transfers, last_block)
+type ExchangeTotals = BTreeMap<(String, String), (Decimal, Decimal, i64, i64)>;
+
+/// `ExchangeTotals` over the IN/OUT transfers that matched an exchange
+/// wallet, just `token`'s when given, adding up every row's amount in `units`
+fn exchange_totals(
+    conn: &Connection,
+    units: NetflowUnits,
+    chain: i64,
+    token: Option<&str>,
+) -> Result<ExchangeTotals> {
+    let filter = match token {
+        Some(_) => "AND token_address = ?2",
+        None => "",
+    };
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT token_address, exchange_address, direction, amount, raw_amount, decimals, block_number
+         FROM transfers
+         WHERE chain_id = ?1 AND direction IN ('IN', 'OUT') AND exchange_address IS NOT NULL {filter}",
+    ))?;
+    let read = |row: &rusqlite::Row<'_>| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, u32>(5)?,
+            row.get::<_, i64>(6)?,
+        ))
+    };
+    let rows = match token {
+        Some(token) => stmt.query_map(params![chain, token], read)?,
+        None => stmt.query_map([chain], read)?,
+    };
+
+    let mut totals = BTreeMap::new();
+    for row in rows {
+        let (token, exchange, direction, amount, raw_amount, decimals, block) = row?;
+        let amount = unit_amount(units, &amount, raw_amount.as_deref(), decimals)?;
+        let entry: &mut (Decimal, Decimal, i64, i64) = totals.entry((token.clone(), exchange)).or_default();
+        if direction == "IN" {
+            entry.0 = add_amount(entry.0, amount, &token)?;
+        } else {
+            entry.1 = add_amount(entry.1, amount, &token)?;
+        }
+        entry.2 += 1;
+        entry.3 = entry.3.max(block);
+    }
+    Ok(totals)
+}
+
+/// Compare each cached `netflows.cumulative_net` with an exact recomputation
+/// from `transfers`, logging every token whose difference exceeds
+/// `VERIFY_EPSILON`. Read-only; run `rebuild_netflows` to repair.
+pub fn verify(conn: &Connection, units: NetflowUnits) -> Result<Vec<NetFlowDrift>> {
+    let chain = chain(conn)?;
+    // None for a cached net that doesn't parse: reported as drift
+    let mut cached: BTreeMap<String, Option<Decimal>> = BTreeMap::new();
+    {
+        let mut stmt = conn.prepare("SELECT token_address, cumulative_net FROM netflows WHERE chain_id = ?1")?;
+        let rows = stmt.query_map([chain], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (token, net) = row?;
+            let parsed = Decimal::from_str(&net).ok();
+            if parsed.is_none() {
+                warn!("Cached netflow for {} doesn't parse: {:?}", token, net);
+            }
+            cached.insert(token, parsed);
+        }
     }
 
-    Ok(())
+    let mut recomputed: BTreeMap<String, Decimal> = exact_totals(conn, units, chain, None)?
+        .into_iter()
+        .map(|(token, (inflow, outflow, _))| (token, inflow - outflow))
+        .collect();
+
+    let mut report = Vec::with_capacity(cached.len().max(recomputed.len()));
+    for (token, cached_net) in cached {
+        // a cached row with no transfers left recomputes to zero
+        let recomputed_net = recomputed.remove(&token).unwrap_or(Decimal::ZERO);
+        report.push(drift(token, cached_net, recomputed_net));
+    }
+    for (token, recomputed_net) in recomputed {
+        report.push(drift(token, None, recomputed_net));
+    }
+    report.sort_by(|a, b| a.token_address.cmp(&b.token_address));
+
+    for d in report.iter().filter(|d| d.drift) {
+        warn!(
+            "Netflow drift for {}: cached {:?}, recomputed {}, diff {}",
+            d.token_address, d.cached, d.recomputed, d.diff
+        );
+    }
+    Ok(report)
+}
+
+fn drift(token_address: String, cached: Option<Decimal>, recomputed: Decimal) -> NetFlowDrift {
+    let diff = cached.unwrap_or(Decimal::ZERO) - recomputed;
+    NetFlowDrift {
+        token_address,
+        cached,
+        recomputed,
+        diff,
+        drift: cached.is_none() || diff.abs() > VERIFY_EPSILON,
+    }
+}
+
+/// Number of cached netflows computed in a unit other than `units` or for
+/// another chain (NETFLOW_UNITS or CHAIN_ID changed since); `recompute_full`
+/// brings them in line
+pub fn stale_units(conn: &Connection, units: NetflowUnits) -> Result<usize> {
+    let n: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM netflows WHERE units != ?1 OR chain_id != ?2",
+        params![units.as_str(), chain(conn)?],
+        |r| r.get(0),
+    )?;
+    Ok(n as usize)
+}
+
+/// One transfer's amount in `units`: the scaled `amount`, or the on-chain
+/// integer `raw_amount` (rebuilt as `amount * 10^decimals` for rows indexed
+/// before it was stored). A value that doesn't parse or fit is a Decode
+/// error rather than a silent zero.
+pub fn unit_amount(units: NetflowUnits, amount: &str, raw_amount: Option<&str>, decimals: u32) -> Result<Decimal> {
+    let scaled = || {
+        Decimal::from_str(amount).map_err(|e| IndexerError::Decode(format!("stored amount {:?}: {}", amount, e)))
+    };
+    match (units, raw_amount) {
+        (NetflowUnits::Token, _) => scaled(),
+        (NetflowUnits::Raw, Some(raw)) => Decimal::from_str(raw)
+            .map_err(|e| IndexerError::Decode(format!("stored raw amount {:?}: {}", raw, e))),
+        (NetflowUnits::Raw, None) => to_raw(scaled()?, decimals),
+    }
+}
+
+/// `amount * 10^decimals`, exact while it fits a Decimal
+fn to_raw(amount: Decimal, decimals: u32) -> Result<Decimal> {
+    (0..decimals)
+        .try_fold(amount, |acc, _| acc.checked_mul(Decimal::TEN))
+        .map(|d| d.normalize())
+        .ok_or_else(|| {
+            IndexerError::Decode(format!("{} with {} decimals does not fit a Decimal in raw units", amount, decimals))
+        })
+}
+
+/// `total + amount` for `token`'s netflow, or a Decode error if the sum
+/// no longer fits a Decimal (raw 18-decimal totals can get there)
+pub fn add_amount(total: Decimal, amount: Decimal, token: &str) -> Result<Decimal> {
+    total
+        .checked_add(amount)
+        .ok_or_else(|| IndexerError::Decode(format!("netflow total for {} overflows a Decimal", token)))
+}
+
+/// token → (inflow, outflow, last_block), summed exactly over `transfers`
+/// (just `token`'s when given) on top of the retention baselines
+fn exact_totals(
+    conn: &Connection,
+    units: NetflowUnits,
+    chain: i64,
+    token: Option<&str>,
+) -> Result<BTreeMap<String, (Decimal, Decimal, i64)>> {
+    let mut stmt = conn.prepare_cached(match token {
+        Some(_) => "SELECT token_address, direction, amount, block_number, raw_amount, decimals FROM transfers
+                    WHERE chain_id = ?1 AND token_address = ?2",
+        None => "SELECT token_address, direction, amount, block_number, raw_amount, decimals FROM transfers
+                 WHERE chain_id = ?1",
+    })?;
+
+    // token → (inflow, outflow, last_block), seeded with pruned history
+    let mut totals: BTreeMap<String, (Decimal, Decimal, i64)> = load_baselines(conn, units)?;
+    if let Some(token) = token {
+        totals.retain(|t, _| t == token);
+    }
+    let read = |row: &rusqlite::Row<'_>| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, u32>(5)?,
+        ))
+    };
+    let rows = match token {
+        Some(token) => stmt.query_map(params![chain, token], read)?,
+        None => stmt.query_map([chain], read)?,
+    };
+
+    for row in rows {
+        let (token, direction, amount, block, raw_amount, decimals) = row?;
+        let amount = unit_amount(units, &amount, raw_amount.as_deref(), decimals)?;
+        let entry = totals.entry(token.clone()).or_insert((Decimal::ZERO, Decimal::ZERO, 0));
+        match direction.as_str() {
+            "IN" => entry.0 = add_amount(entry.0, amount, &token)?,
+            "OUT" => entry.1 = add_amount(entry.1, amount, &token)?,
+            _ => {}
+        }
+        entry.2 = entry.2.max(block);
+    }
+
+    Ok(totals)
+}
+
+/// Per-(token, exchange) `(inflow, outflow)` carried over from pruned
+/// transfers, scaled like `load_baselines`
+fn load_exchange_baselines(
+    conn: &Connection,
+    units: NetflowUnits,
+) -> Result<BTreeMap<(String, String), (Decimal, Decimal)>> {
+    let mut stmt = conn.prepare(
+        "SELECT b.token_address, b.exchange_address, b.inflow, b.outflow,
+                COALESCE(
+                    (SELECT t.decimals FROM tokens t WHERE LOWER(t.address) = LOWER(b.token_address)),
+                    (SELECT tr.decimals FROM transfers tr WHERE tr.token_address = b.token_address LIMIT 1),
+                    ?1
+                )
+         FROM netflow_exchange_baselines b
+         WHERE b.chain_id = ?2",
+    )?;
+    let rows = stmt.query_map(params![db::default_decimals(conn)?, chain(conn)?], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, u32>(4)?,
+        ))
+    })?;
+
+    let mut baselines = BTreeMap::new();
+    for row in rows {
+        let (token, exchange, inflow, outflow, decimals) = row?;
+        baselines.insert(
+            (token, exchange),
+            (unit_amount(units, &inflow, None, decimals)?, unit_amount(units, &outflow, None, decimals)?),
+        );
+    }
+    Ok(baselines)
+}
+
+/// Per-token `(inflow, outflow, pruned_through_block)` carried over from
+/// this chain's transfers deleted by retention. Baselines are kept in token units and
+/// scaled by the token's decimals (`tokens`, else its transfers, else the
+/// recorded DEFAULT_DECIMALS) when `units` is raw.
+pub fn load_baselines(conn: &Connection, units: NetflowUnits) -> Result<BTreeMap<String, (Decimal, Decimal, i64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT b.token_address, b.inflow, b.outflow, b.pruned_through_block,
+                COALESCE(
+                    (SELECT t.decimals FROM tokens t WHERE LOWER(t.address) = LOWER(b.token_address)),
+                    (SELECT tr.decimals FROM transfers tr WHERE tr.token_address = b.token_address LIMIT 1),
+                    ?1
+                )
+         FROM netflow_baselines b
+         WHERE b.chain_id = ?2",
+    )?;
+    let rows = stmt.query_map(params![db::default_decimals(conn)?, chain(conn)?], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, u32>(4)?,
+        ))
+    })?;
+
+    let mut baselines = BTreeMap::new();
+    for row in rows {
+        let (token, inflow, outflow, block, decimals) = row?;
+        baselines.insert(
+            token,
+            (
+                unit_amount(units, &inflow, None, decimals)?,
+                unit_amount(units, &outflow, None, decimals)?,
+                block,
+            ),
+        );
+    }
+    Ok(baselines)
 }
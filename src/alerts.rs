@@ -0,0 +1,178 @@
+// src/alerts.rs
+// Netflow threshold alerts: after the aggregator refreshes netflows, a token
+// whose net outflow crosses its ALERT_RULES threshold is POSTed to WEBHOOK_URL
+use crate::config::Config;
+use crate::models::NetFlowAlert;
+use chrono::Utc;
+use reqwest::Client;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Give up on a webhook delivery after this long
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct RuleState {
+    breached: bool,             // above the threshold when last alerted
+    last_sent: Option<Instant>,
+}
+
+/// Edge-triggered alerts: one alert when the net outflow reaches the threshold,
+/// none while it stays there, and re-armed once it drops back below. Alerts for
+/// the same token are at least `cooldown` apart, so a net hovering around the
+/// threshold doesn't fire every block. State is in memory, so a token already
+/// past its threshold alerts once after a restart.
+#[derive(Debug, Clone)]
+pub struct Alerter {
+    rules: Arc<HashMap<String, Decimal>>,
+    webhook_url: Option<String>,
+    cooldown: Duration,
+    http: Client,
+    state: Arc<Mutex<HashMap<String, RuleState>>>,
+}
+
+impl Alerter {
+    pub fn new(cfg: &Config) -> Self {
+        if !cfg.alert_rules.is_empty() && cfg.webhook_url.is_none() {
+            warn!("ALERT_RULES is set without WEBHOOK_URL: alerts will only be logged");
+        }
+        Self {
+            rules: Arc::new(cfg.alert_rules.clone()),
+            webhook_url: cfg.webhook_url.clone(),
+            cooldown: Duration::from_secs(cfg.alert_cooldown_secs),
+            http: Client::builder().timeout(WEBHOOK_TIMEOUT).build().unwrap_or_default(),
+            state: Arc::default(),
+        }
+    }
+
+    /// Alerts due for the freshly updated `(token, net)` pairs, updating the
+    /// per-token debounce state
+    pub fn evaluate(&self, netflows: &[(String, Decimal)], now: Instant) -> Vec<NetFlowAlert> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut due = Vec::new();
+        for (token, net) in netflows {
+            let token = token.to_lowercase();
+            let Some(&threshold) = self.rules.get(&token) else { continue };
+            let entry = state.entry(token.clone()).or_default();
+
+            let net_outflow = -*net;
+            if net_outflow < threshold {
+                entry.breached = false; // re-arm
+                continue;
+            }
+            let cooled_down = entry.last_sent.is_none_or(|t| now.duration_since(t) >= self.cooldown);
+            if entry.breached || !cooled_down {
+                continue;
+            }
+
+            entry.breached = true;
+            entry.last_sent = Some(now);
+            due.push(NetFlowAlert {
+                token_address: token,
+                cumulative_net: *net,
+                net_outflow,
+                threshold,
+                triggered_at: Utc::now(),
+            });
+        }
+        due
+    }
+
+    /// Evaluate and deliver in the background; a failed POST is only logged
+    pub fn notify(&self, netflows: &[(String, Decimal)]) {
+        if self.rules.is_empty() {
+            return;
+        }
+        for alert in self.evaluate(netflows, Instant::now()) {
+            warn!("🚨 Net outflow {} for {} reached threshold {}",
+                alert.net_outflow, alert.token_address, alert.threshold);
+            let Some(url) = self.webhook_url.clone() else { continue };
+            let http = self.http.clone();
+            tokio::spawn(async move {
+                match http.post(&url).json(&alert).send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => info!("Alert for {} delivered to webhook", alert.token_address),
+                    Err(e) => warn!("Alert webhook failed for {}: {}", alert.token_address, e),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn alerter(webhook_url: Option<String>, cooldown_secs: u64) -> Alerter {
+        let mut cfg = crate::config::Config::defaults();
+        cfg.alert_rules = [("0xtoken".to_string(), Decimal::from(100))].into_iter().collect();
+        cfg.webhook_url = webhook_url;
+        cfg.alert_cooldown_secs = cooldown_secs;
+        Alerter::new(&cfg)
+    }
+
+    fn net(n: i64) -> Vec<(String, Decimal)> {
+        vec![("0xToken".to_string(), Decimal::from(n))]
+    }
+
+    #[test]
+    fn fires_once_per_crossing() {
+        let alerts = alerter(None, 0);
+        let now = Instant::now();
+
+        assert!(alerts.evaluate(&net(-99), now).is_empty());
+        let fired = alerts.evaluate(&net(-100), now);
+        assert_eq!(fired.len(), 1);
+        assert_eq!((fired[0].net_outflow, fired[0].threshold), (Decimal::from(100), Decimal::from(100)));
+
+        // still past the threshold: no repeat every block
+        assert!(alerts.evaluate(&net(-150), now).is_empty());
+
+        // back below re-arms, the next crossing fires again
+        assert!(alerts.evaluate(&net(-10), now).is_empty());
+        assert_eq!(alerts.evaluate(&net(-120), now).len(), 1);
+
+        // untracked tokens and inflows never alert
+        assert!(alerts.evaluate(&[("0xother".to_string(), Decimal::from(-1000))], now).is_empty());
+    }
+
+    #[test]
+    fn cooldown_debounces_a_hovering_net() {
+        let alerts = alerter(None, 600);
+        let start = Instant::now();
+
+        assert_eq!(alerts.evaluate(&net(-100), start).len(), 1);
+        assert!(alerts.evaluate(&net(-99), start).is_empty());
+        assert!(alerts.evaluate(&net(-101), start + Duration::from_secs(60)).is_empty());
+
+        // still breached once the cooldown is over: alert then
+        assert_eq!(alerts.evaluate(&net(-101), start + Duration::from_secs(600)).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn posts_alert_to_webhook() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "token_address": "0xtoken",
+                "net_outflow": "250",
+                "threshold": "100",
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let alerts = alerter(Some(server.uri()), 600);
+        alerts.notify(&net(-250));
+        alerts.notify(&net(-300));
+
+        // delivery is spawned; give it a moment before the expectation is checked
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        server.verify().await;
+    }
+}
@@ -0,0 +1,69 @@
+// src/backoff.rs
+// Exponential backoff with full jitter, so many instances retrying against
+// the same provider don't synchronise into a thundering herd
+use rand::Rng;
+use std::time::Duration;
+
+/// Never sleep less than this, even when jitter rolls ~0
+const MIN_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        let base = base.max(MIN_DELAY);
+        Self { base, cap: cap.max(base) }
+    }
+
+    /// Upper bound for the given (0-based) attempt: `min(cap, base * 2^attempt)`
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        self.base
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.cap)
+            .min(self.cap)
+    }
+
+    /// Full jitter: uniform in `[0, ceiling)`, floored at `MIN_DELAY`
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        let jittered = rand::thread_rng().gen_range(Duration::ZERO..ceiling);
+        jittered.max(MIN_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ceiling_doubles_then_caps() {
+        let b = Backoff::new(Duration::from_secs(2), Duration::from_secs(30));
+        let ceilings: Vec<u64> = (0..6).map(|a| b.ceiling(a).as_secs()).collect();
+        assert_eq!(ceilings, vec![2, 4, 8, 16, 30, 30]);
+        assert_eq!(b.ceiling(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn delays_stay_within_floor_and_ceiling() {
+        let b = Backoff::new(Duration::from_millis(500), Duration::from_secs(10));
+        for attempt in 0..40 {
+            for _ in 0..50 {
+                let d = b.delay(attempt);
+                assert!(d >= MIN_DELAY, "{:?} below floor", d);
+                assert!(d <= b.ceiling(attempt), "{:?} above ceiling", d);
+            }
+        }
+    }
+
+    #[test]
+    fn base_and_cap_are_floored() {
+        let b = Backoff::new(Duration::ZERO, Duration::ZERO);
+        assert_eq!(b.ceiling(0), MIN_DELAY);
+        assert_eq!(b.ceiling(10), MIN_DELAY);
+        assert_eq!(b.delay(3), MIN_DELAY);
+    }
+}
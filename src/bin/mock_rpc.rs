@@ -0,0 +1,243 @@
+// src/bin/mock_rpc.rs
+// Local stand-in for a Polygon JSON-RPC node, serving canned responses from a
+// fixtures file so the whole indexer can run end to end without a real node:
+//
+//   cargo run --bin mock_rpc -- mock_rpc.example.json
+//   RPC_HTTP_URL=http://127.0.0.1:8545 cargo run
+//
+// Answers eth_chainId, eth_blockNumber, eth_getBlockByNumber (numbers and the
+// latest/safe/finalized tags), eth_getLogs (address, block range and topic
+// filters) and the name()/symbol()/decimals() eth_calls, singly or batched.
+use axum::{extract::State, routing::post, Json, Router};
+use polygon_indexer::rpc::{DECIMALS_SELECTOR, NAME_SELECTOR, SYMBOL_SELECTOR};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Contents of the fixtures file; every field but `head` is optional
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixtures {
+    #[serde(default = "default_chain_id")]
+    chain_id: u64,
+    head: u64,              // eth_blockNumber and the `latest` tag
+    safe: Option<u64>,      // defaults to `head`
+    finalized: Option<u64>, // defaults to `safe`
+    #[serde(default = "default_genesis_timestamp")]
+    genesis_timestamp: u64, // block n is at genesis_timestamp + n * block_time_secs
+    #[serde(default = "default_block_time_secs")]
+    block_time_secs: u64,
+    #[serde(default)]
+    tokens: HashMap<String, TokenFixture>, // token address → metadata for eth_call
+    #[serde(default)]
+    logs: Vec<Value>, // eth_getLogs objects, returned as written
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenFixture {
+    name: Option<String>,
+    symbol: Option<String>,
+    decimals: u32,
+}
+
+fn default_chain_id() -> u64 {
+    137
+}
+
+fn default_genesis_timestamp() -> u64 {
+    1_600_000_000
+}
+
+fn default_block_time_secs() -> u64 {
+    2
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var("MOCK_RPC_FIXTURES").ok())
+        .ok_or_else(|| eyre::eyre!("usage: mock_rpc <fixtures.json> (or set MOCK_RPC_FIXTURES)"))?;
+    let fixtures = load(&std::fs::read_to_string(&path)?)?;
+    let addr: SocketAddr = std::env::var("MOCK_RPC_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:8545".to_string())
+        .parse()?;
+
+    println!("Mock RPC on http://{} (chain {}, head {}, {} log(s) from {})",
+        addr, fixtures.chain_id, fixtures.head, fixtures.logs.len(), path);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app(fixtures)).await?;
+    Ok(())
+}
+
+/// Parse fixtures, lowercasing token keys so lookups ignore address case
+fn load(raw: &str) -> eyre::Result<Fixtures> {
+    let mut fixtures: Fixtures = serde_json::from_str(raw)?;
+    fixtures.tokens = fixtures.tokens.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect();
+    Ok(fixtures)
+}
+
+fn app(fixtures: Fixtures) -> Router {
+    Router::new().route("/", post(handle)).with_state(Arc::new(fixtures))
+}
+
+/// A single request or a batch; batch entries are answered in order
+async fn handle(State(fixtures): State<Arc<Fixtures>>, Json(body): Json<Value>) -> Json<Value> {
+    match body {
+        Value::Array(calls) => Json(Value::Array(calls.iter().map(|c| respond(&fixtures, c)).collect())),
+        call => Json(respond(&fixtures, &call)),
+    }
+}
+
+fn respond(fixtures: &Fixtures, call: &Value) -> Value {
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    let params = call.get("params").cloned().unwrap_or(json!([]));
+    let result = match call.get("method").and_then(Value::as_str) {
+        Some("eth_chainId") => Ok(hex(fixtures.chain_id)),
+        Some("eth_blockNumber") => Ok(hex(fixtures.head)),
+        Some("eth_getBlockByNumber") => Ok(block(fixtures, &params[0])),
+        Some("eth_getLogs") => get_logs(fixtures, &params[0]),
+        Some("eth_call") => eth_call(fixtures, &params[0]),
+        other => Err((-32601, format!("method not found: {:?}", other))),
+    };
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+    }
+}
+
+fn hex(n: u64) -> Value {
+    Value::String(format!("0x{:x}", n))
+}
+
+/// A block number param: hex quantity or tag; `None` for anything else
+fn block_number(fixtures: &Fixtures, param: &Value) -> Option<u64> {
+    let safe = fixtures.safe.unwrap_or(fixtures.head);
+    match param.as_str()? {
+        "latest" | "pending" => Some(fixtures.head),
+        "safe" => Some(safe),
+        "finalized" => Some(fixtures.finalized.unwrap_or(safe)),
+        "earliest" => Some(0),
+        n => u64::from_str_radix(n.trim_start_matches("0x"), 16).ok(),
+    }
+}
+
+/// Header-only block, or `null` past the head like a real node
+fn block(fixtures: &Fixtures, param: &Value) -> Value {
+    match block_number(fixtures, param).filter(|n| *n <= fixtures.head) {
+        Some(n) => json!({
+            "number": hex(n),
+            "hash": format!("0x{:064x}", n),
+            "timestamp": hex(fixtures.genesis_timestamp + n * fixtures.block_time_secs),
+        }),
+        None => Value::Null,
+    }
+}
+
+fn get_logs(fixtures: &Fixtures, filter: &Value) -> Result<Value, (i64, String)> {
+    let bound = |key: &str, default: &str| {
+        block_number(fixtures, filter.get(key).unwrap_or(&json!(default)))
+            .ok_or_else(|| (-32602, format!("invalid {}: {}", key, filter[key])))
+    };
+    let (from, to) = (bound("fromBlock", "latest")?, bound("toBlock", "latest")?);
+    let address = filter.get("address").and_then(Value::as_str).map(str::to_lowercase);
+    // per topic position: one topic or an OR-list; null/absent matches all
+    let topics: Vec<Option<Vec<String>>> = match filter.get("topics") {
+        Some(Value::Array(positions)) => positions.iter().map(|p| match p {
+            Value::String(t) => Some(vec![t.to_lowercase()]),
+            Value::Array(ts) => Some(ts.iter().filter_map(Value::as_str).map(str::to_lowercase).collect()),
+            _ => None,
+        }).collect(),
+        _ => Vec::new(),
+    };
+
+    let logs = fixtures.logs.iter().filter(|log| {
+        let str_of = |key: &str| log.get(key).and_then(Value::as_str).map(str::to_lowercase);
+        let in_range = str_of("blockNumber")
+            .and_then(|b| u64::from_str_radix(b.trim_start_matches("0x"), 16).ok())
+            .is_some_and(|b| from <= b && b <= to);
+        let address_ok = address.is_none() || str_of("address") == address;
+        let topic_ok = topics.iter().enumerate().all(|(i, wanted)| wanted.as_ref().is_none_or(|wanted| {
+            log["topics"][i].as_str().is_some_and(|t| wanted.contains(&t.to_lowercase()))
+        }));
+        in_range && address_ok && topic_ok
+    });
+    Ok(Value::Array(logs.cloned().collect()))
+}
+
+/// name() / symbol() / decimals() of a fixture token, ABI-encoded; anything
+/// else reverts like a contract without that function
+fn eth_call(fixtures: &Fixtures, call: &Value) -> Result<Value, (i64, String)> {
+    let reverted = || (3, "execution reverted".to_string());
+    let to = call.get("to").and_then(Value::as_str).ok_or_else(reverted)?.to_lowercase();
+    let token = fixtures.tokens.get(&to).ok_or_else(reverted)?;
+    let data = call.get("data").or_else(|| call.get("input")).and_then(Value::as_str).unwrap_or_default();
+
+    let encoded = match data {
+        DECIMALS_SELECTOR => format!("0x{:064x}", token.decimals),
+        NAME_SELECTOR => abi_string(token.name.as_deref().ok_or_else(reverted)?),
+        SYMBOL_SELECTOR => abi_string(token.symbol.as_deref().ok_or_else(reverted)?),
+        _ => return Err(reverted()),
+    };
+    Ok(Value::String(encoded))
+}
+
+/// ABI encoding of a single `string` return value
+fn abi_string(s: &str) -> String {
+    let mut data = s.as_bytes().to_vec();
+    data.resize(s.len().div_ceil(32) * 32, 0);
+    format!("0x{:064x}{:064x}{}", 32, s.len(), hex::encode(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polygon_indexer::rpc::{BlockTag, RpcClient, TRANSFER_TOPIC};
+
+    const TOKEN: &str = "0x0000000000000000000000000000000000001010";
+
+    #[tokio::test]
+    async fn serves_the_calls_the_indexer_makes() {
+        let logs = [5, 50, 110].map(|block| json!({
+            "address": TOKEN,
+            "topics": [TRANSFER_TOPIC, format!("0x{:064x}", 1), format!("0x{:064x}", 2)],
+            "data": format!("0x{:064x}", 7),
+            "blockNumber": format!("0x{:x}", block),
+            "transactionHash": format!("0x{:064x}", block),
+            "logIndex": "0x0",
+        }));
+        let fixtures = load(&json!({
+            "head": 120,
+            "finalized": 100,
+            "tokens": { TOKEN: { "name": "Polygon", "symbol": "POL", "decimals": 18 } },
+            "logs": logs,
+        }).to_string()).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app(fixtures)).await });
+
+        let rpc = RpcClient::new(&url).unwrap();
+        assert_eq!(rpc.get_chain_id().await.unwrap(), 137);
+        assert_eq!(rpc.get_block_number().await.unwrap(), 120);
+        assert_eq!(rpc.get_tagged_block_number(BlockTag::Finalized).await.unwrap(), 100);
+
+        let logs = rpc.get_transfer_logs(TOKEN, &[TRANSFER_TOPIC.to_string()], 10, BlockTag::Finalized).await.unwrap();
+        assert_eq!(logs.iter().map(|l| l.block_number_hex.as_str()).collect::<Vec<_>>(), ["0x32"]);
+        assert!(rpc.get_transfer_logs(TOKEN, &["0xother".to_string()], 0, 120).await.unwrap().is_empty());
+        let to = [format!("0x{:064x}", 2)];
+        let logs = rpc.get_address_filtered_logs(TOKEN, &[TRANSFER_TOPIC.to_string()], &to, 1, 0, 100).await.unwrap();
+        assert_eq!(logs.len(), 2);
+        assert!(rpc.get_address_filtered_logs(TOKEN, &[TRANSFER_TOPIC.to_string()], &[format!("0x{:064x}", 3)], 1, 0, 120)
+            .await.unwrap().is_empty());
+
+        let meta = rpc.get_token_metadata(TOKEN).await.unwrap();
+        assert_eq!((meta.name.as_deref(), meta.symbol.as_deref(), meta.decimals), (Some("Polygon"), Some("POL"), 18));
+        assert!(rpc.get_token_metadata("0x0000000000000000000000000000000000000bad").await.is_err());
+
+        let ts = rpc.get_block_timestamps(&[10, 500]).await.unwrap();
+        assert_eq!(ts, [(10, 1_600_000_020)].into());
+    }
+}
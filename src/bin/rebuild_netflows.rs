@@ -0,0 +1,37 @@
+// src/bin/rebuild_netflows.rs
+// Recovery tool: wipe the `netflows` cache and rebuild it from `transfers`
+use polygon_indexer::{aggregator, config, db};
+use std::collections::HashMap;
+
+fn main() -> eyre::Result<()> {
+    let cfg = config::load()?;
+    let mut conn = db::connect(&cfg.db_path)?;
+    db::run_migrations(&mut conn)?;
+    db::record_default_decimals(&conn, cfg.default_decimals)?;
+    db::record_chain_id(&conn, cfg.chain_id)?;
+
+    println!("Rebuilding netflows in {} ({} units)", cfg.db_path, cfg.netflow_units.as_str());
+
+    // snapshot current values so the operator can see what changed
+    let before: HashMap<String, String> = {
+        let mut stmt = conn.prepare("SELECT token_address, cumulative_net FROM netflows")?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM netflows", [])?;
+    let after = aggregator::recompute_full(&tx, cfg.netflow_units)?;
+    tx.commit()?;
+
+    for (token, net) in &after {
+        let old = before.get(token).map(String::as_str).unwrap_or("-");
+        println!("  {}  {} → {}", token, old, net);
+    }
+    for token in before.keys().filter(|t| !after.iter().any(|(a, _)| a == *t)) {
+        println!("  {}  {} → (removed, no transfers)", token, before[token]);
+    }
+
+    println!("Rebuilt {} token(s)", after.len());
+    Ok(())
+}
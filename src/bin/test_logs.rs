@@ -1,3 +1,4 @@
+use polygon_indexer::parser::topic_to_address;
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
@@ -37,11 +38,10 @@ const TRANSFER_TOPIC: &str =
     "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
 
 fn decode_address(topic: &str) -> String {
-    if topic.len() >= 66 {
-        format!("0x{}", &topic[26..])
-    } else {
-        topic.to_string()
-    }
+    // same lenient padding as the indexer; show the raw topic if it's not hex
+    topic_to_address(topic)
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| format!("<malformed topic {}>", topic))
 }
 
 fn decode_uint256(data: &str) -> u128 {
@@ -102,6 +102,10 @@ async fn main() -> anyhow::Result<()> {
         RpcResponse::Success { result: logs } => {
             println!("Fetched {} logs", logs.len());
             for log in logs.iter().take(5) {
+                if log.topics.len() < 3 {
+                    println!("Tx: {} | skipped: only {} topic(s)", log.tx_hash, log.topics.len());
+                    continue;
+                }
                 let from = decode_address(&log.topics[1]);
                 let to = decode_address(&log.topics[2]);
                 let value = decode_uint256(&log.data);
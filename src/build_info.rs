@@ -0,0 +1,25 @@
+// src/build_info.rs
+// Version, git commit and build time captured at compile time by build.rs
+use crate::models::BuildInfo;
+use chrono::DateTime;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+/// Unix seconds
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+pub fn build_info() -> BuildInfo {
+    let built_at = BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_else(|| "unknown".to_string());
+    BuildInfo { version: VERSION, git_commit: GIT_COMMIT, built_at }
+}
+
+/// One line for `--version` and the startup log
+pub fn describe() -> String {
+    let info = build_info();
+    format!("polygon-indexer {} ({}, built {})", info.version, info.git_commit, info.built_at)
+}
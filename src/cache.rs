@@ -1,6 +1,203 @@
 // src/cache.rs
-// Placeholder for FUTURE caching logic Redis or in-memory
-//not used in this version right now
-pub fn init_cache() {
-    println!("Cache system initialized (stub).");
+// In-memory copy of the `netflows` table, so `/netflow` is served without
+// taking the SQLite lock. Whoever writes `netflows` reloads it while still
+// holding the connection, so a reader never sees the two disagree.
+// Also the `/transfers/count` results, dropped by every write to `transfers`.
+use crate::models::NetFlow;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+/// Lowercased token → its `netflows` row. Cheap to clone; clones share the map.
+#[derive(Clone, Default)]
+pub struct NetflowCache(Arc<RwLock<HashMap<String, NetFlow>>>);
+
+impl NetflowCache {
+    /// Cached netflow for `token`; `None` is a miss, not proof there is no row
+    pub fn get(&self, token: &str) -> Option<NetFlow> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner).get(&token.to_lowercase()).cloned()
+    }
+
+    /// Fill in a row read from `netflows` on a miss. Only call this while
+    /// holding the connection the row was read with, or a reload that ran in
+    /// between could be overwritten with the older row.
+    pub fn insert(&self, netflow: NetFlow) {
+        self.0.write().unwrap_or_else(PoisonError::into_inner).insert(netflow.token_address.to_lowercase(), netflow);
+    }
+
+    /// Forget `token`, e.g. once its `netflows` row is purged
+    pub fn remove(&self, token: &str) {
+        self.0.write().unwrap_or_else(PoisonError::into_inner).remove(&token.to_lowercase());
+    }
+
+    /// Forget everything; reads fall back to the DB until the next reload
+    pub fn clear(&self) {
+        self.0.write().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    /// Replace the whole cache with the current `netflows` table. Call it in
+    /// the same locked step that wrote `netflows`. Returns the rows loaded.
+    pub fn reload(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT token_address, cumulative_net, inflow_total, outflow_total, last_block, updated_at, units
+             FROM netflows",
+        )?;
+        let rows = stmt
+            .query_map([], crate::api::netflow_from_row)?
+            .map(|row| row.map(|nf| (nf.token_address.to_lowercase(), nf)))
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+
+        let loaded = rows.len();
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = rows;
+        Ok(loaded)
+    }
+}
+
+/// How long a `/transfers/count` result is served without a write having
+/// invalidated it, in case one wasn't seen (e.g. another process's)
+pub const COUNT_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// A `/transfers/count` filter: lowercased token plus the optional filters
+/// (bounds as SQLite `datetime()` text, like the stored `timestamp`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CountKey {
+    pub token: String,
+    pub min_amount: Option<String>,
+    pub direction: Option<&'static str>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+/// `(generation, token version)` a count was computed under
+pub type CountVersion = (u64, u64);
+
+type Clock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+#[derive(Default)]
+struct CountState {
+    generation: u64,                // bumped by `invalidate_all`
+    versions: HashMap<String, u64>, // token → bumped by `invalidate`
+    entries: HashMap<CountKey, (CountVersion, i64, Instant)>,
+}
+
+impl CountState {
+    fn version(&self, token: &str) -> CountVersion {
+        (self.generation, self.versions.get(token).copied().unwrap_or_default())
+    }
+}
+
+/// Cached `/transfers/count` results. Every path that writes or deletes a
+/// token's transfers bumps its version, and a count is only stored and served
+/// under the version it was read at: a slow request that read before a write
+/// can't store its count after it. Cheap to clone; clones share the map.
+#[derive(Clone)]
+pub struct TransferCounts {
+    state: Arc<Mutex<CountState>>,
+    max_age: Duration,
+    clock: Clock,
+}
+
+impl Default for TransferCounts {
+    fn default() -> Self {
+        Self::with_clock(COUNT_MAX_AGE, Instant::now)
+    }
+}
+
+impl TransferCounts {
+    /// Entries expire `max_age` after `clock` says they were stored
+    pub fn with_clock(max_age: Duration, clock: impl Fn() -> Instant + Send + Sync + 'static) -> Self {
+        Self { state: Arc::default(), max_age, clock: Arc::new(clock) }
+    }
+
+    /// The version to store a count under; take it before reading the DB
+    pub fn version(&self, token: &str) -> CountVersion {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).version(token)
+    }
+
+    /// A stored count, if no write has invalidated it and it hasn't expired
+    pub fn get(&self, key: &CountKey) -> Option<i64> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let &(version, count, stored_at) = state.entries.get(key)?;
+        let fresh = (self.clock)().saturating_duration_since(stored_at) < self.max_age;
+        (version == state.version(&key.token) && fresh).then_some(count)
+    }
+
+    /// Store `count` if the token is still at `version`; a write since then
+    /// means it may already be stale, so it is dropped
+    pub fn insert(&self, key: CountKey, version: CountVersion, count: i64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.version(&key.token) == version {
+            let stored_at = (self.clock)();
+            state.entries.insert(key, (version, count, stored_at));
+        }
+    }
+
+    /// `token`'s transfers changed: drop its counts, and any being computed
+    pub fn invalidate(&self, token: &str) {
+        let token = token.to_lowercase();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.entries.retain(|key, _| key.token != token);
+        *state.versions.entry(token).or_default() += 1;
+    }
+
+    /// Transfers changed for tokens that aren't known: drop every count
+    pub fn invalidate_all(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.entries.clear();
+        state.versions.clear();
+        state.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(token: &str) -> CountKey {
+        CountKey { token: token.to_string(), min_amount: None, direction: None, since: None, until: None }
+    }
+
+    #[test]
+    fn counts_are_dropped_by_writes_and_expire() {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let counts = TransferCounts::with_clock(Duration::from_secs(60), {
+            let now = Arc::clone(&now);
+            move || *now.lock().unwrap()
+        });
+
+        counts.insert(key("0xa"), counts.version("0xa"), 3);
+        counts.insert(key("0xb"), counts.version("0xb"), 5);
+        assert_eq!(counts.get(&key("0xa")), Some(3));
+
+        // a write to 0xa drops its counts only
+        counts.invalidate("0xA");
+        assert_eq!(counts.get(&key("0xa")), None);
+        assert_eq!(counts.get(&key("0xb")), Some(5));
+
+        *now.lock().unwrap() += Duration::from_secs(60);
+        assert_eq!(counts.get(&key("0xb")), None);
+
+        counts.insert(key("0xb"), counts.version("0xb"), 6);
+        counts.invalidate_all();
+        assert_eq!(counts.get(&key("0xb")), None);
+    }
+
+    #[test]
+    fn a_count_read_before_a_write_is_never_stored() {
+        let counts = TransferCounts::default();
+        // request reads the version, then the DB; a write lands in between
+        let version = counts.version("0xa");
+        counts.invalidate("0xa");
+        counts.insert(key("0xa"), version, 3);
+        assert_eq!(counts.get(&key("0xa")), None);
+
+        let version = counts.version("0xa");
+        counts.invalidate_all();
+        counts.insert(key("0xa"), version, 3);
+        assert_eq!(counts.get(&key("0xa")), None);
+
+        counts.insert(key("0xa"), counts.version("0xa"), 4);
+        assert_eq!(counts.get(&key("0xa")), Some(4));
+    }
 }
@@ -0,0 +1,102 @@
+// src/check.rs
+// Dry-run mode: validate config, DB and RPC connectivity, then exit
+use crate::{config::Config, db, rpc};
+use std::{fs::OpenOptions, path::Path};
+
+/// Run every startup check and print a summary. Returns `true` if all passed.
+pub async fn run(cfg: &Config) -> bool {
+    let mut ok = true;
+
+    println!("Polygon Indexer config check");
+
+    // Addresses
+    if cfg.invalid_addresses.is_empty() {
+        println!("  [ok]   {} token(s), {} exchange(s) parsed",
+            cfg.token_set.len(), cfg.exchange_set.len());
+    } else {
+        ok = false;
+        for addr in &cfg.invalid_addresses {
+            println!("  [FAIL] invalid address: {}", addr);
+        }
+    }
+
+    // DB path writable
+    let existed = Path::new(&cfg.db_path).exists();
+    match OpenOptions::new().append(true).create(true).open(&cfg.db_path) {
+        Ok(_) => {
+            if !existed {
+                let _ = std::fs::remove_file(&cfg.db_path);
+            }
+            println!("  [ok]   DB path {} is writable", cfg.db_path);
+        }
+        Err(e) => {
+            ok = false;
+            println!("  [FAIL] DB path {} not writable: {}", cfg.db_path, e);
+        }
+    }
+
+    // Migrations against a temp copy so the real DB is never touched
+    match check_migrations(&cfg.db_path, existed) {
+        Ok(()) => println!("  [ok]   migrations apply cleanly"),
+        Err(e) => {
+            ok = false;
+            println!("  [FAIL] migrations: {}", e);
+        }
+    }
+
+    // RPC connectivity + chain id
+    match rpc::RpcClient::with_http_options(&cfg.rpc_http_url, &cfg.rpc_http_options()) {
+        Ok(client) => {
+            match client.get_block_number().await {
+                Ok(block) => println!("  [ok]   RPC {} at block {}", cfg.rpc_http_url, block),
+                Err(e) => {
+                    ok = false;
+                    println!("  [FAIL] RPC {}: {}", cfg.rpc_http_url, e);
+                }
+            }
+            if let Some(tag) = cfg.block_tag {
+                match client.get_tagged_block_number(tag).await {
+                    Ok(block) => println!("  [ok]   BLOCK_TAG {} is block {}", tag.as_str(), block),
+                    Err(e) => {
+                        ok = false;
+                        println!("  [FAIL] BLOCK_TAG {}: {}", tag.as_str(), e);
+                    }
+                }
+            }
+            match client.get_chain_id().await {
+                Ok(id) if id == cfg.chain_id => println!("  [ok]   RPC chain id {} matches CHAIN_ID", id),
+                Ok(id) => {
+                    ok = false;
+                    println!("  [FAIL] RPC serves chain {} but CHAIN_ID is {}", id, cfg.chain_id);
+                }
+                Err(e) => {
+                    ok = false;
+                    println!("  [FAIL] eth_chainId: {}", e);
+                }
+            }
+        }
+        Err(e) => {
+            ok = false;
+            println!("  [FAIL] RPC {}: {}", cfg.rpc_http_url, e);
+        }
+    }
+
+    println!("{}", if ok { "All checks passed" } else { "Some checks failed" });
+    ok
+}
+
+fn check_migrations(db_path: &str, copy_existing: bool) -> eyre::Result<()> {
+    let tmp = std::env::temp_dir().join(format!("polygon-indexer-check-{}.db", std::process::id()));
+    if copy_existing {
+        std::fs::copy(db_path, &tmp)?;
+    }
+
+    let result = db::connect(&tmp.to_string_lossy())
+        .and_then(|mut conn| db::run_migrations(&mut conn))
+        .map_err(Into::into);
+
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", tmp.display(), suffix));
+    }
+    result
+}
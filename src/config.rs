@@ -1,71 +1,1453 @@
 use dotenvy::dotenv;
-use eyre::Result;
-use serde::Deserialize;
-use std::{collections::HashSet, env};
+use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashMap, HashSet}, env, net::IpAddr, time::Duration};
 use alloy::primitives::Address;
-use tracing::info;
+use axum::http::{HeaderName, HeaderValue};
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use tracing::{info, warn};
+use crate::error::{IndexerError, Result};
+use crate::parser::ValueLayout;
+use crate::rpc::{BlockTag, CircuitBreaker, HttpOptions, APPROVAL_TOPIC, DEPOSIT_TOPIC, TRANSFER_TOPIC, WITHDRAWAL_TOPIC};
 
-#[derive(Debug, Clone, Deserialize)]
+/// Decimals assumed for a token whose `decimals()` can't be read, unless
+/// DEFAULT_DECIMALS says otherwise (the ERC20 convention)
+pub const DEFAULT_TOKEN_DECIMALS: u32 = 18;
+/// CHAIN_ID when unset: Polygon PoS
+pub const DEFAULT_CHAIN_ID: u64 = 137;
+
+/// Largest scale a `Decimal` can hold, so the most decimals an amount can be scaled by
+pub const MAX_DECIMAL_SCALE: u32 = 28;
+
+/// Which transfers the indexer records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexMode {
+    /// Only transfers with an exchange on one side (IN/OUT)
+    ExchangeOnly,
+    /// Every decoded transfer; non-exchange ones are stored as NEUTRAL
+    All,
+}
+
+/// Unit netflows are computed, stored and served in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NetflowUnits {
+    /// Scaled by the token's decimals (`transfers.amount`)
+    #[default]
+    Token,
+    /// On-chain integers (`transfers.raw_amount`), exact for any decimals
+    Raw,
+}
+
+impl NetflowUnits {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Token => "token",
+            Self::Raw => "raw",
+        }
+    }
+
+    /// Inverse of `as_str`; anything unknown is the default `token`
+    pub fn from_db(s: &str) -> Self {
+        if s == "raw" { Self::Raw } else { Self::Token }
+    }
+}
+
+/// Where a MODE=backfill snapshot starts: FROM_BLOCK, or FROM_DATE resolved
+/// to the first block at or after it once the RPC is reachable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SnapshotStart {
+    Block(u64),
+    Date(DateTime<Utc>),
+}
+
+impl std::fmt::Display for SnapshotStart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Block(block) => write!(f, "{}", block),
+            Self::Date(date) => write!(f, "{} (FROM_DATE)", date.to_rfc3339()),
+        }
+    }
+}
+
+/// Bearer tokens accepted by the API; `Debug` and `Serialize` give only the
+/// count so the keys never end up in logs or `/debug/config`
+#[derive(Clone, Default, Deserialize)]
+pub struct ApiKeys(pub Vec<String>);
+
+impl std::fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ApiKeys({} configured)", self.0.len())
+    }
+}
+
+impl Serialize for ApiKeys {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{} configured", self.0.len()))
+    }
+}
+
+/// Serializes (for `/debug/config`) with secrets redacted: URLs go through
+/// `redact_url`, API keys are only counted and sets are sorted. `Debug`
+/// prints that same redacted view, so logging a config can't leak them either.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(serialize_with = "redacted_url")]
     pub rpc_http_url: String,       // ✅ HTTP RPC URL
+    pub chain_id: u64,              // must match the RPC's eth_chainId; stored on every transfer
     pub db_path: String,
-    pub confirmations: u64,
-    pub exchange_set: HashSet<Address>,
+    pub confirmations: u64,         // for tokens not in token_confirmations
+    pub token_confirmations: HashMap<String, u64>, // lowercased token -> its own CONFIRMATIONS
+    pub block_tag: Option<BlockTag>, // scan up to this provider-resolved block instead of head - confirmations
+    pub start_block: u64,           // never scan below this block
+    pub backfill_blocks: u64,       // blocks to scan once at startup (chunk size in snapshot mode)
+    pub backfill_chunk_blocks: u64, // eth_getLogs span per request during the startup backfill
+    pub max_batch_rows: usize,      // rows written per SQLite transaction before committing
+    pub backfill_range: Option<(SnapshotStart, u64)>, // MODE=backfill: scan [FROM_BLOCK or FROM_DATE, TO_BLOCK] then exit
+    pub lookback_blocks: u64,       // blocks to scan per live loop
+    pub rescan_depth: u64,          // extra already-scanned blocks re-read each loop
+    pub rpc_pause_ms: u64,          // pause between per-token RPC calls
+    pub rpc_batch_size: usize,      // max calls per JSON-RPC batch request
+    pub rpc_breaker_threshold: u32, // consecutive RPC failures before the circuit opens; 0 = off
+    pub rpc_breaker_cooldown_secs: u64, // how long an open circuit fails fast before probing
+    pub rpc_pool_max_idle_per_host: usize, // idle RPC connections kept per host
+    pub rpc_pool_idle_timeout_secs: u64, // close idle RPC connections after this; 0 = never
+    pub rpc_tcp_keepalive_secs: u64, // TCP keepalive on RPC connections; 0 = off
+    pub rpc_http2_prior_knowledge: bool, // speak HTTP/2 (h2c) without negotiating
+    pub rpc_http2_keepalive_secs: u64, // HTTP/2 PING interval; 0 = off
+    pub poll_interval_secs: u64,    // sleep between live loops
+    pub retry_base_ms: u64,         // first RPC retry backoff ceiling
+    pub retry_cap_secs: u64,        // max backoff for RPC and indexer retries
+    #[serde(serialize_with = "sorted")]
+    pub exchange_set: HashSet<Address>, // EXCHANGE_ADDRESSES plus EXCHANGE_ADDRESSES_FILE
+    #[serde(skip)]
+    pub env_exchange_set: HashSet<Address>, // just EXCHANGE_ADDRESSES, kept across file reloads
+    pub exchange_label: String,     // label stored for config-seeded exchanges
+    pub exchange_labels: BTreeMap<Address, String>, // per-wallet labels from the file, else exchange_label
+    pub exchange_addresses_file: Option<String>, // CSV or JSON of address,label merged into exchange_set
+    pub exchange_reload_secs: u64,  // re-read the file between live rounds this often; 0 = startup only
+    pub exchange_topic_filter: bool, // exchange_only: eth_getLogs only for transfers from/to an exchange
+    pub exchange_topic_batch: usize, // exchange wallets per topic filter before splitting the query
+    #[serde(serialize_with = "sorted")]
     pub token_set: HashSet<String>,
+    pub event_topics: HashMap<String, Vec<String>>, // lowercased token -> topic0 list
+    pub value_layouts: HashMap<String, ValueLayout>, // lowercased token -> where its Transfer value is (default auto)
+    pub sample_rates: HashMap<String, f64>, // lowercased token -> share of NEUTRAL transfers recorded (default 1)
+    #[serde(serialize_with = "sorted")]
+    pub fee_on_transfer: HashSet<String>, // lowercased tokens whose netflows are only best effort
+    pub bind_addr: IpAddr,          // API listen address
     pub port: u16,
+    pub unix_socket: Option<String>, // serve the API on this socket path instead of BIND_ADDR:PORT
+    pub api_timeout_secs: u64,      // per-request timeout (408 on expiry)
+    pub api_max_concurrency: usize, // in-flight requests before shedding (503)
+    pub api_max_body_bytes: usize,  // request body limit (413 above)
+    pub api_cache_max_age_secs: u64, // Cache-Control max-age on aggregate endpoints
+    pub api_keys: ApiKeys,          // bearer tokens; empty = auth disabled
+    #[serde(skip)]
+    pub allowed_origins: Vec<HeaderValue>, // CORS allow-list; empty = no cross-origin access
+    pub dev_mode: bool,             // permissive CORS (any origin) when no allow-list is set
+    pub request_id_header: String,  // lowercased header read/echoed as the request id
+    pub token_stale_secs: u64,      // /health/tokens flags tokens quiet for longer
+    pub retention_days: u64,        // prune transfers older than this; 0 = keep forever
+    pub wal_checkpoint_secs: u64,   // periodic WAL checkpoint(TRUNCATE); 0 = only on shutdown
+    pub alert_rules: HashMap<String, Decimal>, // lowercased token -> net outflow threshold
+    #[serde(serialize_with = "redacted_optional_url")]
+    pub webhook_url: Option<String>, // where netflow alerts are POSTed
+    pub alert_cooldown_secs: u64,   // min time between two alerts for the same token
+    pub min_transfer_amount: Decimal, // skip transfers below this (scaled units)
+    pub default_decimals: u32,      // assumed (with a warning) when decimals() can't be fetched
+    pub index_mode: IndexMode,
+    pub netflow_units: NetflowUnits, // token or raw; applies to every netflow output
+    pub invalid_addresses: Vec<String>, // config entries that failed to parse
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redacted = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "Config {}", redacted)
+    }
+}
+
+/// `url` reduced to scheme, host and port: user info, path, query and
+/// fragment can all carry credentials (e.g. `/v2/<api key>`), so any of them
+/// present shows as `<redacted>`. Anything unparseable is redacted whole.
+pub fn redact_url(url: &str) -> String {
+    let Ok(parsed) = reqwest::Url::parse(url) else {
+        return "<redacted>".to_string();
+    };
+    let Some(host) = parsed.host_str() else {
+        return format!("{}:<redacted>", parsed.scheme());
+    };
+    let user = if parsed.username().is_empty() && parsed.password().is_none() { "" } else { "<redacted>@" };
+    let port = parsed.port().map(|p| format!(":{}", p)).unwrap_or_default();
+    let rest = parsed.path().trim_matches('/').is_empty() && parsed.query().is_none() && parsed.fragment().is_none();
+    let rest = if rest { "" } else { "/<redacted>" };
+    format!("{}://{}{}{}{}", parsed.scheme(), user, host, port, rest)
+}
+
+fn redacted_url<S: serde::Serializer>(url: &str, s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_str(&redact_url(url))
+}
+
+fn redacted_optional_url<S: serde::Serializer>(url: &Option<String>, s: S) -> std::result::Result<S::Ok, S::Error> {
+    url.as_deref().map(redact_url).serialize(s)
+}
+
+fn sorted<T: Ord + Serialize, S: serde::Serializer>(set: &HashSet<T>, s: S) -> std::result::Result<S::Ok, S::Error> {
+    set.iter().collect::<std::collections::BTreeSet<_>>().serialize(s)
 }
 
 pub fn load() -> Result<Config> {
     dotenv().ok(); // ✅ Load from .env file
 
+    // ✅ Optional TOML file; env vars (including .env) override its values
+    from_source(&Source::load()?)
+}
+
+fn from_source(src: &Source) -> Result<Config> {
     // ✅ Load RPC URL (prefer HTTP, fallback to polygon-rpc.com)
-    let rpc_http_url = env::var("RPC_HTTP_URL")
-        .or_else(|_| env::var("POLYGON_RPC")) // alias support
+    let rpc_http_url = src.var("RPC_HTTP_URL")
+        .or_else(|_| src.var("POLYGON_RPC")) // alias support
         .unwrap_or_else(|_| "https://polygon-rpc.com".to_string());
 
+    // ✅ Chain the RPC must serve (default: 137, Polygon PoS)
+    let chain_id = src.var("CHAIN_ID")
+        .unwrap_or_else(|_| DEFAULT_CHAIN_ID.to_string())
+        .trim()
+        .parse()
+        .map_err(|e| IndexerError::Config(format!("Invalid CHAIN_ID: {}", e)))?;
+
     // ✅ SQLite DB path (default: netflow.db)
-    let db_path = env::var("DATABASE_URL").unwrap_or_else(|_| "netflow.db".to_string());
+    let db_path = src.var("DATABASE_URL").unwrap_or_else(|_| "netflow.db".to_string());
+
+    // ✅ Block confirmations, optionally per token (default: 2)
+    let (confirmations, token_confirmations) =
+        parse_confirmations(&src.var("CONFIRMATIONS").unwrap_or_default(), 2)?;
+
+    // ✅ Scan head by provider block tag (default: unset = latest minus CONFIRMATIONS)
+    let block_tag = match src.var("BLOCK_TAG") {
+        Ok(tag) if tag.trim().is_empty() => None,
+        Ok(tag) => Some(match tag.trim().to_lowercase().as_str() {
+            "latest" => BlockTag::Latest,
+            "safe" => BlockTag::Safe,
+            "finalized" => BlockTag::Finalized,
+            other => {
+                return Err(IndexerError::Config(format!(
+                    "Invalid BLOCK_TAG {:?}: expected finalized, safe or latest", other
+                )))
+            }
+        }),
+        Err(_) => None,
+    };
+
+    // ✅ Lowest block the indexer will ever scan (default: 0)
+    let start_block = src.var("START_BLOCK")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .unwrap_or(0);
+
+    // ✅ Startup backfill size (default: 5000 blocks)
+    let backfill_blocks = src.var("BACKFILL_BLOCKS")
+        .unwrap_or_else(|_| "5000".to_string())
+        .parse()
+        .unwrap_or(5000);
 
-    // ✅ Block confirmations (default: 2)
-    let confirmations = env::var("CONFIRMATIONS")
-        .unwrap_or_else(|_| "2".to_string())
+    // ✅ Startup backfill request size; progress is reported per chunk (default: 1000 blocks)
+    let backfill_chunk_blocks = src.var("BACKFILL_CHUNK_BLOCKS")
+        .unwrap_or_else(|_| "1000".to_string())
         .parse()
-        .unwrap_or(2);
+        .unwrap_or(1000);
+
+    // ✅ Rows per write transaction, so a huge chunk doesn't hold the DB lock
+    //    for its whole insert (default: 5000)
+    let max_batch_rows = src.var("MAX_BATCH_ROWS")
+        .unwrap_or_else(|_| "5000".to_string())
+        .parse()
+        .unwrap_or(5000);
+
+    // ✅ Snapshot mode: MODE=backfill scans FROM_BLOCK (or FROM_DATE)..=TO_BLOCK once and exits
+    let backfill_range = if src.var("MODE").map(|m| m.trim() == "backfill").unwrap_or(false) {
+        let block = |name: &str| -> Result<u64> {
+            let raw = src.var(name)
+                .map_err(|_| IndexerError::Config(format!("MODE=backfill requires {}", name)))?;
+            raw.trim()
+                .parse()
+                .map_err(|e| IndexerError::Config(format!("Invalid {} {:?}: {}", name, raw, e)))
+        };
+        let from = match src.var("FROM_DATE") {
+            Ok(_) if src.var("FROM_BLOCK").is_ok() => {
+                return Err(IndexerError::Config("Set either FROM_BLOCK or FROM_DATE, not both".into()));
+            }
+            Ok(raw) => SnapshotStart::Date(parse_from_date(&raw)?),
+            Err(_) => SnapshotStart::Block(block("FROM_BLOCK")?),
+        };
+        let to = block("TO_BLOCK")?;
+        if let SnapshotStart::Block(from) = from {
+            if from > to {
+                return Err(IndexerError::Config(format!("FROM_BLOCK {} is above TO_BLOCK {}", from, to)));
+            }
+        }
+        Some((from, to))
+    } else {
+        None
+    };
+
+    // ✅ Live lookback window per loop (default: 100 blocks)
+    let lookback_blocks = src.var("LOOKBACK_BLOCKS")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse()
+        .unwrap_or(100);
+
+    // ✅ Reorg safety re-scan depth on top of lookback (default: 0)
+    let rescan_depth = src.var("RESCAN_DEPTH")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .unwrap_or(0);
+
+    // ✅ Pause between RPC requests (default: 200ms)
+    let rpc_pause_ms = src.var("RPC_PAUSE_MS")
+        .unwrap_or_else(|_| "200".to_string())
+        .parse()
+        .unwrap_or(200);
+
+    // ✅ Max calls per JSON-RPC batch request (default: 100; many providers cap it)
+    let rpc_batch_size = src.var("RPC_BATCH_SIZE")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse()
+        .unwrap_or(100);
+
+    // ✅ RPC circuit breaker: open after N consecutive failures for a cooldown (default: 5 / 30s)
+    let rpc_breaker_threshold = src.var("RPC_BREAKER_THRESHOLD")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse()
+        .unwrap_or(5);
+
+    let rpc_breaker_cooldown_secs = src.var("RPC_BREAKER_COOLDOWN_SECS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse()
+        .unwrap_or(30);
+
+    // ✅ RPC connection pool: idle connections per host (default: 16) and how
+    // long they stay open (default: 90s, 0 = forever)
+    let rpc_pool_max_idle_per_host = src.var("RPC_POOL_MAX_IDLE_PER_HOST")
+        .unwrap_or_else(|_| "16".to_string())
+        .parse()
+        .unwrap_or(16);
+
+    let rpc_pool_idle_timeout_secs = src.var("RPC_POOL_IDLE_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "90".to_string())
+        .parse()
+        .unwrap_or(90);
+
+    // ✅ Keepalive on RPC connections: TCP (default: 60s) and HTTP/2 PINGs
+    // (default: 30s); 0 turns either off
+    let rpc_tcp_keepalive_secs = src.var("RPC_TCP_KEEPALIVE_SECS")
+        .unwrap_or_else(|_| "60".to_string())
+        .parse()
+        .unwrap_or(60);
+
+    let rpc_http2_keepalive_secs = src.var("RPC_HTTP2_KEEPALIVE_SECS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse()
+        .unwrap_or(30);
+
+    // ✅ HTTP/2 without negotiation, for plain-http providers that speak it (default: false)
+    let rpc_http2_prior_knowledge = src.var("RPC_HTTP2_PRIOR_KNOWLEDGE")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+
+    // ✅ Poll interval between live loops (default: 10s)
+    let poll_interval_secs = src.var("POLL_INTERVAL_SECS")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse()
+        .unwrap_or(10);
+
+    // ✅ API bind address (default: 127.0.0.1; use 0.0.0.0 in containers)
+    let bind_addr_raw = src.var("BIND_ADDR").unwrap_or_else(|_| "127.0.0.1".to_string());
+    let bind_addr: IpAddr = bind_addr_raw
+        .trim()
+        .parse()
+        .map_err(|e| IndexerError::Config(format!("Invalid BIND_ADDR {:?}: {}", bind_addr_raw, e)))?;
+
+    // ✅ Unix domain socket for the API (default: unset, listen on BIND_ADDR:PORT)
+    let unix_socket = src.var("UNIX_SOCKET")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+
+    // ✅ Jittered retry backoff: base for RPC retries, cap for everything (default: 2000ms / 120s)
+    let retry_base_ms = src.var("RETRY_BASE_MS")
+        .unwrap_or_else(|_| "2000".to_string())
+        .parse()
+        .unwrap_or(2000);
+
+    let retry_cap_secs = src.var("RETRY_CAP_SECS")
+        .unwrap_or_else(|_| "120".to_string())
+        .parse()
+        .unwrap_or(120);
 
     // ✅ API port (default: 8080)
-    let port = env::var("PORT")
+    let port = src.var("PORT")
         .unwrap_or_else(|_| "8080".to_string())
         .parse()
         .unwrap_or(8080);
 
+    // ✅ API hardening: timeout, concurrency and body size limits
+    let api_timeout_secs = src.var("API_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse()
+        .unwrap_or(10);
+
+    let api_max_concurrency = src.var("API_MAX_CONCURRENCY")
+        .unwrap_or_else(|_| "64".to_string())
+        .parse()
+        .unwrap_or(64);
+
+    let api_max_body_bytes = src.var("API_MAX_BODY_BYTES")
+        .unwrap_or_else(|_| "65536".to_string())
+        .parse()
+        .unwrap_or(65536);
+
+    // ✅ Cache-Control max-age for /netflow, /coverage, /token, /exchanges (default: 5s)
+    let api_cache_max_age_secs = src.var("API_CACHE_MAX_AGE_SECS")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse()
+        .unwrap_or(5);
+
+    // ✅ API bearer tokens (default: none = auth disabled for local dev)
+    let api_keys = ApiKeys(
+        src.var("API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    );
+
+    // ✅ CORS origins allowed to call the API (default: none; DEV_MODE=true allows any)
+    let mut allowed_origins = Vec::new();
+    for origin in src.var("ALLOWED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().trim_end_matches('/'))
+        .filter(|s| !s.is_empty())
+    {
+        if !(origin.starts_with("http://") || origin.starts_with("https://")) {
+            return Err(IndexerError::Config(format!(
+                "Invalid ALLOWED_ORIGINS entry {:?}: expected scheme://host[:port]", origin
+            )));
+        }
+        let value = HeaderValue::from_str(origin)
+            .map_err(|e| IndexerError::Config(format!("Invalid ALLOWED_ORIGINS entry {:?}: {}", origin, e)))?;
+        allowed_origins.push(value);
+    }
+
+    // ✅ Header carrying the request id, read from clients and echoed back (default: x-request-id)
+    let request_id_header = src.var("REQUEST_ID_HEADER")
+        .map(|s| s.trim().to_lowercase())
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "x-request-id".to_string());
+    HeaderName::from_bytes(request_id_header.as_bytes())
+        .map_err(|e| IndexerError::Config(format!("Invalid REQUEST_ID_HEADER {:?}: {}", request_id_header, e)))?;
+
+    let dev_mode = src.var("DEV_MODE")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+
+    // ✅ Per-token staleness window for /health/tokens (default: 3600s)
+    let token_stale_secs = src.var("TOKEN_STALE_SECS")
+        .unwrap_or_else(|_| "3600".to_string())
+        .parse()
+        .unwrap_or(3600);
+
+    // ✅ Delete transfers older than this many days (default: 0 = keep forever)
+    let retention_days = src.var("RETENTION_DAYS")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .unwrap_or(0);
+
+    // ✅ Truncate the WAL this often so it stays bounded (default: 300s; 0 = only on shutdown)
+    let wal_checkpoint_secs = src.var("WAL_CHECKPOINT_SECS")
+        .unwrap_or_else(|_| "300".to_string())
+        .parse()
+        .unwrap_or(300);
+
+    // ✅ Netflow alerts: token:threshold pairs, POSTed to WEBHOOK_URL (default: none)
+    let alert_rules = parse_alert_rules(&src.var("ALERT_RULES").unwrap_or_default())?;
+
+    let webhook_url = src.var("WEBHOOK_URL")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+    if let Some(url) = &webhook_url {
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(IndexerError::Config(format!("Invalid WEBHOOK_URL {:?}: expected an http(s) URL", url)));
+        }
+    }
+
+    let alert_cooldown_secs = src.var("ALERT_COOLDOWN_SECS")
+        .unwrap_or_else(|_| "600".to_string())
+        .parse()
+        .unwrap_or(600);
+
+    // ✅ Minimum scaled transfer amount to record (default: 0 = record everything)
+    let min_transfer_amount = src.var("MIN_TRANSFER_AMOUNT")
+        .unwrap_or_else(|_| "0".to_string())
+        .trim()
+        .parse()
+        .unwrap_or(Decimal::ZERO);
+
+    // ✅ Decimals to assume when a token's decimals() call fails (default: 18)
+    let default_decimals = src.var("DEFAULT_DECIMALS")
+        .unwrap_or_else(|_| DEFAULT_TOKEN_DECIMALS.to_string())
+        .trim()
+        .parse()
+        .unwrap_or(DEFAULT_TOKEN_DECIMALS);
+    if default_decimals > MAX_DECIMAL_SCALE {
+        return Err(IndexerError::Config(format!(
+            "Invalid DEFAULT_DECIMALS {}: amounts can be scaled by at most {} decimals", default_decimals, MAX_DECIMAL_SCALE
+        )));
+    }
+
+    // ✅ Index mode (default: exchange_only; RECORD_ALL=true is a legacy alias for all)
+    let index_mode = match src.var("INDEX_MODE") {
+        Ok(mode) => match mode.trim().to_lowercase().as_str() {
+            "exchange_only" => IndexMode::ExchangeOnly,
+            "all" => IndexMode::All,
+            other => {
+                return Err(IndexerError::Config(format!(
+                    "Invalid INDEX_MODE {:?}: expected exchange_only or all", other
+                )))
+            }
+        },
+        Err(_) => {
+            let record_all = src.var("RECORD_ALL")
+                .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false);
+            if record_all { IndexMode::All } else { IndexMode::ExchangeOnly }
+        }
+    };
+
+    // ✅ Netflow units (default: token = scaled by decimals; raw = on-chain integers)
+    let netflow_units = match src.var("NETFLOW_UNITS") {
+        Ok(units) => match units.trim().to_lowercase().as_str() {
+            "token" => NetflowUnits::Token,
+            "raw" => NetflowUnits::Raw,
+            other => {
+                return Err(IndexerError::Config(format!(
+                    "Invalid NETFLOW_UNITS {:?}: expected token or raw", other
+                )))
+            }
+        },
+        Err(_) => NetflowUnits::Token,
+    };
+
     // ✅ Binance exchange wallets (default: empty set)
-    let exchange_set: HashSet<Address> = env::var("EXCHANGE_ADDRESSES")
-        .or_else(|_| env::var("BINANCE_WALLETS"))
+    let mut invalid_addresses = Vec::new();
+    let mut exchange_set: HashSet<Address> = HashSet::new();
+    for s in src.var("EXCHANGE_ADDRESSES")
+        .or_else(|_| src.var("BINANCE_WALLETS"))
         .unwrap_or_default()
         .split(',')
-        .filter_map(|s| s.parse::<Address>().ok())
-        .collect();
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        match s.parse::<Address>() {
+            Ok(addr) => {
+                exchange_set.insert(addr);
+            }
+            Err(_) => invalid_addresses.push(s.to_string()),
+        }
+    }
+
+    // ✅ Label for exchange wallets seeded from config (default: Binance)
+    let exchange_label = src.var("EXCHANGE_LABEL").unwrap_or_else(|_| "Binance".to_string());
+
+    // ✅ More exchange wallets from a CSV/JSON file of address,label (default: none)
+    let exchange_addresses_file = src.var("EXCHANGE_ADDRESSES_FILE").ok().filter(|p| !p.trim().is_empty());
+    let env_exchange_set = exchange_set;
+    let (exchange_set, exchange_labels) = match &exchange_addresses_file {
+        Some(path) => merge_exchanges(&env_exchange_set, read_exchange_file(path)?),
+        None => (env_exchange_set.clone(), BTreeMap::new()),
+    };
+
+    // ✅ Re-read EXCHANGE_ADDRESSES_FILE every N seconds (default: 0 = only at startup)
+    let exchange_reload_secs = src.var("EXCHANGE_ADDRESSES_RELOAD_SECS")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .unwrap_or(0);
+
+    // ✅ Ask eth_getLogs only for transfers from/to an exchange, via the
+    // indexed from/to topics (default: false; exchange_only mode only)
+    let exchange_topic_filter = src.var("EXCHANGE_TOPIC_FILTER")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+
+    // ✅ Exchange wallets OR-ed into one topic filter; more are split across
+    // queries (default: 100; providers cap filter size)
+    let exchange_topic_batch = src.var("EXCHANGE_TOPIC_BATCH")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse()
+        .unwrap_or(100)
+        .max(1);
 
     // ✅ Token contract addresses (default: empty set)
-    let token_set: HashSet<String> = env::var("TOKEN_ADDRESSES")
-        .or_else(|_| env::var("POL_TOKEN").map(|s| s.to_string()))
+    let token_set: HashSet<String> = src.var("TOKEN_ADDRESSES")
+        .or_else(|_| src.var("POL_TOKEN").map(|s| s.to_string()))
         .unwrap_or_default()
         .split(',')
         .map(|s| s.trim().to_string())
         .filter(|s| !s.is_empty())
         .collect();
+    invalid_addresses.extend(
+        token_set.iter().filter(|t| t.parse::<Address>().is_err()).cloned(),
+    );
+
+    // ✅ Per-token event signatures (default: Transfer only for every token)
+    let event_topics = parse_event_topics(&src.var("EVENT_TOPICS").unwrap_or_default())?;
+
+    // ✅ Per-token Transfer value layout, for tokens auto-detection gets wrong
+    // (default: auto for every token)
+    let value_layouts = parse_value_layouts(&src.var("TRANSFER_VALUE_LAYOUT").unwrap_or_default())?;
+
+    // ✅ Per-token share of NEUTRAL transfers to record under INDEX_MODE=all
+    // (default: all of them); IN/OUT transfers are always recorded
+    let sample_rates = parse_sample_rates(&src.var("SAMPLE_RATE").unwrap_or_default())?;
+
+    // ✅ Fee-on-transfer / rebasing tokens, whose Transfer value isn't what the
+    // recipient ends up with (default: none)
+    let fee_on_transfer: HashSet<String> = src.var("FEE_ON_TRANSFER_TOKENS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect();
+    invalid_addresses.extend(
+        fee_on_transfer.iter().filter(|t| t.parse::<Address>().is_err()).cloned(),
+    );
 
     let cfg = Config {
         rpc_http_url,
+        chain_id,
         db_path,
         confirmations,
+        token_confirmations,
+        block_tag,
+        start_block,
+        backfill_blocks,
+        backfill_chunk_blocks,
+        max_batch_rows,
+        backfill_range,
+        lookback_blocks,
+        rescan_depth,
+        rpc_pause_ms,
+        rpc_batch_size,
+        rpc_breaker_threshold,
+        rpc_breaker_cooldown_secs,
+        rpc_pool_max_idle_per_host,
+        rpc_pool_idle_timeout_secs,
+        rpc_tcp_keepalive_secs,
+        rpc_http2_prior_knowledge,
+        rpc_http2_keepalive_secs,
+        poll_interval_secs,
+        retry_base_ms,
+        retry_cap_secs,
         exchange_set,
+        env_exchange_set,
+        exchange_label,
+        exchange_labels,
+        exchange_addresses_file,
+        exchange_reload_secs,
+        exchange_topic_filter,
+        exchange_topic_batch,
         token_set,
+        event_topics,
+        value_layouts,
+        sample_rates,
+        fee_on_transfer,
+        bind_addr,
         port,
+        unix_socket,
+        api_timeout_secs,
+        api_max_concurrency,
+        api_max_body_bytes,
+        api_cache_max_age_secs,
+        api_keys,
+        allowed_origins,
+        dev_mode,
+        request_id_header,
+        token_stale_secs,
+        retention_days,
+        wal_checkpoint_secs,
+        alert_rules,
+        webhook_url,
+        alert_cooldown_secs,
+        min_transfer_amount,
+        default_decimals,
+        index_mode,
+        netflow_units,
+        invalid_addresses,
     };
 
     // ✅ Log loaded config for debugging
     info!("Loaded config: {:?}", cfg);
 
+    // ✅ Empty token/exchange sets mean nothing gets indexed; say so loudly
+    let problems = empty_set_warnings(&cfg.token_set, &cfg.exchange_set, cfg.index_mode);
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    let strict = src.var("STRICT_CONFIG")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    if strict && !problems.is_empty() {
+        return Err(IndexerError::Config(format!("STRICT_CONFIG: {}", problems.join("; "))));
+    }
+
     Ok(cfg)
 }
+
+/// `confirmations` in CONFIG_FILE: a number, or a table of token → number
+/// with an optional `default` entry
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum FileConfirmations {
+    Global(u64),
+    PerToken(HashMap<String, u64>),
+}
+
+/// Settings read from `CONFIG_FILE`. Keys are the env var names in lowercase,
+/// lists replace the comma-separated strings, and `event_topics` is a table of
+/// token → topics (`transfer_value_layout` and `sample_rate` of token → value).
+/// Unknown keys are rejected so typos don't go unnoticed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub rpc_http_url: Option<String>,
+    pub chain_id: Option<u64>,
+    pub database_url: Option<String>,
+    pub confirmations: Option<FileConfirmations>,
+    pub block_tag: Option<BlockTag>,
+    pub start_block: Option<u64>,
+    pub backfill_blocks: Option<u64>,
+    pub backfill_chunk_blocks: Option<u64>,
+    pub max_batch_rows: Option<usize>,
+    pub lookback_blocks: Option<u64>,
+    pub rescan_depth: Option<u64>,
+    pub rpc_pause_ms: Option<u64>,
+    pub rpc_batch_size: Option<usize>,
+    pub rpc_breaker_threshold: Option<u32>,
+    pub rpc_breaker_cooldown_secs: Option<u64>,
+    pub rpc_pool_max_idle_per_host: Option<usize>,
+    pub rpc_pool_idle_timeout_secs: Option<u64>,
+    pub rpc_tcp_keepalive_secs: Option<u64>,
+    pub rpc_http2_prior_knowledge: Option<bool>,
+    pub rpc_http2_keepalive_secs: Option<u64>,
+    pub poll_interval_secs: Option<u64>,
+    pub bind_addr: Option<String>,
+    pub retry_base_ms: Option<u64>,
+    pub retry_cap_secs: Option<u64>,
+    pub port: Option<u16>,
+    pub unix_socket: Option<String>,
+    pub api_timeout_secs: Option<u64>,
+    pub api_max_concurrency: Option<usize>,
+    pub api_max_body_bytes: Option<usize>,
+    pub api_cache_max_age_secs: Option<u64>,
+    pub api_keys: Option<Vec<String>>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub dev_mode: Option<bool>,
+    pub request_id_header: Option<String>,
+    pub token_stale_secs: Option<u64>,
+    pub retention_days: Option<u64>,
+    pub wal_checkpoint_secs: Option<u64>,
+    pub alert_rules: Option<HashMap<String, String>>, // token -> threshold, strings so no precision is lost
+    pub webhook_url: Option<String>,
+    pub alert_cooldown_secs: Option<u64>,
+    pub min_transfer_amount: Option<String>, // string so no precision is lost
+    pub default_decimals: Option<u32>,
+    pub index_mode: Option<IndexMode>,
+    pub netflow_units: Option<NetflowUnits>,
+    pub exchange_addresses: Option<Vec<String>>,
+    pub exchange_label: Option<String>,
+    pub exchange_addresses_file: Option<String>,
+    pub exchange_addresses_reload_secs: Option<u64>,
+    pub exchange_topic_filter: Option<bool>,
+    pub exchange_topic_batch: Option<usize>,
+    pub token_addresses: Option<Vec<String>>,
+    pub event_topics: Option<HashMap<String, Vec<String>>>,
+    pub transfer_value_layout: Option<HashMap<String, ValueLayout>>,
+    pub sample_rate: Option<HashMap<String, f64>>,
+    pub fee_on_transfer_tokens: Option<Vec<String>>,
+    pub strict_config: Option<bool>,
+}
+
+impl FileConfig {
+    /// Flatten into the env var representation `load()` already parses
+    fn into_vars(self) -> HashMap<&'static str, String> {
+        let mut vars = HashMap::new();
+        let mut set = |key: &'static str, value: Option<String>| {
+            if let Some(value) = value {
+                vars.insert(key, value);
+            }
+        };
+        let num = |v: Option<u64>| v.map(|n| n.to_string());
+        let size = |v: Option<usize>| v.map(|n| n.to_string());
+        let list = |v: Option<Vec<String>>| v.map(|l| l.join(","));
+
+        set("RPC_HTTP_URL", self.rpc_http_url);
+        set("CHAIN_ID", num(self.chain_id));
+        set("DATABASE_URL", self.database_url);
+        set("CONFIRMATIONS", self.confirmations.map(|c| match c {
+            FileConfirmations::Global(n) => n.to_string(),
+            FileConfirmations::PerToken(map) => map
+                .into_iter()
+                .map(|(token, n)| format!("{}:{}", token, n))
+                .collect::<Vec<_>>()
+                .join(","),
+        }));
+        set("BLOCK_TAG", self.block_tag.map(|t| t.as_str().to_string()));
+        set("START_BLOCK", num(self.start_block));
+        set("BACKFILL_BLOCKS", num(self.backfill_blocks));
+        set("BACKFILL_CHUNK_BLOCKS", num(self.backfill_chunk_blocks));
+        set("MAX_BATCH_ROWS", size(self.max_batch_rows));
+        set("LOOKBACK_BLOCKS", num(self.lookback_blocks));
+        set("RESCAN_DEPTH", num(self.rescan_depth));
+        set("RPC_PAUSE_MS", num(self.rpc_pause_ms));
+        set("RPC_BATCH_SIZE", size(self.rpc_batch_size));
+        set("RPC_BREAKER_THRESHOLD", self.rpc_breaker_threshold.map(|n| n.to_string()));
+        set("RPC_BREAKER_COOLDOWN_SECS", num(self.rpc_breaker_cooldown_secs));
+        set("RPC_POOL_MAX_IDLE_PER_HOST", size(self.rpc_pool_max_idle_per_host));
+        set("RPC_POOL_IDLE_TIMEOUT_SECS", num(self.rpc_pool_idle_timeout_secs));
+        set("RPC_TCP_KEEPALIVE_SECS", num(self.rpc_tcp_keepalive_secs));
+        set("RPC_HTTP2_PRIOR_KNOWLEDGE", self.rpc_http2_prior_knowledge.map(|b| b.to_string()));
+        set("RPC_HTTP2_KEEPALIVE_SECS", num(self.rpc_http2_keepalive_secs));
+        set("POLL_INTERVAL_SECS", num(self.poll_interval_secs));
+        set("BIND_ADDR", self.bind_addr);
+        set("RETRY_BASE_MS", num(self.retry_base_ms));
+        set("RETRY_CAP_SECS", num(self.retry_cap_secs));
+        set("PORT", self.port.map(|p| p.to_string()));
+        set("UNIX_SOCKET", self.unix_socket);
+        set("API_TIMEOUT_SECS", num(self.api_timeout_secs));
+        set("API_MAX_CONCURRENCY", size(self.api_max_concurrency));
+        set("API_MAX_BODY_BYTES", size(self.api_max_body_bytes));
+        set("API_CACHE_MAX_AGE_SECS", num(self.api_cache_max_age_secs));
+        set("API_KEYS", list(self.api_keys));
+        set("ALLOWED_ORIGINS", list(self.allowed_origins));
+        set("DEV_MODE", self.dev_mode.map(|b| b.to_string()));
+        set("REQUEST_ID_HEADER", self.request_id_header);
+        set("TOKEN_STALE_SECS", num(self.token_stale_secs));
+        set("RETENTION_DAYS", num(self.retention_days));
+        set("WAL_CHECKPOINT_SECS", num(self.wal_checkpoint_secs));
+        set("ALERT_RULES", self.alert_rules.map(|map| {
+            map.into_iter()
+                .map(|(token, threshold)| format!("{}:{}", token, threshold))
+                .collect::<Vec<_>>()
+                .join(",")
+        }));
+        set("WEBHOOK_URL", self.webhook_url);
+        set("ALERT_COOLDOWN_SECS", num(self.alert_cooldown_secs));
+        set("MIN_TRANSFER_AMOUNT", self.min_transfer_amount);
+        set("DEFAULT_DECIMALS", self.default_decimals.map(|d| d.to_string()));
+        set("INDEX_MODE", self.index_mode.map(|m| match m {
+            IndexMode::ExchangeOnly => "exchange_only".to_string(),
+            IndexMode::All => "all".to_string(),
+        }));
+        set("NETFLOW_UNITS", self.netflow_units.map(|u| u.as_str().to_string()));
+        set("EXCHANGE_ADDRESSES", list(self.exchange_addresses));
+        set("EXCHANGE_LABEL", self.exchange_label);
+        set("EXCHANGE_ADDRESSES_FILE", self.exchange_addresses_file);
+        set("EXCHANGE_ADDRESSES_RELOAD_SECS", num(self.exchange_addresses_reload_secs));
+        set("EXCHANGE_TOPIC_FILTER", self.exchange_topic_filter.map(|b| b.to_string()));
+        set("EXCHANGE_TOPIC_BATCH", size(self.exchange_topic_batch));
+        set("TOKEN_ADDRESSES", list(self.token_addresses));
+        set("EVENT_TOPICS", self.event_topics.map(|map| {
+            map.into_iter()
+                .map(|(token, topics)| format!("{}={}", token, topics.join("|")))
+                .collect::<Vec<_>>()
+                .join(";")
+        }));
+        set("TRANSFER_VALUE_LAYOUT", self.transfer_value_layout.map(|map| {
+            map.into_iter()
+                .map(|(token, layout)| format!("{}={}", token, layout.as_str()))
+                .collect::<Vec<_>>()
+                .join(";")
+        }));
+        set("SAMPLE_RATE", self.sample_rate.map(|map| {
+            map.into_iter()
+                .map(|(token, rate)| format!("{}={}", token, rate))
+                .collect::<Vec<_>>()
+                .join(";")
+        }));
+        set("FEE_ON_TRANSFER_TOKENS", list(self.fee_on_transfer_tokens));
+        set("STRICT_CONFIG", self.strict_config.map(|b| b.to_string()));
+        vars
+    }
+}
+
+/// Legacy env aliases: if one is set, the env wins over the file's primary key
+const ALIASES: &[(&str, &str)] = &[
+    ("RPC_HTTP_URL", "POLYGON_RPC"),
+    ("EXCHANGE_ADDRESSES", "BINANCE_WALLETS"),
+    ("TOKEN_ADDRESSES", "POL_TOKEN"),
+    ("INDEX_MODE", "RECORD_ALL"),
+];
+
+/// Where `load()` reads settings from: the process env first, then `CONFIG_FILE`
+struct Source {
+    env: bool,
+    file: HashMap<&'static str, String>,
+}
+
+impl Source {
+    fn load() -> Result<Self> {
+        let file = match env::var("CONFIG_FILE") {
+            Ok(path) if !path.trim().is_empty() => {
+                let raw = std::fs::read_to_string(path.trim())
+                    .map_err(|e| IndexerError::Config(format!("Cannot read CONFIG_FILE {:?}: {}", path, e)))?;
+                let vars = parse_config_file(&raw)
+                    .map_err(|e| IndexerError::Config(format!("Invalid CONFIG_FILE {:?}: {}", path, e)))?;
+                info!("Loaded {} setting(s) from {}", vars.len(), path.trim());
+                vars
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Self { env: true, file })
+    }
+
+    /// Neither the env nor a file: every setting at its default
+    fn empty() -> Self {
+        Self { env: false, file: HashMap::new() }
+    }
+
+    /// Same contract as `env::var`, falling back to the file
+    fn var(&self, key: &str) -> std::result::Result<String, env::VarError> {
+        if !self.env {
+            return self.file.get(key).cloned().ok_or(env::VarError::NotPresent);
+        }
+        if let Ok(value) = env::var(key) {
+            return Ok(value);
+        }
+        let alias_set = ALIASES
+            .iter()
+            .any(|(primary, alias)| *primary == key && env::var(alias).is_ok());
+        if alias_set {
+            return Err(env::VarError::NotPresent);
+        }
+        self.file.get(key).cloned().ok_or(env::VarError::NotPresent)
+    }
+}
+
+fn parse_config_file(raw: &str) -> std::result::Result<HashMap<&'static str, String>, toml::de::Error> {
+    Ok(toml::from_str::<FileConfig>(raw)?.into_vars())
+}
+
+impl Config {
+    /// Every setting at its default, without reading the env, `.env` or
+    /// CONFIG_FILE, so tests and benches behave the same on any machine
+    pub fn defaults() -> Config {
+        from_source(&Source::empty()).expect("the defaults are valid")
+    }
+
+    /// Circuit breaker for the RPC endpoint; share one instance between the
+    /// indexer's client and the API so `/health` reports what the indexer sees
+    pub fn rpc_breaker(&self) -> CircuitBreaker {
+        CircuitBreaker::new(self.rpc_breaker_threshold, Duration::from_secs(self.rpc_breaker_cooldown_secs))
+    }
+
+    /// Connection pool and protocol settings for the RPC client
+    pub fn rpc_http_options(&self) -> HttpOptions {
+        let secs = |s: u64| (s > 0).then(|| Duration::from_secs(s));
+        HttpOptions {
+            pool_max_idle_per_host: self.rpc_pool_max_idle_per_host,
+            pool_idle_timeout: secs(self.rpc_pool_idle_timeout_secs),
+            tcp_keepalive: secs(self.rpc_tcp_keepalive_secs),
+            http2_prior_knowledge: self.rpc_http2_prior_knowledge,
+            http2_keep_alive_interval: secs(self.rpc_http2_keepalive_secs),
+        }
+    }
+
+    /// Event signatures to fetch for `token`: Transfer, plus any EVENT_TOPICS adds
+    pub fn topics_for(&self, token: &str) -> Vec<String> {
+        self.event_topics
+            .get(&token.to_lowercase())
+            .cloned()
+            .unwrap_or_else(|| vec![TRANSFER_TOPIC.to_string()])
+    }
+
+    /// Label stored in `exchanges` for a newly added wallet
+    pub fn exchange_label_for(&self, wallet: &Address) -> &str {
+        self.exchange_labels.get(wallet).unwrap_or(&self.exchange_label)
+    }
+
+    /// Re-read EXCHANGE_ADDRESSES_FILE. The EXCHANGE_ADDRESSES wallets stay;
+    /// returns the new `(exchange_set, exchange_labels)` only if the wallets
+    /// differ from the current ones. A label-only edit isn't a change: labels
+    /// are only used when a wallet is first added. Nothing is changed here.
+    pub fn reread_exchange_file(&self) -> Result<Option<ExchangeWallets>> {
+        let Some(path) = &self.exchange_addresses_file else { return Ok(None) };
+        let (set, labels) = merge_exchanges(&self.env_exchange_set, read_exchange_file(path)?);
+        Ok((set != self.exchange_set).then_some((set, labels)))
+    }
+
+    /// Exchange wallets as 32-byte `from`/`to` topics (sorted) when
+    /// EXCHANGE_TOPIC_FILTER narrows eth_getLogs to them. None means fetch
+    /// every log, as INDEX_MODE=all needs its NEUTRAL transfers.
+    pub fn exchange_topics(&self) -> Option<Vec<String>> {
+        if !self.exchange_topic_filter || self.index_mode != IndexMode::ExchangeOnly {
+            return None;
+        }
+        let mut topics: Vec<String> = self.exchange_set
+            .iter()
+            .map(|addr| format!("0x{:0>64}", hex::encode(addr.as_slice())))
+            .collect();
+        topics.sort();
+        Some(topics)
+    }
+
+    /// Share of `token`'s NEUTRAL transfers to record; 1 unless SAMPLE_RATE says otherwise
+    pub fn sample_rate_for(&self, token: &str) -> f64 {
+        self.sample_rates.get(&token.to_lowercase()).copied().unwrap_or(1.0)
+    }
+
+    /// Blocks `token`'s scan stays behind the head: its CONFIRMATIONS entry,
+    /// else the default. 0 with BLOCK_TAG, whose block is already final.
+    pub fn confirmations_for(&self, token: &str) -> u64 {
+        if self.block_tag.is_some() {
+            return 0;
+        }
+        self.token_confirmations.get(&token.to_lowercase()).copied().unwrap_or(self.confirmations)
+    }
+
+    /// Where `token`'s Transfer value is; auto unless TRANSFER_VALUE_LAYOUT says otherwise
+    pub fn value_layout_for(&self, token: &str) -> ValueLayout {
+        self.value_layouts.get(&token.to_lowercase()).copied().unwrap_or_default()
+    }
+}
+
+/// One wallet of an EXCHANGE_ADDRESSES_FILE in JSON form
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExchangeEntry {
+    address: String,
+    label: Option<String>,
+}
+
+/// Read EXCHANGE_ADDRESSES_FILE: JSON (`[{"address", "label"}]`) if the path
+/// ends in `.json`, else CSV
+fn read_exchange_file(path: &str) -> Result<Vec<(Address, Option<String>)>> {
+    let raw = std::fs::read_to_string(path).map_err(|e| IndexerError::Config(format!(
+        "Cannot read EXCHANGE_ADDRESSES_FILE {}: {}", path, e
+    )))?;
+    parse_exchange_list(&raw, path.to_lowercase().ends_with(".json"))
+        .map_err(|e| IndexerError::Config(format!("EXCHANGE_ADDRESSES_FILE {}: {}", path, e)))
+}
+
+/// Parse an exchange wallet list. CSV is one `address[,label]` per line;
+/// blank lines, `#` comments and an `address,label` header are skipped. Any
+/// address that doesn't parse fails the whole file, so a bad edit never
+/// silently drops a wallet.
+fn parse_exchange_list(raw: &str, json: bool) -> std::result::Result<Vec<(Address, Option<String>)>, String> {
+    let entries: Vec<(String, Option<String>)> = if json {
+        serde_json::from_str::<Vec<ExchangeEntry>>(raw)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|e| (e.address, e.label))
+            .collect()
+    } else {
+        raw.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter(|line| !line.to_lowercase().starts_with("address"))
+            .map(|line| match line.split_once(',') {
+                Some((address, label)) => (address.to_string(), Some(label.to_string())),
+                None => (line.to_string(), None),
+            })
+            .collect()
+    };
+
+    entries
+        .into_iter()
+        .map(|(address, label)| {
+            let parsed = address.trim().parse::<Address>().map_err(|_| format!("invalid address {:?}", address.trim()))?;
+            let label = label.map(|l| l.trim().trim_matches('"').to_string()).filter(|l| !l.is_empty());
+            Ok((parsed, label))
+        })
+        .collect()
+}
+
+/// Exchange wallets and the labels some of them were given
+pub type ExchangeWallets = (HashSet<Address>, BTreeMap<Address, String>);
+
+/// The EXCHANGE_ADDRESSES wallets plus the file's, with the file's labels
+fn merge_exchanges(env_set: &HashSet<Address>, file: Vec<(Address, Option<String>)>) -> ExchangeWallets {
+    let mut set = env_set.clone();
+    let mut labels = BTreeMap::new();
+    for (address, label) in file {
+        set.insert(address);
+        if let Some(label) = label {
+            labels.insert(address, label);
+        }
+    }
+    (set, labels)
+}
+
+/// Parse `SAMPLE_RATE`, e.g. `0xtoken=0.1;0xother=0.01`: `;`-separated
+/// token=rate entries, each rate in (0, 1]
+fn parse_sample_rates(raw: &str) -> Result<HashMap<String, f64>> {
+    let mut map = HashMap::new();
+    for entry in raw.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (token, rate) = entry
+            .split_once('=')
+            .ok_or_else(|| IndexerError::Config(format!(
+                "Invalid SAMPLE_RATE entry {:?}: expected token=rate", entry
+            )))?;
+
+        let rate = rate.trim().parse::<f64>().ok().filter(|r| *r > 0.0 && *r <= 1.0).ok_or_else(|| {
+            IndexerError::Config(format!(
+                "Invalid SAMPLE_RATE {:?} for {}: expected a number in (0, 1]", rate.trim(), token.trim()
+            ))
+        })?;
+        map.insert(token.trim().to_lowercase(), rate);
+    }
+    Ok(map)
+}
+
+/// Parse `TRANSFER_VALUE_LAYOUT`, e.g. `0xtoken=topic;0xnft=data`: `;`-separated
+/// token=layout entries, the layout being `auto`, `data` or `topic`
+fn parse_value_layouts(raw: &str) -> Result<HashMap<String, ValueLayout>> {
+    let mut map = HashMap::new();
+    for entry in raw.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (token, layout) = entry
+            .split_once('=')
+            .ok_or_else(|| IndexerError::Config(format!(
+                "Invalid TRANSFER_VALUE_LAYOUT entry {:?}: expected token=layout", entry
+            )))?;
+
+        let layout = match layout.trim().to_lowercase().as_str() {
+            "auto" => ValueLayout::Auto,
+            "data" => ValueLayout::Data,
+            "topic" => ValueLayout::Topic,
+            _ => {
+                return Err(IndexerError::Config(format!(
+                    "Invalid TRANSFER_VALUE_LAYOUT {:?} for {}: expected auto, data or topic", layout.trim(), token.trim()
+                )))
+            }
+        };
+        map.insert(token.trim().to_lowercase(), layout);
+    }
+    Ok(map)
+}
+
+/// Parse `EVENT_TOPICS`, e.g. `0xtoken=transfer|approval;0xother=0x<topic0>`.
+/// Entries are `;`-separated, topics `|`-separated; `transfer`, `approval`,
+/// `deposit` and `withdrawal` are shorthands, anything else must be a 32-byte
+/// hex topic. The topics are fetched on top of Transfer, which every list
+/// starts with whether it's named or not.
+fn parse_event_topics(raw: &str) -> Result<HashMap<String, Vec<String>>> {
+    let mut map = HashMap::new();
+    for entry in raw.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (token, topics) = entry
+            .split_once('=')
+            .ok_or_else(|| IndexerError::Config(format!(
+                "Invalid EVENT_TOPICS entry {:?}: expected token=topic|topic", entry
+            )))?;
+
+        let mut list = vec![TRANSFER_TOPIC.to_string()];
+        let mut named = 0;
+        for topic in topics.split('|').map(str::trim).filter(|t| !t.is_empty()) {
+            let topic = match topic.to_lowercase().as_str() {
+                "transfer" => TRANSFER_TOPIC.to_string(),
+                "approval" => APPROVAL_TOPIC.to_string(),
+                "deposit" => DEPOSIT_TOPIC.to_string(),
+                "withdrawal" => WITHDRAWAL_TOPIC.to_string(),
+                hex if hex.len() == 66
+                    && hex.starts_with("0x")
+                    && hex[2..].bytes().all(|b| b.is_ascii_hexdigit()) => hex.to_string(),
+                _ => {
+                    return Err(IndexerError::Config(format!(
+                        "Invalid EVENT_TOPICS topic {:?} for {}", topic, token.trim()
+                    )))
+                }
+            };
+            named += 1;
+            if !list.contains(&topic) {
+                list.push(topic);
+            }
+        }
+        if named == 0 {
+            return Err(IndexerError::Config(format!("EVENT_TOPICS entry for {} has no topics", token.trim())));
+        }
+        map.insert(token.trim().to_lowercase(), list);
+    }
+    Ok(map)
+}
+
+/// FROM_DATE: an RFC3339 timestamp, or a bare `YYYY-MM-DD` meaning midnight UTC
+fn parse_from_date(raw: &str) -> Result<DateTime<Utc>> {
+    let raw = raw.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(raw) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+        .map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        .map_err(|_| IndexerError::Config(format!(
+            "Invalid FROM_DATE {:?}: expected YYYY-MM-DD or an RFC3339 timestamp", raw
+        )))
+}
+
+/// Parse `CONFIRMATIONS`: a plain number, or per token as
+/// `0xtoken:5,0xother:12,default:2`. Returns the default (`fallback` unless
+/// given) and the per-token overrides.
+fn parse_confirmations(raw: &str, fallback: u64) -> Result<(u64, HashMap<String, u64>)> {
+    let mut default = fallback;
+    let mut per_token = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (key, value) = match entry.split_once(':') {
+            Some((key, value)) => (Some(key.trim()), value.trim()),
+            None => (None, entry),
+        };
+        let n: u64 = value.parse().map_err(|e| IndexerError::Config(format!(
+            "Invalid CONFIRMATIONS entry {:?}: {}", entry, e
+        )))?;
+        match key {
+            None | Some("default") => default = n,
+            Some(token) => {
+                per_token.insert(token.to_lowercase(), n);
+            }
+        }
+    }
+    Ok((default, per_token))
+}
+
+/// Parse `ALERT_RULES`, e.g. `0xtoken:100000,0xother:2500.5`: alert when the
+/// token's net outflow (outflow - inflow) reaches the threshold, in token units
+fn parse_alert_rules(raw: &str) -> Result<HashMap<String, Decimal>> {
+    let mut rules = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (token, threshold) = entry
+            .split_once(':')
+            .ok_or_else(|| IndexerError::Config(format!(
+                "Invalid ALERT_RULES entry {:?}: expected token:threshold", entry
+            )))?;
+        let threshold: Decimal = threshold.trim().parse().map_err(|e| IndexerError::Config(format!(
+            "Invalid ALERT_RULES threshold {:?} for {}: {}", threshold.trim(), token.trim(), e
+        )))?;
+        if threshold <= Decimal::ZERO {
+            return Err(IndexerError::Config(format!(
+                "ALERT_RULES threshold for {} must be positive (it is a net outflow)", token.trim()
+            )));
+        }
+        rules.insert(token.trim().to_lowercase(), threshold);
+    }
+    Ok(rules)
+}
+
+/// Explain why the indexer would silently record nothing, if that's the case
+fn empty_set_warnings(
+    token_set: &HashSet<String>,
+    exchange_set: &HashSet<Address>,
+    index_mode: IndexMode,
+) -> Vec<&'static str> {
+    let mut problems = Vec::new();
+    if token_set.is_empty() {
+        problems.push("TOKEN_ADDRESSES is empty: no tokens will be indexed");
+    }
+    if exchange_set.is_empty() && index_mode == IndexMode::ExchangeOnly {
+        problems.push("EXCHANGE_ADDRESSES is empty and INDEX_MODE is exchange_only: no transfers will be recorded");
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_when_token_set_is_empty() {
+        let exchanges: HashSet<Address> = [Address::ZERO].into_iter().collect();
+        let problems = empty_set_warnings(&HashSet::new(), &exchanges, IndexMode::ExchangeOnly);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("TOKEN_ADDRESSES"));
+    }
+
+    #[test]
+    fn warns_when_exchange_set_is_empty_unless_indexing_all() {
+        let tokens: HashSet<String> = ["0x0000000000000000000000000000000000001010".to_string()].into();
+        let problems = empty_set_warnings(&tokens, &HashSet::new(), IndexMode::ExchangeOnly);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("EXCHANGE_ADDRESSES"));
+
+        assert!(empty_set_warnings(&tokens, &HashSet::new(), IndexMode::All).is_empty());
+    }
+
+    #[test]
+    fn no_warnings_when_both_sets_are_populated() {
+        let tokens: HashSet<String> = ["0x0000000000000000000000000000000000001010".to_string()].into();
+        let exchanges: HashSet<Address> = [Address::ZERO].into_iter().collect();
+        assert!(empty_set_warnings(&tokens, &exchanges, IndexMode::ExchangeOnly).is_empty());
+    }
+
+    #[test]
+    fn config_file_flattens_to_env_names() {
+        let vars = parse_config_file(
+            r#"
+            rpc_http_url = "http://localhost:8545"
+            confirmations = 12
+            block_tag = "finalized"
+            index_mode = "all"
+            token_addresses = ["0xaaa", "0xbbb"]
+            fee_on_transfer_tokens = ["0xbbb"]
+            min_transfer_amount = "0.000000000000000001"
+
+            [event_topics]
+            "0xaaa" = ["transfer", "approval"]
+
+            [transfer_value_layout]
+            "0xbbb" = "topic"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(vars["RPC_HTTP_URL"], "http://localhost:8545");
+        assert_eq!(vars["CONFIRMATIONS"], "12");
+        assert_eq!(vars["BLOCK_TAG"], "finalized");
+        assert_eq!(vars["INDEX_MODE"], "all");
+        assert_eq!(vars["TOKEN_ADDRESSES"], "0xaaa,0xbbb");
+        assert_eq!(vars["FEE_ON_TRANSFER_TOKENS"], "0xbbb");
+        assert_eq!(vars["MIN_TRANSFER_AMOUNT"], "0.000000000000000001");
+        assert_eq!(vars["EVENT_TOPICS"], "0xaaa=transfer|approval");
+        assert_eq!(vars["TRANSFER_VALUE_LAYOUT"], "0xbbb=topic");
+        assert!(!vars.contains_key("PORT"));
+
+        let topics = parse_event_topics(&vars["EVENT_TOPICS"]).unwrap();
+        assert_eq!(topics["0xaaa"], [TRANSFER_TOPIC, APPROVAL_TOPIC]);
+
+        // per-token confirmations as a table
+        let vars = parse_config_file("[confirmations]\n\"0xaaa\" = 5\ndefault = 3").unwrap();
+        let (default, per_token) = parse_confirmations(&vars["CONFIRMATIONS"], 2).unwrap();
+        assert_eq!((default, per_token["0xaaa"]), (3, 5));
+    }
+
+    #[test]
+    fn config_file_rejects_unknown_keys() {
+        let err = parse_config_file("confirmatons = 3").unwrap_err();
+        assert!(err.to_string().contains("confirmatons"));
+        assert!(parse_config_file("port = \"80\"").is_err());
+    }
+
+    #[test]
+    fn parses_event_topics_with_shorthands() {
+        let map = parse_event_topics("0xAbC=transfer|approval ; 0xdef=approval; 0x123=transfer|deposit|withdrawal").unwrap();
+        assert_eq!(map["0xabc"], [TRANSFER_TOPIC, APPROVAL_TOPIC]);
+        // Transfer is kept even when the entry doesn't name it
+        assert_eq!(map["0xdef"], [TRANSFER_TOPIC, APPROVAL_TOPIC]);
+        assert_eq!(map["0x123"], [TRANSFER_TOPIC, DEPOSIT_TOPIC, WITHDRAWAL_TOPIC]);
+        assert!(parse_event_topics("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_raw_event_topic() {
+        let topic = format!("0x{}", "ab".repeat(32));
+        let map = parse_event_topics(&format!("0xabc={}", topic.to_uppercase().replace("0X", "0x"))).unwrap();
+        assert_eq!(map["0xabc"], [TRANSFER_TOPIC.to_string(), topic]);
+    }
+
+    #[test]
+    fn redact_url_keeps_only_scheme_host_and_port() {
+        assert_eq!(redact_url("https://polygon-rpc.com"), "https://polygon-rpc.com");
+        assert_eq!(redact_url("http://127.0.0.1:8545/"), "http://127.0.0.1:8545");
+        assert_eq!(redact_url("https://polygon-mainnet.g.alchemy.com/v2/KEY"), "https://polygon-mainnet.g.alchemy.com/<redacted>");
+        assert_eq!(redact_url("https://rpc.example.com?apikey=KEY"), "https://rpc.example.com/<redacted>");
+        assert_eq!(redact_url("https://user:pw@rpc.example.com"), "https://<redacted>@rpc.example.com");
+        assert_eq!(redact_url("not a url"), "<redacted>");
+    }
+
+    #[test]
+    fn parses_per_token_confirmations() {
+        assert_eq!(parse_confirmations("", 2).unwrap(), (2, HashMap::new()));
+        assert_eq!(parse_confirmations("12", 2).unwrap(), (12, HashMap::new()));
+
+        let (default, per_token) = parse_confirmations("0xAbC:5, default:3 ,0xdef:0", 2).unwrap();
+        assert_eq!(default, 3);
+        assert_eq!((per_token["0xabc"], per_token["0xdef"]), (5, 0));
+        // tokens alone keep the fallback default
+        assert_eq!(parse_confirmations("0xabc:5", 2).unwrap().0, 2);
+
+        for bad in ["-1", "0xabc:", "0xabc:five", "default:1.5"] {
+            assert!(parse_confirmations(bad, 2).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_sample_rates() {
+        let map = parse_sample_rates("0xAbC=0.1 ; 0xdef = 1").unwrap();
+        assert_eq!((map["0xabc"], map["0xdef"]), (0.1, 1.0));
+        assert!(parse_sample_rates("").unwrap().is_empty());
+        for bad in ["0xabc", "0xabc=0", "0xabc=1.5", "0xabc=-0.1", "0xabc=half", "0xabc=NaN"] {
+            assert!(parse_sample_rates(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_exchange_files_as_csv_or_json() {
+        let (a, b) = ("0xF977814e90dA44bFA03b6295A0616a897441aceC", "0xe7804c37c13166fF0b37F5aE0BB07A3aEbb6e245");
+        let csv = format!("address,label\n# cold wallets\n{a}, Binance 8\n\n{}\n", b.to_lowercase());
+        let json = format!(r#"[{{"address": "{a}", "label": "Binance 8"}}, {{"address": "{b}"}}]"#);
+        let expected = vec![(a.parse().unwrap(), Some("Binance 8".to_string())), (b.parse().unwrap(), None)];
+        assert_eq!(parse_exchange_list(&csv, false).unwrap(), expected);
+        assert_eq!(parse_exchange_list(&json, true).unwrap(), expected);
+
+        assert!(parse_exchange_list(&format!("{a}\n0xnot-an-address,Oops"), false).is_err());
+        assert!(parse_exchange_list(r#"[{"addr": "0x1"}]"#, true).is_err());
+
+        // the file adds to EXCHANGE_ADDRESSES; labels only for wallets that have one
+        let env: HashSet<Address> = [b.parse().unwrap(), Address::ZERO].into();
+        let (set, labels) = merge_exchanges(&env, expected);
+        assert_eq!(set.len(), 3);
+        assert_eq!(labels.into_iter().collect::<Vec<_>>(), [(a.parse().unwrap(), "Binance 8".to_string())]);
+    }
+
+    #[test]
+    fn parses_transfer_value_layouts() {
+        let map = parse_value_layouts("0xAbC=topic ; 0xdef = DATA;0x123=auto").unwrap();
+        assert_eq!(map["0xabc"], ValueLayout::Topic);
+        assert_eq!(map["0xdef"], ValueLayout::Data);
+        assert_eq!(map["0x123"], ValueLayout::Auto);
+        assert!(parse_value_layouts("").unwrap().is_empty());
+        assert!(parse_value_layouts("0xabc").is_err());
+        assert!(parse_value_layouts("0xabc=indexed").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_event_topics() {
+        assert!(parse_event_topics("0xabc").is_err());
+        assert!(parse_event_topics("0xabc=").is_err());
+        assert!(parse_event_topics("0xabc=mint").is_err());
+        assert!(parse_event_topics("0xabc=0x1234").is_err());
+    }
+
+    #[test]
+    fn parses_from_date() {
+        let midnight = parse_from_date("2024-03-01").unwrap();
+        assert_eq!(midnight.to_rfc3339(), "2024-03-01T00:00:00+00:00");
+        let offset = parse_from_date(" 2024-03-01T02:30:00+02:00 ").unwrap();
+        assert_eq!(offset.to_rfc3339(), "2024-03-01T00:30:00+00:00");
+
+        assert!(parse_from_date("01/03/2024").is_err());
+        assert!(parse_from_date("2024-02-30").is_err());
+    }
+
+    #[test]
+    fn parses_alert_rules() {
+        let rules = parse_alert_rules("0xAbC:100000, 0xdef:2500.5").unwrap();
+        assert_eq!(rules["0xabc"], Decimal::from(100_000));
+        assert_eq!(rules["0xdef"], "2500.5".parse::<Decimal>().unwrap());
+        assert!(parse_alert_rules("").unwrap().is_empty());
+
+        assert!(parse_alert_rules("0xabc").is_err());
+        assert!(parse_alert_rules("0xabc:lots").is_err());
+        assert!(parse_alert_rules("0xabc:-5").is_err());
+    }
+}
@@ -1,8 +1,17 @@
-use eyre::Result;
-use rusqlite::{params, Connection};
+use alloy::primitives::{keccak256, Address};
+use rusqlite::{params, Connection, OptionalExtension, Statement, Transaction, TransactionBehavior};
 use rust_decimal::Decimal;
+use std::{collections::{BTreeMap, HashSet}, sync::{Arc, Mutex, MutexGuard, PoisonError}, thread, time::Duration};
+use tracing::{error, info, warn};
+use crate::error::{is_busy, IndexerError, Result};
+use crate::config::{DEFAULT_CHAIN_ID, DEFAULT_TOKEN_DECIMALS};
+use crate::models::{BackfillStatus, FailedRange, PurgedToken};
 
-const INIT_SQL: &str = r#"
+/// Ordered schema migrations. Each step runs once, in order; append new steps
+/// to the end and never edit one that has already shipped.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    r#"
 CREATE TABLE IF NOT EXISTS exchanges (
   id      INTEGER PRIMARY KEY AUTOINCREMENT,
   address TEXT UNIQUE NOT NULL,
@@ -29,56 +38,1899 @@ CREATE TABLE IF NOT EXISTS netflows (
   last_block     INTEGER NOT NULL,
   updated_at     TEXT NOT NULL DEFAULT (datetime('now'))
 );
-"#;
+"#,
+    // 2: gross inflow/outflow totals alongside the net
+    r#"
+ALTER TABLE netflows ADD COLUMN inflow_total  TEXT NOT NULL DEFAULT '0';
+ALTER TABLE netflows ADD COLUMN outflow_total TEXT NOT NULL DEFAULT '0';
+"#,
+    // 3: allow NEUTRAL direction (non-exchange transfers); SQLite can't alter a CHECK, so rebuild
+    r#"
+CREATE TABLE transfers_new (
+  id            INTEGER PRIMARY KEY AUTOINCREMENT,
+  block_number  INTEGER NOT NULL,
+  tx_hash       TEXT NOT NULL,
+  log_index     INTEGER NOT NULL,
+  token_address TEXT NOT NULL,
+  from_address  TEXT NOT NULL,
+  to_address    TEXT NOT NULL,
+  amount        TEXT NOT NULL, -- Decimal stored as string
+  direction     TEXT NOT NULL CHECK (direction IN ('IN','OUT','NEUTRAL')),
+  timestamp     TEXT NOT NULL DEFAULT (datetime('now')),
+  UNIQUE(tx_hash, log_index, token_address)
+);
+
+INSERT INTO transfers_new (
+  id, block_number, tx_hash, log_index, token_address,
+  from_address, to_address, amount, direction, timestamp
+)
+SELECT
+  id, block_number, tx_hash, log_index, token_address,
+  from_address, to_address, amount, direction, timestamp
+FROM transfers;
+
+DROP TABLE transfers;
+ALTER TABLE transfers_new RENAME TO transfers;
+"#,
+    // 4: which exchange wallet matched (NULL for NEUTRAL)
+    r#"
+ALTER TABLE transfers ADD COLUMN exchange_address TEXT;
+"#,
+    // 5: unscaled on-chain integer and the decimals used to scale `amount`
+    r#"
+ALTER TABLE transfers ADD COLUMN raw_amount TEXT; -- U256 as decimal string
+ALTER TABLE transfers ADD COLUMN decimals   INTEGER NOT NULL DEFAULT 18;
+"#,
+    // 6: numeric shadow of `amount` for ordering / range filters; `amount`
+    //    stays the canonical string, `amount_num` is for indexing only
+    r#"
+ALTER TABLE transfers ADD COLUMN amount_num REAL
+  GENERATED ALWAYS AS (CAST(amount AS REAL)) VIRTUAL;
+CREATE INDEX IF NOT EXISTS idx_transfers_token_amount_num
+  ON transfers(token_address, amount_num);
+"#,
+    // 7: non-Transfer logs picked up via EVENT_TOPICS, stored raw
+    r#"
+CREATE TABLE IF NOT EXISTS events (
+  id               INTEGER PRIMARY KEY AUTOINCREMENT,
+  block_number     INTEGER NOT NULL,
+  tx_hash          TEXT NOT NULL,
+  log_index        INTEGER NOT NULL,
+  contract_address TEXT NOT NULL,
+  topic0           TEXT NOT NULL,
+  name             TEXT,          -- e.g. 'Approval'; NULL for custom signatures
+  topics           TEXT NOT NULL, -- indexed args, comma-separated
+  data             TEXT NOT NULL,
+  timestamp        TEXT NOT NULL DEFAULT (datetime('now')),
+  UNIQUE(tx_hash, log_index, contract_address)
+);
+CREATE INDEX IF NOT EXISTS idx_events_contract_topic
+  ON events(contract_address, topic0);
+"#,
+    // 8: ERC20 metadata cache, filled via eth_call the first time a token is indexed
+    r#"
+CREATE TABLE IF NOT EXISTS tokens (
+  address    TEXT PRIMARY KEY, -- lowercased
+  name       TEXT,
+  symbol     TEXT,
+  decimals   INTEGER NOT NULL,
+  fetched_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+"#,
+    // 9: a log is identified by (block_number, log_index) — log_index is unique
+    //    per block, so keying on token was wrong. Rebuild, keeping the newest
+    //    row for any (block, index) that was stored twice.
+    r#"
+CREATE TABLE transfers_new (
+  id               INTEGER PRIMARY KEY AUTOINCREMENT,
+  block_number     INTEGER NOT NULL,
+  tx_hash          TEXT NOT NULL,
+  log_index        INTEGER NOT NULL,
+  token_address    TEXT NOT NULL,
+  from_address     TEXT NOT NULL,
+  to_address       TEXT NOT NULL,
+  amount           TEXT NOT NULL, -- Decimal stored as string
+  direction        TEXT NOT NULL CHECK (direction IN ('IN','OUT','NEUTRAL')),
+  timestamp        TEXT NOT NULL DEFAULT (datetime('now')),
+  exchange_address TEXT,
+  raw_amount       TEXT,          -- U256 as decimal string
+  decimals         INTEGER NOT NULL DEFAULT 18,
+  amount_num       REAL GENERATED ALWAYS AS (CAST(amount AS REAL)) VIRTUAL,
+  UNIQUE(block_number, log_index)
+);
+
+INSERT INTO transfers_new (
+  id, block_number, tx_hash, log_index, token_address, from_address, to_address,
+  amount, direction, timestamp, exchange_address, raw_amount, decimals
+)
+SELECT
+  id, block_number, tx_hash, log_index, token_address, from_address, to_address,
+  amount, direction, timestamp, exchange_address, raw_amount, decimals
+FROM transfers
+WHERE id IN (SELECT MAX(id) FROM transfers GROUP BY block_number, log_index);
+
+DROP TABLE transfers;
+ALTER TABLE transfers_new RENAME TO transfers;
+
+CREATE INDEX IF NOT EXISTS idx_transfers_token_amount_num
+  ON transfers(token_address, amount_num);
+"#,
+    // 10: inflow/outflow carried over from transfers deleted by retention,
+    // so netflows stay cumulative once old rows are gone
+    r#"
+CREATE TABLE IF NOT EXISTS netflow_baselines (
+  token_address        TEXT PRIMARY KEY,
+  inflow               TEXT NOT NULL DEFAULT '0',
+  outflow              TEXT NOT NULL DEFAULT '0',
+  pruned_rows          INTEGER NOT NULL DEFAULT 0,
+  pruned_through_block INTEGER NOT NULL DEFAULT 0
+);
+"#,
+    // 11: block ranges whose fetch/write failed, retried with backoff until they succeed
+    r#"
+CREATE TABLE IF NOT EXISTS failed_ranges (
+  token_address   TEXT NOT NULL,
+  from_block      INTEGER NOT NULL,
+  to_block        INTEGER NOT NULL,
+  attempts        INTEGER NOT NULL DEFAULT 1,
+  last_error      TEXT NOT NULL,
+  first_failed_at TEXT NOT NULL DEFAULT (datetime('now')),
+  next_retry_at   TEXT NOT NULL DEFAULT (datetime('now')),
+  PRIMARY KEY (token_address, from_block, to_block)
+);
+"#,
+    // 12: tag transfers with the chain they came from so one DB can hold
+    //     several chains; (block, log_index) is only unique within a chain.
+    //     Rows indexed before this were Polygon PoS (137).
+    r#"
+CREATE TABLE transfers_new (
+  id               INTEGER PRIMARY KEY AUTOINCREMENT,
+  chain_id         INTEGER NOT NULL DEFAULT 137,
+  block_number     INTEGER NOT NULL,
+  tx_hash          TEXT NOT NULL,
+  log_index        INTEGER NOT NULL,
+  token_address    TEXT NOT NULL,
+  from_address     TEXT NOT NULL,
+  to_address       TEXT NOT NULL,
+  amount           TEXT NOT NULL, -- Decimal stored as string
+  direction        TEXT NOT NULL CHECK (direction IN ('IN','OUT','NEUTRAL')),
+  timestamp        TEXT NOT NULL DEFAULT (datetime('now')),
+  exchange_address TEXT,
+  raw_amount       TEXT,          -- U256 as decimal string
+  decimals         INTEGER NOT NULL DEFAULT 18,
+  amount_num       REAL GENERATED ALWAYS AS (CAST(amount AS REAL)) VIRTUAL,
+  UNIQUE(chain_id, block_number, log_index)
+);
+
+INSERT INTO transfers_new (
+  id, block_number, tx_hash, log_index, token_address, from_address, to_address,
+  amount, direction, timestamp, exchange_address, raw_amount, decimals
+)
+SELECT id, block_number, tx_hash, log_index, token_address, from_address, to_address,
+       amount, direction, timestamp, exchange_address, raw_amount, decimals
+FROM transfers;
+
+DROP TABLE transfers;
+ALTER TABLE transfers_new RENAME TO transfers;
+
+CREATE INDEX IF NOT EXISTS idx_transfers_token_amount_num
+  ON transfers(token_address, amount_num);
+"#,
+    // 13: startup backfill progress per token, for /health/backfill
+    r#"
+CREATE TABLE IF NOT EXISTS backfill_progress (
+  token_address   TEXT PRIMARY KEY,
+  from_block      INTEGER NOT NULL,
+  to_block        INTEGER NOT NULL,
+  scanned_through INTEGER,        -- last block of the last finished chunk
+  started_at      TEXT NOT NULL DEFAULT (datetime('now')),
+  completed_at    TEXT
+);
+"#,
+    // 14: NETFLOW_UNITS the cached row was computed in, so a change of unit
+    //     is detected and recomputed at startup
+    r#"
+ALTER TABLE netflows ADD COLUMN units TEXT NOT NULL DEFAULT 'token';
+"#,
+    // 15: /transfers/largest — the API matches tokens case-insensitively, so
+    //     the plain (token_address, amount_num) index can't serve it
+    r#"
+CREATE INDEX IF NOT EXISTS idx_transfers_lower_token_amount_num
+  ON transfers(LOWER(token_address), amount_num DESC);
+"#,
+    // 16: FROM_DATE → block lookups, so the binary search over block
+    //     timestamps runs once per date and chain
+    r#"
+CREATE TABLE IF NOT EXISTS block_dates (
+  chain_id      INTEGER NOT NULL,
+  timestamp     INTEGER NOT NULL,  -- unix seconds
+  block_number  INTEGER NOT NULL,  -- first block at or after `timestamp`
+  resolved_at   TEXT NOT NULL DEFAULT (datetime('now')),
+  PRIMARY KEY (chain_id, timestamp)
+);
+"#,
+    // 17: fingerprints of config the stored data depends on (the exchange
+    //     set), so a change can be detected and applied at startup
+    r#"
+CREATE TABLE IF NOT EXISTS settings (
+  key         TEXT PRIMARY KEY,
+  value       TEXT NOT NULL,
+  updated_at  TEXT NOT NULL DEFAULT (datetime('now'))
+);
+"#,
+    // 18: netflows split by the exchange wallet each transfer matched, and
+    //     each exchange's share of the history pruned by retention
+    r#"
+CREATE TABLE IF NOT EXISTS netflows_by_exchange (
+  token_address    TEXT NOT NULL,
+  exchange_address TEXT NOT NULL,
+  cumulative_net   TEXT NOT NULL,
+  inflow_total     TEXT NOT NULL,
+  outflow_total    TEXT NOT NULL,
+  transfers        INTEGER NOT NULL,  -- retained IN/OUT rows
+  last_block       INTEGER NOT NULL,
+  updated_at       TEXT NOT NULL DEFAULT (datetime('now')),
+  units            TEXT NOT NULL DEFAULT 'token',
+  PRIMARY KEY (token_address, exchange_address)
+);
+CREATE TABLE IF NOT EXISTS netflow_exchange_baselines (
+  token_address    TEXT NOT NULL,
+  exchange_address TEXT NOT NULL,
+  inflow           TEXT NOT NULL DEFAULT '0',
+  outflow          TEXT NOT NULL DEFAULT '0',
+  PRIMARY KEY (token_address, exchange_address)
+);
+"#,
+    // 19: /transfers/by-tx — hashes are matched case-insensitively
+    r#"
+CREATE INDEX IF NOT EXISTS idx_transfers_lower_tx_hash
+  ON transfers(LOWER(tx_hash));
+"#,
+    // 20: the block's own time, looked up per fetched range; `timestamp`
+    //     holds it too from now on, and keeps the indexing time only where
+    //     this is NULL (rows indexed before)
+    r#"
+ALTER TABLE transfers ADD COLUMN block_timestamp INTEGER;  -- unix seconds
+"#,
+    // 21: /netflow/window — a token's transfers by block time
+    r#"
+CREATE INDEX IF NOT EXISTS idx_transfers_lower_token_block_timestamp
+  ON transfers(LOWER(token_address), block_timestamp);
+"#,
+    // 22: decimals assumed because decimals() couldn't be read, flagged on the
+    //     token and on every transfer scaled with them so both can be rescaled
+    r#"
+ALTER TABLE tokens ADD COLUMN decimals_assumed INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE transfers ADD COLUMN decimals_assumed INTEGER NOT NULL DEFAULT 0;
+"#,
+    // 23: /transfers/stream pages a token's history in keyset order, so each
+    //     page is an index range rather than a re-sort of the whole history
+    r#"
+CREATE INDEX IF NOT EXISTS idx_transfers_token_block
+  ON transfers(LOWER(token_address), chain_id, block_number, log_index);
+"#,
+    // 24: aggregates are per chain. Retention baselines are keyed by chain
+    //     (rows pruned before this were Polygon PoS, as in 12) and cached
+    //     netflows record the chain they were summed for
+    r#"
+CREATE TABLE netflow_baselines_new (
+  chain_id             INTEGER NOT NULL DEFAULT 137,
+  token_address        TEXT NOT NULL,
+  inflow               TEXT NOT NULL DEFAULT '0',
+  outflow              TEXT NOT NULL DEFAULT '0',
+  pruned_rows          INTEGER NOT NULL DEFAULT 0,
+  pruned_through_block INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (chain_id, token_address)
+);
+INSERT INTO netflow_baselines_new (token_address, inflow, outflow, pruned_rows, pruned_through_block)
+  SELECT token_address, inflow, outflow, pruned_rows, pruned_through_block FROM netflow_baselines;
+DROP TABLE netflow_baselines;
+ALTER TABLE netflow_baselines_new RENAME TO netflow_baselines;
+
+CREATE TABLE netflow_exchange_baselines_new (
+  chain_id         INTEGER NOT NULL DEFAULT 137,
+  token_address    TEXT NOT NULL,
+  exchange_address TEXT NOT NULL,
+  inflow           TEXT NOT NULL DEFAULT '0',
+  outflow          TEXT NOT NULL DEFAULT '0',
+  PRIMARY KEY (chain_id, token_address, exchange_address)
+);
+INSERT INTO netflow_exchange_baselines_new (token_address, exchange_address, inflow, outflow)
+  SELECT token_address, exchange_address, inflow, outflow FROM netflow_exchange_baselines;
+DROP TABLE netflow_exchange_baselines;
+ALTER TABLE netflow_exchange_baselines_new RENAME TO netflow_exchange_baselines;
+
+ALTER TABLE netflows ADD COLUMN chain_id INTEGER NOT NULL DEFAULT 137;
+ALTER TABLE netflows_by_exchange ADD COLUMN chain_id INTEGER NOT NULL DEFAULT 137;
+"#,
+];
+
+/// `settings` key holding the fingerprint of the exchange set the stored
+/// directions were derived from
+const EXCHANGE_SET_KEY: &str = "exchange_set";
+/// `settings` key holding CHAIN_ID, the chain netflows and baselines cover
+const CHAIN_ID_KEY: &str = "chain_id";
+/// `settings` key holding DEFAULT_DECIMALS, the last-resort scale for tokens
+/// with neither metadata nor stored transfers
+const DEFAULT_DECIMALS_KEY: &str = "default_decimals";
+
+/// How long SQLite itself waits on a locked database before returning SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Extra commit attempts after SQLITE_BUSY / SQLITE_LOCKED slips past the timeout
+const BUSY_RETRIES: u32 = 5;
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Compiled statements kept per connection for `prepare_cached`; the API read
+/// path alone has a dozen, and the indexer shares the connection
+const STATEMENT_CACHE_CAPACITY: usize = 64;
 
 /// Connect to SQLite (with WAL mode for performance)
 pub fn connect(path: &str) -> Result<Connection> {
     let conn = Connection::open(path)?;
     conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
     Ok(conn)
 }
 
-/// Run schema migrations
-pub fn run_migrations(conn: &Connection) -> Result<()> {
-    conn.execute_batch(INIT_SQL)?;
+/// Lock the connection shared by the indexer and the API, even if a holder
+/// panicked (the API turns handler panics into a 500 and keeps serving). A
+/// transaction left open by the panic was rolled back when it was dropped, so
+/// the connection is still consistent.
+pub fn lock(conn: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    conn.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// `PRAGMA wal_checkpoint(TRUNCATE)`: copy every WAL frame into the database
+/// and truncate the `-wal` file to zero bytes. Returns false when another
+/// connection kept it from finishing (SQLite reports busy); the WAL is then
+/// left for the next attempt.
+pub fn checkpoint(conn: &Connection) -> Result<bool> {
+    let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| r.get(0))?;
+    if busy != 0 {
+        warn!("WAL checkpoint incomplete: database busy");
+    }
+    Ok(busy == 0)
+}
+
+/// Background loop: checkpoint the WAL every `every` (WAL_CHECKPOINT_SECS) so
+/// a long-running instance doesn't grow it without bound between restarts
+pub async fn run_checkpoints(conn: Arc<Mutex<Connection>>, every: Duration) {
+    let mut ticker = tokio::time::interval(every);
+    ticker.tick().await; // the first tick is immediate; nothing to flush yet
+    loop {
+        ticker.tick().await;
+        let conn = Arc::clone(&conn);
+        match tokio::task::spawn_blocking(move || checkpoint(&lock(&conn))).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("WAL checkpoint failed: {}", e),
+            Err(e) => error!("WAL checkpoint task panicked: {:?}", e),
+        }
+    }
+}
+
+/// Commit `tx`, retrying with a linear backoff while the database is busy
+/// instead of rolling the whole batch back on the first SQLITE_BUSY.
+pub fn commit_with_retry(tx: Transaction) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        // `Transaction::commit` consumes the tx, so issue COMMIT by hand; once it
+        // succeeds the connection is back in autocommit and dropping `tx` is a no-op
+        match tx.execute_batch("COMMIT") {
+            Ok(()) => return Ok(()),
+            Err(e) if is_busy(&e) && attempt < BUSY_RETRIES => {
+                attempt += 1;
+                warn!("Database busy on commit, retry {}/{}", attempt, BUSY_RETRIES);
+                thread::sleep(BUSY_RETRY_DELAY * attempt);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Run pending schema migrations inside a single transaction
+pub fn run_migrations(conn: &mut Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version    INTEGER PRIMARY KEY,
+            applied_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );",
+    )?;
+
+    let current: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |r| r.get(0),
+    )?;
+
+    let tx = conn.transaction()?;
+    for (i, sql) in MIGRATIONS.iter().enumerate() {
+        let version = i as i64 + 1;
+        if version <= current {
+            continue;
+        }
+        tx.execute_batch(sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version) VALUES (?1)",
+            params![version],
+        )?;
+        info!("Applied migration {}", version);
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Fresh in-memory DB with every migration applied, for tests
+#[cfg(test)]
+pub(crate) fn open_in_memory() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+    run_migrations(&mut conn).unwrap();
+    conn
+}
+
+/// How `sync_exchanges` changed the `exchanges` table, addresses sorted
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExchangeDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ExchangeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Make `exchanges` match the configured set (new wallets labelled from `labels`,
+/// else `default_label`; existing labels kept). Transfers are left to `reclassify`.
+pub fn sync_exchanges(
+    conn: &mut Connection,
+    exchanges: &HashSet<Address>,
+    labels: &BTreeMap<Address, String>,
+    default_label: &str,
+) -> Result<ExchangeDiff> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut diff = ExchangeDiff::default();
+    {
+        let stored: Vec<String> = tx
+            .prepare("SELECT address FROM exchanges")?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        // compared as addresses, so a row stored in another case still matches
+        let mut present = HashSet::new();
+        let mut delete = tx.prepare("DELETE FROM exchanges WHERE address = ?1")?;
+        for address in stored {
+            match address.parse::<Address>() {
+                Ok(addr) if exchanges.contains(&addr) => {
+                    present.insert(addr);
+                }
+                _ => {
+                    delete.execute([&address])?;
+                    diff.removed.push(address);
+                }
+            }
+        }
+
+        let mut insert = tx.prepare("INSERT INTO exchanges (address, label) VALUES (?1, ?2)")?;
+        for addr in exchanges.difference(&present) {
+            let label = labels.get(addr).map_or(default_label, String::as_str);
+            insert.execute(params![addr.to_string(), label])?;
+            diff.added.push(addr.to_string());
+        }
+    }
+    commit_with_retry(tx)?;
+
+    diff.added.sort();
+    diff.removed.sort();
+    Ok(diff)
+}
+
+const UPSERT_TRANSFER_SQL: &str = r#"
+    INSERT INTO transfers (
+        block_number, tx_hash, log_index,
+        token_address, from_address, to_address,
+        amount, direction, exchange_address, raw_amount, decimals, chain_id, timestamp, block_timestamp,
+        decimals_assumed
+    )
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, COALESCE(datetime(?13, 'unixepoch'), datetime('now')), ?13, ?14)
+    ON CONFLICT(chain_id, block_number, log_index) DO UPDATE SET
+        tx_hash          = excluded.tx_hash,
+        token_address    = excluded.token_address,
+        from_address     = excluded.from_address,
+        to_address       = excluded.to_address,
+        amount           = excluded.amount,
+        raw_amount       = excluded.raw_amount,
+        decimals         = excluded.decimals,
+        decimals_assumed = excluded.decimals_assumed,
+        direction        = excluded.direction,
+        exchange_address = excluded.exchange_address,
+        timestamp        = excluded.timestamp,
+        block_timestamp  = excluded.block_timestamp
+"#;
+
+/// Store CHAIN_ID as the chain every aggregate is summed for: netflows,
+/// their baselines and retention then ignore every other chain's rows
+pub fn record_chain_id(conn: &Connection, chain_id: u64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = datetime('now')",
+        params![CHAIN_ID_KEY, chain_id.to_string()],
+    )?;
+    Ok(())
+}
+
+/// CHAIN_ID as last recorded by `record_chain_id`, else the built-in default
+pub fn chain_id(conn: &Connection) -> Result<u64> {
+    let stored: Option<String> = conn
+        .query_row("SELECT value FROM settings WHERE key = ?1", [CHAIN_ID_KEY], |r| r.get(0))
+        .optional()?;
+    match stored {
+        Some(value) => value
+            .parse()
+            .map_err(|_| IndexerError::Decode(format!("Stored chain id {:?} is not a number", value))),
+        None => Ok(DEFAULT_CHAIN_ID),
+    }
+}
+
+/// Store DEFAULT_DECIMALS so baselines and the API scale unknown tokens the
+/// way the indexer does, whichever process reads them
+pub fn record_default_decimals(conn: &Connection, decimals: u32) -> Result<()> {
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = datetime('now')",
+        params![DEFAULT_DECIMALS_KEY, decimals.to_string()],
+    )?;
+    Ok(())
+}
+
+/// DEFAULT_DECIMALS as last recorded by `record_default_decimals`, else the
+/// built-in default
+pub fn default_decimals(conn: &Connection) -> Result<u32> {
+    let stored: Option<String> = conn
+        .query_row("SELECT value FROM settings WHERE key = ?1", [DEFAULT_DECIMALS_KEY], |r| r.get(0))
+        .optional()?;
+    match stored {
+        Some(value) => value
+            .parse()
+            .map_err(|_| IndexerError::Decode(format!("Stored default decimals {:?} is not a number", value))),
+        None => Ok(DEFAULT_TOKEN_DECIMALS),
+    }
+}
+
+/// Convert an on-chain `u64` (block number, log index) to SQLite's signed
+/// INTEGER. Every such value goes through here so out-of-range input errors
+/// instead of silently wrapping negative.
+pub fn to_sql_int(value: u64) -> Result<i64> {
+    i64::try_from(value)
+        .map_err(|_| IndexerError::Decode(format!("{} does not fit in a SQLite INTEGER", value)))
+}
+
+/// A transfer row ready to be written
+#[derive(Debug, Clone)]
+pub struct NewTransfer<'a> {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub tx_hash: &'a str,
+    pub log_index: u64,
+    pub token_address: &'a str,
+    pub from: &'a str,
+    pub to: &'a str,
+    pub amount: Decimal,
+    pub direction: &'a str,
+    pub exchange_address: Option<&'a str>,
+    pub raw_amount: &'a str,
+    pub decimals: u32,
+    pub decimals_assumed: bool, // scaled with DEFAULT_DECIMALS, pending a rescale
+    pub block_timestamp: Option<u64>, // unix seconds; None stamps the indexing time
+}
+
+/// Batched transfer upserts: prepares the INSERT once and reuses it for
+/// every `push`. Create it against a `Transaction` and drop it before commit.
+pub struct TransferWriter<'conn> {
+    stmt: Statement<'conn>,
+}
+
+impl<'conn> TransferWriter<'conn> {
+    pub fn new(conn: &'conn Connection) -> Result<Self> {
+        Ok(Self {
+            stmt: conn.prepare(UPSERT_TRANSFER_SQL)?,
+        })
+    }
+
+    pub fn push(&mut self, t: &NewTransfer) -> Result<()> {
+        self.stmt.execute(params![
+            to_sql_int(t.block_number)?,
+            t.tx_hash,
+            to_sql_int(t.log_index)?,
+            t.token_address,
+            t.from,
+            t.to,
+            t.amount.to_string(),
+            t.direction,
+            t.exchange_address,
+            t.raw_amount,
+            t.decimals,
+            to_sql_int(t.chain_id)?,
+            t.block_timestamp.map(to_sql_int).transpose()?,
+            t.decimals_assumed
+        ])?;
+        Ok(())
+    }
+}
+
+/// Insert or update a single transfer (prepares per call; use
+/// `TransferWriter` for batches)
+pub fn record_transfer(conn: &Connection, transfer: &NewTransfer) -> Result<()> {
+    TransferWriter::new(conn)?.push(transfer)
+}
+
+/// A token's decimals, and whether they are DEFAULT_DECIMALS assumed because
+/// `decimals()` couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenDecimals {
+    pub decimals: u32,
+    pub assumed: bool,
+}
+
+impl From<u32> for TokenDecimals {
+    fn from(decimals: u32) -> Self {
+        TokenDecimals { decimals, assumed: false }
+    }
+}
+
+/// Stored decimals for a token, fetched or assumed, if it has a `tokens` row
+pub fn token_decimals(conn: &Connection, address: &str) -> Result<Option<TokenDecimals>> {
+    let decimals = conn
+        .query_row(
+            "SELECT decimals, decimals_assumed FROM tokens WHERE address = LOWER(?1)",
+            [address],
+            |r| Ok(TokenDecimals { decimals: r.get(0)?, assumed: r.get(1)? }),
+        )
+        .optional()?;
+    Ok(decimals)
+}
+
+/// Record `decimals` as assumed for a token with no `tokens` row yet. An
+/// existing row (fetched, or assumed under another DEFAULT_DECIMALS) is kept,
+/// so all of a token's rows share one scale.
+pub fn assume_token_decimals(conn: &Connection, address: &str, decimals: u32) -> Result<()> {
+    conn.execute(
+        "INSERT INTO tokens (address, decimals, decimals_assumed) VALUES (LOWER(?1), ?2, 1)
+         ON CONFLICT(address) DO NOTHING",
+        params![address, decimals],
+    )?;
+    Ok(())
+}
+
+/// Insert or refresh a token's fetched metadata, clearing any assumed decimals
+/// (rows already written with them keep their `decimals_assumed` flag)
+pub fn upsert_token(
+    conn: &Connection,
+    address: &str,
+    name: Option<&str>,
+    symbol: Option<&str>,
+    decimals: u32,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO tokens (address, name, symbol, decimals)
+         VALUES (LOWER(?1), ?2, ?3, ?4)
+         ON CONFLICT(address) DO UPDATE SET
+             name       = excluded.name,
+             symbol     = excluded.symbol,
+             decimals   = excluded.decimals,
+             decimals_assumed = 0,
+             fetched_at = datetime('now')",
+        params![address, name, symbol, decimals],
+    )?;
     Ok(())
 }
 
-/// Insert or update a transfer
-pub fn record_transfer(
+/// A non-Transfer log ready to be written to `events`
+#[derive(Debug, Clone)]
+pub struct NewEvent<'a> {
+    pub block_number: u64,
+    pub tx_hash: &'a str,
+    pub log_index: u64,
+    pub contract_address: &'a str,
+    pub topic0: &'a str,
+    pub name: Option<&'a str>,
+    pub topics: &'a [String],
+    pub data: &'a str,
+}
+
+/// Insert a raw event; re-seeing the same log (lookback overlap) is a no-op
+pub fn record_event(conn: &Connection, e: &NewEvent) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR IGNORE INTO events (
+            block_number, tx_hash, log_index, contract_address, topic0, name, topics, data
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?
+    .execute(params![
+        to_sql_int(e.block_number)?,
+        e.tx_hash,
+        to_sql_int(e.log_index)?,
+        e.contract_address,
+        e.topic0,
+        e.name,
+        e.topics.join(","),
+        e.data
+    ])?;
+    Ok(())
+}
+
+/// Drop whatever a log reported as `removed` (reorged out) had written: its
+/// transfer row and raw event. The tx hash must match, so a canonical log that
+/// has since taken the same (block, log index) is left alone. Returns rows deleted.
+pub fn delete_log(
     conn: &Connection,
-    block_number: i64,
+    chain_id: u64,
+    contract_address: &str,
+    block_number: u64,
     tx_hash: &str,
-    log_index: i64,
-    token_address: &str,
-    from: &str,
-    to: &str,
-    amount: Decimal,
-    direction: &str,
-) -> Result<()> {
+    log_index: u64,
+) -> Result<usize> {
+    let transfers = conn
+        .prepare_cached(
+            "DELETE FROM transfers
+             WHERE chain_id = ?1 AND block_number = ?2 AND log_index = ?3 AND LOWER(tx_hash) = LOWER(?4)",
+        )?
+        .execute(params![to_sql_int(chain_id)?, to_sql_int(block_number)?, to_sql_int(log_index)?, tx_hash])?;
+    let events = conn
+        .prepare_cached(
+            "DELETE FROM events
+             WHERE LOWER(tx_hash) = LOWER(?1) AND log_index = ?2 AND contract_address = ?3",
+        )?
+        .execute(params![tx_hash, to_sql_int(log_index)?, contract_address])?;
+    Ok(transfers + events)
+}
+
+/// Queue (or re-queue) a range that couldn't be indexed; the next attempt is
+/// due `retry_in` from now. Returns the number of attempts so far.
+pub fn record_failed_range(
+    conn: &Connection,
+    token: &str,
+    from_block: u64,
+    to_block: u64,
+    error: &str,
+    retry_in: Duration,
+) -> Result<u32> {
+    let attempts: i64 = conn.query_row(
+        "INSERT INTO failed_ranges (token_address, from_block, to_block, last_error, next_retry_at)
+         VALUES (?1, ?2, ?3, ?4, datetime('now', '+' || ?5 || ' seconds'))
+         ON CONFLICT(token_address, from_block, to_block) DO UPDATE SET
+            attempts      = attempts + 1,
+            last_error    = excluded.last_error,
+            next_retry_at = excluded.next_retry_at
+         RETURNING attempts",
+        params![token, to_sql_int(from_block)?, to_sql_int(to_block)?, error, retry_in.as_secs_f64().ceil() as i64],
+        |r| r.get(0),
+    )?;
+    Ok(attempts as u32)
+}
+
+/// Queue only the parts of `[from_block, to_block]` no queued range of
+/// `token` already covers, each as `record_failed_range` would. The live loop
+/// scans an overlapping lookback window every round, so queuing it whole on
+/// each failed round would pile up overlapping retries of the same blocks.
+/// Returns the ranges queued.
+pub fn record_uncovered_range(
+    conn: &Connection,
+    token: &str,
+    from_block: u64,
+    to_block: u64,
+    error: &str,
+    retry_in: Duration,
+) -> Result<Vec<(u64, u64)>> {
+    let covered: Vec<(i64, i64)> = conn
+        .prepare_cached(
+            "SELECT from_block, to_block FROM failed_ranges
+             WHERE LOWER(token_address) = LOWER(?1) AND from_block <= ?3 AND to_block >= ?2
+             ORDER BY from_block",
+        )?
+        .query_map(params![token, to_sql_int(from_block)?, to_sql_int(to_block)?], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut gaps = Vec::new();
+    let mut next = Some(from_block);
+    for (start, end) in covered {
+        let Some(at) = next else { break };
+        if start as u64 > at {
+            gaps.push((at, start as u64 - 1));
+        }
+        next = (end as u64).checked_add(1).map(|b| b.max(at)).filter(|&b| b <= to_block);
+    }
+    if let Some(at) = next {
+        gaps.push((at, to_block));
+    }
+    for &(start, end) in &gaps {
+        record_failed_range(conn, token, start, end, error, retry_in)?;
+    }
+    Ok(gaps)
+}
+
+/// Ranges whose `next_retry_at` has passed, oldest first
+pub fn due_failed_ranges(conn: &Connection, limit: usize) -> Result<Vec<FailedRange>> {
+    query_failed_ranges(
+        conn,
+        "WHERE next_retry_at <= datetime('now') ORDER BY from_block LIMIT ?1",
+        limit as i64,
+    )
+}
+
+/// Every queued range (for `/health/failed-ranges`)
+pub fn list_failed_ranges(conn: &Connection) -> Result<Vec<FailedRange>> {
+    query_failed_ranges(conn, "ORDER BY token_address, from_block LIMIT ?1", i64::MAX)
+}
+
+fn query_failed_ranges(conn: &Connection, tail: &str, limit: i64) -> Result<Vec<FailedRange>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT token_address, from_block, to_block, attempts, last_error, first_failed_at, next_retry_at
+         FROM failed_ranges {}",
+        tail
+    ))?;
+    let rows = stmt.query_map([limit], |r| {
+        Ok(FailedRange {
+            token_address: r.get(0)?,
+            from_block: r.get(1)?,
+            to_block: r.get(2)?,
+            attempts: r.get(3)?,
+            last_error: r.get(4)?,
+            first_failed_at: r.get(5)?,
+            next_retry_at: r.get(6)?,
+        })
+    })?;
+    Ok(rows.collect::<std::result::Result<_, _>>()?)
+}
+
+/// Drop a range from the queue once it has been indexed
+pub fn clear_failed_range(conn: &Connection, token: &str, from_block: u64, to_block: u64) -> Result<()> {
     conn.execute(
-        r#"
-        INSERT INTO transfers (
-            block_number, tx_hash, log_index,
-            token_address, from_address, to_address,
-            amount, direction, timestamp
+        "DELETE FROM failed_ranges WHERE LOWER(token_address) = LOWER(?1) AND from_block = ?2 AND to_block = ?3",
+        params![token, to_sql_int(from_block)?, to_sql_int(to_block)?],
+    )?;
+    Ok(())
+}
+
+/// Start tracking the startup backfill of `[from_block, to_block]` for
+/// `token` and return the first block still to scan.
+///
+/// A backfill that was interrupted (never completed) is resumed after the
+/// last chunk it recorded, as long as that run started at or before
+/// `from_block` and got at least up to it; the row keeps its original
+/// `from_block` and is extended to the new `to_block`. Anything else starts
+/// over at `from_block`.
+pub fn start_backfill(conn: &Connection, token: &str, from_block: u64, to_block: u64) -> Result<u64> {
+    let interrupted: Option<(i64, Option<i64>)> = conn
+        .query_row(
+            "SELECT from_block, scanned_through FROM backfill_progress
+             WHERE token_address = ?1 AND completed_at IS NULL",
+            [token],
+            |r| Ok((r.get(0)?, r.get(1)?)),
         )
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, datetime('now'))
-        ON CONFLICT(tx_hash, log_index, token_address) DO UPDATE SET
-            amount    = excluded.amount,
-            direction = excluded.direction,
-            timestamp = excluded.timestamp
-        "#,
-        params![
-            block_number,
-            tx_hash,
-            log_index,
-            token_address,
-            from,
-            to,
-            amount.to_string(),
-            direction
-        ],
+        .optional()?;
+    if let Some((started_at, Some(scanned_through))) = interrupted {
+        let (started_at, scanned_through) = (started_at as u64, scanned_through as u64);
+        if started_at <= from_block && scanned_through.saturating_add(1) >= from_block {
+            conn.execute(
+                "UPDATE backfill_progress
+                 SET to_block = ?2,
+                     completed_at = CASE WHEN scanned_through >= ?2 THEN datetime('now') END
+                 WHERE token_address = ?1",
+                params![token, to_sql_int(to_block)?],
+            )?;
+            return Ok(scanned_through + 1);
+        }
+    }
+
+    conn.execute(
+        "INSERT INTO backfill_progress (token_address, from_block, to_block)
+         VALUES (?1, ?2, ?3)
+         ON CONFLICT(token_address) DO UPDATE SET
+            from_block      = excluded.from_block,
+            to_block        = excluded.to_block,
+            scanned_through = NULL,
+            started_at      = datetime('now'),
+            completed_at    = NULL",
+        params![token, to_sql_int(from_block)?, to_sql_int(to_block)?],
+    )?;
+    Ok(from_block)
+}
+
+/// Mark everything up to `scanned_through` as scanned; the backfill completes
+/// once that reaches `to_block`
+pub fn record_backfill_progress(conn: &Connection, token: &str, scanned_through: u64) -> Result<()> {
+    conn.execute(
+        "UPDATE backfill_progress
+         SET scanned_through = ?2,
+             completed_at = CASE WHEN ?2 >= to_block THEN datetime('now') END
+         WHERE token_address = ?1",
+        params![token, to_sql_int(scanned_through)?],
     )?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Block a FROM_DATE resolved to on an earlier run
+pub fn cached_block_for_timestamp(conn: &Connection, chain_id: u64, timestamp: i64) -> Result<Option<u64>> {
+    let block: Option<i64> = conn
+        .query_row(
+            "SELECT block_number FROM block_dates WHERE chain_id = ?1 AND timestamp = ?2",
+            params![to_sql_int(chain_id)?, timestamp],
+            |r| r.get(0),
+        )
+        .optional()?;
+    Ok(block.map(|b| b as u64))
+}
+
+pub fn cache_block_for_timestamp(conn: &Connection, chain_id: u64, timestamp: i64, block: u64) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO block_dates (chain_id, timestamp, block_number) VALUES (?1, ?2, ?3)",
+        params![to_sql_int(chain_id)?, timestamp, to_sql_int(block)?],
+    )?;
+    Ok(())
+}
+
+/// Backfill progress per token. A backfill only counts as `complete` once
+/// every chunk was scanned and none of its range is waiting in `failed_ranges`.
+pub fn backfill_status(conn: &Connection) -> Result<Vec<BackfillStatus>> {
+    let mut stmt = conn.prepare(
+        "SELECT b.token_address, b.from_block, b.to_block, b.scanned_through, b.started_at, b.completed_at,
+                (SELECT COUNT(*) FROM failed_ranges f
+                 WHERE f.token_address = b.token_address
+                   AND f.from_block <= b.to_block AND f.to_block >= b.from_block)
+         FROM backfill_progress b
+         ORDER BY b.token_address",
+    )?;
+    let rows = stmt.query_map([], |r| {
+        let (from_block, to_block): (i64, i64) = (r.get(1)?, r.get(2)?);
+        let scanned_through: Option<i64> = r.get(3)?;
+        let completed_at: Option<String> = r.get(5)?;
+        let pending_retries: i64 = r.get(6)?;
+
+        let total_blocks = to_block - from_block + 1;
+        let scanned_blocks = scanned_through.map_or(0, |b| (b - from_block + 1).clamp(0, total_blocks));
+        Ok(BackfillStatus {
+            token_address: r.get(0)?,
+            from_block: Some(from_block),
+            to_block: Some(to_block),
+            scanned_blocks,
+            total_blocks,
+            percent: if total_blocks > 0 { scanned_blocks as f64 * 100.0 / total_blocks as f64 } else { 100.0 },
+            pending_retries,
+            complete: completed_at.is_some() && pending_retries == 0,
+            started_at: r.get(4)?,
+            completed_at,
+        })
+    })?;
+    Ok(rows.collect::<std::result::Result<_, _>>()?)
+}
+
+/// Reset one token so it gets re-indexed: delete its transfers and cached
+/// netflow, then queue the purged block span (from the first to the last
+/// deleted transfer, in `chunk`-block pieces) in `failed_ranges`, where the
+/// live loop picks it up. Queued retries inside the span are replaced by the
+/// rescan; the parts of them outside it stay queued as they were. All in one
+/// transaction.
+///
+/// Retention baselines are kept and the rescan starts after
+/// `pruned_through_block`, so the rebuilt netflow still includes pruned rows.
+/// Other tokens' rows and netflows are untouched.
+pub fn purge_token(conn: &mut Connection, token: &str, chunk: u64) -> Result<PurgedToken> {
+    let token = token.trim().to_lowercase();
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let chain = to_sql_int(chain_id(&tx)?)?;
+    let (first, last): (Option<i64>, Option<i64>) = tx.query_row(
+        "SELECT MIN(block_number), MAX(block_number) FROM transfers WHERE LOWER(token_address) = ?1 AND chain_id = ?2",
+        params![&token, chain],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    let pruned_through: Option<i64> = tx
+        .query_row(
+            "SELECT pruned_through_block FROM netflow_baselines WHERE chain_id = ?1 AND LOWER(token_address) = ?2",
+            params![chain, &token],
+            |r| r.get(0),
+        )
+        .optional()?;
+
+    // only this chain's rows: other chains' transfers are kept
+    let transfers = tx.execute("DELETE FROM transfers WHERE LOWER(token_address) = ?1 AND chain_id = ?2", params![&token, chain])?;
+    tx.execute("DELETE FROM netflows WHERE LOWER(token_address) = ?1 AND chain_id = ?2", params![&token, chain])?;
+    tx.execute("DELETE FROM netflows_by_exchange WHERE LOWER(token_address) = ?1 AND chain_id = ?2", params![&token, chain])?;
+
+    let rescan = match (first, last) {
+        (Some(first), Some(last)) => {
+            let from = first.max(pruned_through.map_or(0, |b| b + 1)) as u64;
+            let to = last as u64;
+            (from <= to).then_some((from, to))
+        }
+        _ => None,
+    };
+    if let Some((from, to)) = rescan {
+        let (from_sql, to_sql) = (to_sql_int(from)?, to_sql_int(to)?);
+        // keep what sticks out either side of the span, then drop the overlap
+        tx.execute(
+            "INSERT OR IGNORE INTO failed_ranges
+                (token_address, from_block, to_block, attempts, last_error, first_failed_at, next_retry_at)
+             SELECT token_address, from_block, ?2 - 1, attempts, last_error, first_failed_at, next_retry_at
+             FROM failed_ranges
+             WHERE LOWER(token_address) = ?1 AND from_block < ?2 AND to_block >= ?2",
+            params![&token, from_sql],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO failed_ranges
+                (token_address, from_block, to_block, attempts, last_error, first_failed_at, next_retry_at)
+             SELECT token_address, ?2 + 1, to_block, attempts, last_error, first_failed_at, next_retry_at
+             FROM failed_ranges
+             WHERE LOWER(token_address) = ?1 AND from_block <= ?2 AND to_block > ?2",
+            params![&token, to_sql],
+        )?;
+        tx.execute(
+            "DELETE FROM failed_ranges WHERE LOWER(token_address) = ?1 AND from_block <= ?3 AND to_block >= ?2",
+            params![&token, from_sql, to_sql],
+        )?;
+
+        let chunk = chunk.max(1);
+        let mut start = from;
+        while start <= to {
+            let end = start.saturating_add(chunk - 1).min(to);
+            record_failed_range(&tx, &token, start, end, "reindex requested", Duration::ZERO)?;
+            start = end + 1;
+        }
+    }
+
+    commit_with_retry(tx)?;
+    info!("Purged {} transfer(s) for {}, rescan queued: {:?}", transfers, token, rescan);
+    Ok(PurgedToken {
+        token_address: token,
+        transfers_deleted: transfers,
+        rescan_from_block: rescan.map(|(from, _)| from as i64),
+        rescan_to_block: rescan.map(|(_, to)| to as i64),
+    })
+}
+
+/// Order-independent fingerprint of an exchange set
+fn exchange_set_fingerprint(exchanges: &HashSet<Address>) -> String {
+    let mut addrs: Vec<Address> = exchanges.iter().copied().collect();
+    addrs.sort();
+    keccak256(addrs.concat()).to_string()
+}
+
+/// If the exchange set differs from the one stored transfers were classified
+/// with (or none was recorded yet), re-derive every transfer's direction and
+/// exchange address from its raw from/to, the same way the indexer does: IN
+/// when `to` is an exchange, else OUT when `from` is, else NEUTRAL. Returns
+/// the number of rows changed, or `None` when the set is unchanged.
+///
+/// Only rows already stored can move: with INDEX_MODE=exchange_only,
+/// transfers that never touched a listed exchange were never written, so a
+/// newly added wallet's history still needs a reindex. Rows that lose their
+/// exchange become NEUTRAL rather than being deleted, and pruned rows'
+/// retention baselines are not revisited. Netflows must be recomputed after.
+pub fn reclassify(conn: &mut Connection, exchanges: &HashSet<Address>) -> Result<Option<usize>> {
+    let fingerprint = exchange_set_fingerprint(exchanges);
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let stored: Option<String> = tx
+        .query_row("SELECT value FROM settings WHERE key = ?1", [EXCHANGE_SET_KEY], |r| r.get(0))
+        .optional()?;
+    if stored.as_deref() == Some(fingerprint.as_str()) {
+        return Ok(None);
+    }
+
+    let mut changed = 0;
+    {
+        let mut select = tx.prepare(
+            "SELECT id, from_address, to_address, direction, exchange_address FROM transfers",
+        )?;
+        let mut update = tx.prepare(
+            "UPDATE transfers SET direction = ?2, exchange_address = ?3 WHERE id = ?1",
+        )?;
+        let is_exchange = |addr: &str| addr.parse::<Address>().is_ok_and(|a| exchanges.contains(&a));
+        let rows = select.query_map([], |r| {
+            Ok((
+                r.get::<_, i64>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+                r.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        for row in rows {
+            let (id, from, to, direction, exchange) = row?;
+            let (new_direction, new_exchange) = if is_exchange(&to) {
+                ("IN", Some(to))
+            } else if is_exchange(&from) {
+                ("OUT", Some(from))
+            } else {
+                ("NEUTRAL", None)
+            };
+            if new_direction != direction || new_exchange != exchange {
+                update.execute(params![id, new_direction, new_exchange])?;
+                changed += 1;
+            }
+        }
+    }
+
+    tx.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = datetime('now')",
+        params![EXCHANGE_SET_KEY, fingerprint],
+    )?;
+    commit_with_retry(tx)?;
+    Ok(Some(changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetflowUnits;
+    use std::str::FromStr;
+
+    const TOKEN: &str = "0x0000000000000000000000000000000000001010";
+
+    fn new_transfer<'a>(tx_hash: &'a str, log_index: u64) -> NewTransfer<'a> {
+        NewTransfer {
+            chain_id: 137,
+            block_number: 100,
+            tx_hash,
+            log_index,
+            token_address: TOKEN,
+            from: "0xfrom",
+            to: "0xto",
+            amount: Decimal::ONE,
+            direction: "IN",
+            exchange_address: Some("0xto"),
+            raw_amount: "1000000000000000000",
+            decimals: 18,
+            decimals_assumed: false,
+            block_timestamp: None,
+        }
+    }
+
+    fn record(conn: &Connection, tx_hash: &str, log_index: u64, amount: &str, direction: &str) {
+        let transfer = NewTransfer {
+            amount: Decimal::from_str(amount).unwrap(),
+            direction,
+            ..new_transfer(tx_hash, log_index)
+        };
+        record_transfer(conn, &transfer).unwrap();
+    }
+
+    fn count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0)).unwrap()
+    }
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let mut conn = open_in_memory();
+        run_migrations(&mut conn).unwrap();
+
+        let version: i64 = conn
+            .query_row("SELECT MAX(version) FROM schema_migrations", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn record_transfer_inserts_rows() {
+        let conn = open_in_memory();
+        record(&conn, "0xa", 0, "1.5", "IN");
+        record(&conn, "0xa", 1, "2", "OUT");
+        record(&conn, "0xb", 2, "0.25", "IN");
+        assert_eq!(count(&conn), 3);
+    }
+
+    #[test]
+    fn record_transfer_upserts_on_duplicate_log() {
+        let conn = open_in_memory();
+        record(&conn, "0xa", 0, "1.5", "IN");
+        record(&conn, "0xa", 0, "3", "OUT");
+        assert_eq!(count(&conn), 1);
+
+        let (amount, direction): (String, String) = conn
+            .query_row(
+                "SELECT amount, direction FROM transfers WHERE tx_hash = '0xa' AND log_index = 0",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(amount, "3");
+        assert_eq!(direction, "OUT");
+    }
+
+    #[test]
+    fn transfers_are_keyed_by_block_and_log_index() {
+        let conn = open_in_memory();
+        let other_token = "0x2791bca1f2de4661ed88a30c99a7a9449aa84174";
+
+        // two tokens' Transfer logs in the same tx and block, different indices
+        record_transfer(&conn, &new_transfer("0xa", 4)).unwrap();
+        record_transfer(&conn, &NewTransfer { token_address: other_token, ..new_transfer("0xa", 5) }).unwrap();
+        assert_eq!(count(&conn), 2);
+
+        // the same (block, log_index) seen again replaces the row rather than duplicating it
+        record_transfer(&conn, &NewTransfer { token_address: other_token, ..new_transfer("0xa", 4) }).unwrap();
+        assert_eq!(count(&conn), 2);
+        let token: String = conn
+            .query_row("SELECT token_address FROM transfers WHERE log_index = 4", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(token, other_token);
+    }
+
+    #[test]
+    fn same_block_and_log_index_on_another_chain_is_a_new_row() {
+        let conn = open_in_memory();
+        record_transfer(&conn, &new_transfer("0xa", 0)).unwrap();
+        record_transfer(&conn, &NewTransfer { chain_id: 1, ..new_transfer("0xb", 0) }).unwrap();
+        assert_eq!(count(&conn), 2);
+
+        let chains: Vec<i64> = conn
+            .prepare("SELECT chain_id FROM transfers ORDER BY chain_id")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(chains, vec![1, 137]);
+    }
+
+    #[test]
+    fn rekey_migration_drops_duplicate_logs() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE schema_migrations (version INTEGER PRIMARY KEY, applied_at TEXT NOT NULL DEFAULT (datetime('now')));",
+        )
+        .unwrap();
+        {
+            let tx = conn.transaction().unwrap();
+            for (i, sql) in MIGRATIONS[..8].iter().enumerate() {
+                tx.execute_batch(sql).unwrap();
+                tx.execute("INSERT INTO schema_migrations (version) VALUES (?1)", [i as i64 + 1]).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        // old key allowed the same log under two token addresses
+        for token in ["0xold", "0xnew"] {
+            conn.execute(
+                "INSERT INTO transfers (block_number, tx_hash, log_index, token_address, from_address, to_address, amount, direction)
+                 VALUES (100, '0xa', 7, ?1, '0xf', '0xt', '1', 'IN')",
+                [token],
+            )
+            .unwrap();
+        }
+
+        run_migrations(&mut conn).unwrap();
+        let tokens: Vec<String> = conn
+            .prepare("SELECT token_address FROM transfers")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(tokens, ["0xnew"]);
+    }
+
+    #[test]
+    fn record_event_ignores_duplicates() {
+        let conn = open_in_memory();
+        let topics = ["0xowner".to_string(), "0xspender".to_string()];
+        let event = NewEvent {
+            block_number: 100,
+            tx_hash: "0xa",
+            log_index: 3,
+            contract_address: TOKEN,
+            topic0: "0xtopic",
+            name: Some("Approval"),
+            topics: &topics,
+            data: "0x01",
+        };
+        record_event(&conn, &event).unwrap();
+        record_event(&conn, &event).unwrap();
+
+        let (n, stored): (i64, String) = conn
+            .query_row("SELECT COUNT(*), MAX(topics) FROM events", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(stored, "0xowner,0xspender");
+    }
+
+    #[test]
+    fn block_numbers_near_i64_max_are_checked() {
+        let conn = open_in_memory();
+        let max = i64::MAX as u64;
+
+        record_transfer(&conn, &NewTransfer { block_number: max, ..new_transfer("0xa", 0) }).unwrap();
+        let stored: i64 = conn
+            .query_row("SELECT block_number FROM transfers", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(stored, i64::MAX);
+
+        let err = record_transfer(&conn, &NewTransfer { block_number: max + 1, ..new_transfer("0xb", 0) });
+        assert!(err.is_err());
+        assert_eq!(count(&conn), 1);
+
+        assert_eq!(to_sql_int(max).unwrap(), i64::MAX);
+        assert!(to_sql_int(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn token_metadata_round_trips_case_insensitively() {
+        let conn = open_in_memory();
+        assert_eq!(token_decimals(&conn, "0xAbC").unwrap(), None);
+
+        upsert_token(&conn, "0xAbC", Some("USD Coin"), Some("USDC"), 6).unwrap();
+        assert_eq!(token_decimals(&conn, "0xabc").unwrap(), Some(6.into()));
+
+        upsert_token(&conn, "0xabc", None, None, 8).unwrap();
+        assert_eq!(token_decimals(&conn, "0xABC").unwrap(), Some(8.into()));
+    }
+
+    #[test]
+    fn assumed_decimals_stick_until_metadata_is_fetched() {
+        let conn = open_in_memory();
+        let assumed = |decimals| Some(TokenDecimals { decimals, assumed: true });
+        assume_token_decimals(&conn, "0xAbC", 18).unwrap();
+        assert_eq!(token_decimals(&conn, "0xabc").unwrap(), assumed(18));
+        // a later assumption (another DEFAULT_DECIMALS) keeps the first scale
+        assume_token_decimals(&conn, "0xabc", 6).unwrap();
+        assert_eq!(token_decimals(&conn, "0xabc").unwrap(), assumed(18));
+
+        upsert_token(&conn, "0xabc", None, None, 6).unwrap();
+        assert_eq!(token_decimals(&conn, "0xabc").unwrap(), Some(6.into()));
+        // fetched metadata is never downgraded to an assumption
+        assume_token_decimals(&conn, "0xabc", 18).unwrap();
+        assert_eq!(token_decimals(&conn, "0xabc").unwrap(), Some(6.into()));
+    }
+
+    #[test]
+    fn default_decimals_come_from_settings() {
+        let conn = open_in_memory();
+        assert_eq!(default_decimals(&conn).unwrap(), DEFAULT_TOKEN_DECIMALS);
+        record_default_decimals(&conn, 6).unwrap();
+        assert_eq!(default_decimals(&conn).unwrap(), 6);
+    }
+
+    #[test]
+    fn amount_num_orders_numerically() {
+        let conn = open_in_memory();
+        record(&conn, "0xa", 0, "9", "IN");
+        record(&conn, "0xb", 1, "10", "IN");
+        record(&conn, "0xc", 2, "0.5", "IN");
+
+        let mut stmt = conn
+            .prepare("SELECT amount FROM transfers ORDER BY amount_num DESC")
+            .unwrap();
+        let amounts: Vec<String> = stmt
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(amounts, ["10", "9", "0.5"]);
+    }
+
+    #[test]
+    fn update_netflows_computes_net() {
+        let conn = open_in_memory();
+        record(&conn, "0xa", 0, "10", "IN");
+        record(&conn, "0xb", 1, "2.5", "IN");
+        record(&conn, "0xc", 2, "4", "OUT");
+        record(&conn, "0xd", 3, "100", "NEUTRAL");
+
+        crate::aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+
+        let (net, inflow, outflow): (String, String, String) = conn
+            .query_row(
+                "SELECT cumulative_net, inflow_total, outflow_total FROM netflows WHERE token_address = ?1",
+                [TOKEN],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(Decimal::from_str(&net).unwrap(), Decimal::from_str("8.5").unwrap());
+        assert_eq!(Decimal::from_str(&inflow).unwrap(), Decimal::from_str("12.5").unwrap());
+        assert_eq!(Decimal::from_str(&outflow).unwrap(), Decimal::from(4));
+    }
+
+    #[test]
+    fn checkpoint_truncates_the_wal_of_a_populated_db() {
+        let path = std::env::temp_dir().join(format!("polygon-indexer-wal-{}.db", std::process::id()));
+        let mut conn = connect(path.to_str().unwrap()).unwrap();
+        run_migrations(&mut conn).unwrap();
+        // keep SQLite's own passive checkpoints out of the way
+        conn.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+        for i in 0..500 {
+            record(&conn, &format!("0x{i}"), i, "1", "IN");
+        }
+        let wal = path.with_extension("db-wal");
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+
+        assert!(checkpoint(&conn).unwrap());
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+        assert_eq!(count(&conn), 500);
+
+        drop(conn);
+        for ext in ["db", "db-wal", "db-shm"] {
+            let _ = std::fs::remove_file(path.with_extension(ext));
+        }
+    }
+
+    #[test]
+    fn netflows_are_split_by_exchange() {
+        let conn = open_in_memory();
+        for (i, (amount, direction, exchange)) in [
+            ("10", "IN", Some("0xbinance")),
+            ("4", "OUT", Some("0xbinance")),
+            ("3", "IN", Some("0xcoinbase")),
+            ("100", "NEUTRAL", None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            record_transfer(&conn, &NewTransfer {
+                block_number: 100 + i as u64,
+                amount: Decimal::from_str(amount).unwrap(),
+                direction,
+                exchange_address: exchange,
+                ..new_transfer(&format!("0x{i}"), 0)
+            })
+            .unwrap();
+        }
+        let by_exchange = |conn: &Connection| -> Vec<(String, String, String, i64, i64)> {
+            conn.prepare(
+                "SELECT exchange_address, cumulative_net, inflow_total, transfers, last_block
+                 FROM netflows_by_exchange ORDER BY exchange_address",
+            )
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+        };
+
+        crate::aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(by_exchange(&conn), [
+            ("0xbinance".to_string(), "6".to_string(), "10".to_string(), 2, 101),
+            ("0xcoinbase".to_string(), "3".to_string(), "3".to_string(), 1, 102),
+        ]);
+
+        // a pair with no transfers left is dropped on the next refresh
+        conn.execute("UPDATE transfers SET exchange_address = '0xbinance' WHERE block_number = 102", []).unwrap();
+        crate::aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(by_exchange(&conn), [("0xbinance".to_string(), "9".to_string(), "13".to_string(), 3, 102)]);
+    }
+
+    #[test]
+    fn aggregates_cover_only_the_recorded_chain() {
+        let conn = open_in_memory();
+        record(&conn, "0xa", 0, "2", "IN");
+        // same block and log index, on another chain
+        record_transfer(&conn, &NewTransfer { chain_id: 1, amount: Decimal::from(5), ..new_transfer("0xb", 0) }).unwrap();
+        let nets = |conn: &Connection| -> Vec<(String, i64)> {
+            conn.prepare(
+                "SELECT cumulative_net, chain_id FROM netflows
+                 UNION ALL SELECT cumulative_net, chain_id FROM netflows_by_exchange",
+            )
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+        };
+
+        crate::aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(nets(&conn), [("2".to_string(), 137), ("2".to_string(), 137)]);
+        assert!(crate::aggregator::verify(&conn, NetflowUnits::Token).unwrap().iter().all(|d| !d.drift));
+
+        // CHAIN_ID changed: the cache is stale until recomputed for the new chain
+        record_chain_id(&conn, 1).unwrap();
+        assert_eq!(crate::aggregator::stale_units(&conn, NetflowUnits::Token).unwrap(), 1);
+        crate::aggregator::recompute_full(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(nets(&conn), [("5".to_string(), 1), ("5".to_string(), 1)]);
+        assert_eq!(crate::aggregator::stale_units(&conn, NetflowUnits::Token).unwrap(), 0);
+    }
+
+    #[test]
+    fn a_batch_refresh_rewrites_only_its_tokens_and_all_or_nothing() {
+        let conn = open_in_memory();
+        let other = "0x0000000000000000000000000000000000000abc";
+        record_transfer(&conn, &NewTransfer { exchange_address: Some("0xbinance"), ..new_transfer("0xa", 0) }).unwrap();
+        record_transfer(&conn, &NewTransfer { token_address: other, exchange_address: Some("0xbinance"), ..new_transfer("0xb", 1) })
+            .unwrap();
+        crate::aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        let rows = |conn: &Connection| -> Vec<(String, String, String)> {
+            conn.prepare(
+                "SELECT token_address, cumulative_net, units FROM netflows
+                 UNION ALL SELECT token_address, cumulative_net, units FROM netflows_by_exchange
+                 ORDER BY 1, 3",
+            )
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+        };
+        let before = rows(&conn);
+        assert_eq!(before.len(), 4);
+
+        // the other token isn't in the batch, so its pair stays even with its transfers gone
+        conn.execute("DELETE FROM transfers WHERE token_address = ?1", [other]).unwrap();
+        crate::aggregator::update_token_netflows(&conn, NetflowUnits::Token, &[TOKEN.to_string()]).unwrap();
+        assert_eq!(rows(&conn), before);
+
+        // the second token overflows, so the first one's rewrite is rolled back too
+        for (tx, log_index) in [("0xc", 2), ("0xd", 3)] {
+            let transfer = NewTransfer { token_address: other, raw_amount: "79228162514264337593543950335", ..new_transfer(tx, log_index) };
+            record_transfer(&conn, &transfer).unwrap();
+        }
+        let tokens = [TOKEN.to_string(), other.to_string()];
+        assert!(crate::aggregator::update_token_netflows(&conn, NetflowUnits::Raw, &tokens).is_err());
+        assert_eq!(rows(&conn), before);
+    }
+
+    #[test]
+    fn update_netflows_in_raw_units_is_exact() {
+        let conn = open_in_memory();
+        for (i, (amount, raw, direction)) in [
+            ("1.5", "1500000000000000000", "IN"),
+            ("0.000000000000000001", "1", "IN"),
+            ("0.5", "500000000000000000", "OUT"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            record_transfer(&conn, &NewTransfer {
+                amount: Decimal::from_str(amount).unwrap(),
+                raw_amount: raw,
+                direction,
+                ..new_transfer(&format!("0x{i}"), i as u64)
+            })
+            .unwrap();
+        }
+        // indexed before raw_amount existed: rebuilt from amount and decimals
+        conn.execute("UPDATE transfers SET raw_amount = NULL WHERE tx_hash = '0x2'", []).unwrap();
+
+        let updated = crate::aggregator::update_netflows(&conn, NetflowUnits::Raw).unwrap();
+        assert_eq!(updated, [(TOKEN.to_string(), Decimal::from_str("1000000000000000001").unwrap())]);
+        let units: String = conn.query_row("SELECT units FROM netflows", [], |r| r.get(0)).unwrap();
+        assert_eq!(units, "raw");
+        assert!(crate::aggregator::verify(&conn, NetflowUnits::Raw).unwrap().iter().all(|d| !d.drift));
+
+        // switching NETFLOW_UNITS back flags the cached row for recomputation
+        assert_eq!(crate::aggregator::stale_units(&conn, NetflowUnits::Token).unwrap(), 1);
+        crate::aggregator::recompute_full(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(crate::aggregator::stale_units(&conn, NetflowUnits::Token).unwrap(), 0);
+        let net: String = conn.query_row("SELECT cumulative_net FROM netflows", [], |r| r.get(0)).unwrap();
+        assert_eq!(Decimal::from_str(&net).unwrap(), Decimal::from_str("1.000000000000000001").unwrap());
+    }
+
+    #[test]
+    fn raw_netflow_refresh_reads_only_the_given_tokens_and_fails_on_overflow() {
+        let conn = open_in_memory();
+        let other = "0x0000000000000000000000000000000000000abc";
+        record_transfer(&conn, &new_transfer("0xa", 0)).unwrap();
+        record_transfer(&conn, &NewTransfer { token_address: other, ..new_transfer("0xb", 1) }).unwrap();
+
+        let updated = crate::aggregator::update_token_netflows(&conn, NetflowUnits::Raw, &[TOKEN.to_string()]).unwrap();
+        assert_eq!(updated, [(TOKEN.to_string(), Decimal::from_str("1000000000000000000").unwrap())]);
+        let cached: Vec<String> = conn
+            .prepare("SELECT token_address FROM netflows")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(cached, [TOKEN]);
+
+        // two transfers of Decimal::MAX raw units: an error, not a panic
+        for (tx, log_index) in [("0xc", 2), ("0xd", 3)] {
+            record_transfer(&conn, &NewTransfer { raw_amount: "79228162514264337593543950335", ..new_transfer(tx, log_index) }).unwrap();
+        }
+        let err = crate::aggregator::update_token_netflows(&conn, NetflowUnits::Raw, &[TOKEN.to_string()]).unwrap_err();
+        assert!(matches!(err, crate::error::IndexerError::Decode(_)), "{err}");
+
+        // an amount that doesn't parse is reported, not counted as zero
+        assert!(crate::aggregator::unit_amount(NetflowUnits::Raw, "1", Some("1e18"), 18).is_err());
+        assert!(crate::aggregator::unit_amount(NetflowUnits::Token, "", None, 18).is_err());
+    }
+
+    #[test]
+    fn token_unit_refreshes_sum_exactly() {
+        let conn = open_in_memory();
+        for (i, amount) in ["0.1", "0.2", "0.000000000000000001"].into_iter().enumerate() {
+            record(&conn, &format!("0x{i}"), i as u64, amount, "IN");
+        }
+        crate::aggregator::update_token_netflows(&conn, NetflowUnits::Token, &[TOKEN.to_string()]).unwrap();
+
+        // an f64 SUM would give 0.30000000000000004 and lose the last wei
+        let net: String = conn.query_row("SELECT cumulative_net FROM netflows", [], |r| r.get(0)).unwrap();
+        let by_exchange: String = conn.query_row("SELECT cumulative_net FROM netflows_by_exchange", [], |r| r.get(0)).unwrap();
+        assert_eq!((net.as_str(), by_exchange.as_str()), ("0.300000000000000001", "0.300000000000000001"));
+    }
+
+    #[test]
+    fn verify_reports_drift_between_cache_and_transfers() {
+        let conn = open_in_memory();
+        record(&conn, "0xa", 0, "0.1", "IN");
+        record(&conn, "0xb", 1, "0.2", "IN");
+        crate::aggregator::recompute_full(&conn, NetflowUnits::Token).unwrap();
+
+        let report = crate::aggregator::verify(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].recomputed, Decimal::from_str("0.3").unwrap());
+        assert!(!report[0].drift);
+
+        // simulate a cache that lost precision
+        conn.execute("UPDATE netflows SET cumulative_net = '0.30000001'", []).unwrap();
+        let report = crate::aggregator::verify(&conn, NetflowUnits::Token).unwrap();
+        assert!(report[0].drift);
+        assert_eq!(report[0].diff, Decimal::from_str("0.00000001").unwrap());
+
+        // transfers for a token with no netflows row at all
+        conn.execute(
+            "INSERT INTO transfers (block_number, tx_hash, log_index, token_address, from_address, to_address, amount, direction)
+             VALUES (2, '0xc', 0, '0xother', '0xa', '0xb', '5', 'IN')",
+            [],
+        )
+        .unwrap();
+        let report = crate::aggregator::verify(&conn, NetflowUnits::Token).unwrap();
+        let other = report.iter().find(|d| d.token_address == "0xother").unwrap();
+        assert!(other.cached.is_none() && other.drift);
+
+        // a corrupt cached value is drift, not zero; another chain's row isn't compared
+        conn.execute("UPDATE netflows SET cumulative_net = 'garbage'", []).unwrap();
+        conn.execute(
+            "INSERT INTO netflows (token_address, cumulative_net, inflow_total, outflow_total, last_block, chain_id)
+             VALUES ('0xelsewhere', '9', '9', '0', 1, 80002)",
+            [],
+        )
+        .unwrap();
+        let report = crate::aggregator::verify(&conn, NetflowUnits::Token).unwrap();
+        assert!(report.iter().all(|d| d.token_address != "0xelsewhere"));
+        let token = report.iter().find(|d| d.token_address != "0xother").unwrap();
+        assert!(token.cached.is_none() && token.drift);
+    }
+
+    #[test]
+    fn failed_ranges_queue_until_cleared() {
+        let conn = open_in_memory();
+        let attempts = record_failed_range(&conn, TOKEN, 100, 199, "timeout", Duration::ZERO).unwrap();
+        assert_eq!(attempts, 1);
+        record_failed_range(&conn, TOKEN, 300, 399, "timeout", Duration::from_secs(3600)).unwrap();
+
+        // only the first is due; the second waits out its backoff
+        let due = due_failed_ranges(&conn, 10).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].from_block, due[0].to_block), (100, 199));
+
+        let attempts = record_failed_range(&conn, TOKEN, 100, 199, "429", Duration::ZERO).unwrap();
+        assert_eq!(attempts, 2);
+        let all = list_failed_ranges(&conn).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].last_error, "429");
+
+        clear_failed_range(&conn, TOKEN, 100, 199).unwrap();
+        assert!(due_failed_ranges(&conn, 10).unwrap().is_empty());
+        assert_eq!(list_failed_ranges(&conn).unwrap().len(), 1);
+
+        // recorded under another casing than the one it is cleared with
+        record_failed_range(&conn, "0xAbC", 500, 599, "timeout", Duration::ZERO).unwrap();
+        clear_failed_range(&conn, "0xabc", 500, 599).unwrap();
+        assert_eq!(list_failed_ranges(&conn).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn uncovered_range_skips_blocks_already_queued() {
+        let conn = open_in_memory();
+        let queue = |from, to| record_uncovered_range(&conn, TOKEN, from, to, "timeout", Duration::ZERO).unwrap();
+
+        assert_eq!(queue(100, 199), [(100, 199)]);
+        // the next round's window overlaps the last one: only the new blocks
+        assert_eq!(queue(150, 300), [(200, 300)]);
+        // a wider window fills in around what's queued
+        assert_eq!(queue(50, 350), [(50, 99), (301, 350)]);
+        assert!(queue(120, 320).is_empty());
+
+        let queued: Vec<(i64, i64, i64)> = list_failed_ranges(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.from_block, r.to_block, r.attempts))
+            .collect();
+        assert_eq!(queued, [(50, 99, 1), (100, 199, 1), (200, 300, 1), (301, 350, 1)]);
+    }
+
+    #[test]
+    fn purge_token_resets_one_token_and_queues_rescan() {
+        let mut conn = open_in_memory();
+        for (block, token, amount) in [(100, TOKEN, "5"), (250, TOKEN, "1"), (120, "0xother", "3")] {
+            conn.execute(
+                "INSERT INTO transfers (block_number, tx_hash, log_index, token_address, from_address, to_address, amount, direction)
+                 VALUES (?1, ?2, 0, ?3, '0xa', '0xb', ?4, 'IN')",
+                params![block, format!("0x{block}"), token, amount],
+            )
+            .unwrap();
+        }
+        crate::aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        record_failed_range(&conn, TOKEN, 300, 399, "timeout", Duration::from_secs(3600)).unwrap();
+        record_failed_range(&conn, TOKEN, 150, 160, "timeout", Duration::from_secs(3600)).unwrap();
+        record_failed_range(&conn, TOKEN, 240, 279, "timeout", Duration::from_secs(3600)).unwrap();
+
+        let purged = purge_token(&mut conn, TOKEN, 100).unwrap();
+        assert_eq!(purged.token_address, TOKEN);
+        assert_eq!(purged.transfers_deleted, 2);
+        assert_eq!((purged.rescan_from_block, purged.rescan_to_block), (Some(100), Some(250)));
+
+        // the rescan is split into 100-block chunks and replaces the retries
+        // inside it; what lies past block 250 is still queued
+        let queued: Vec<(i64, i64, String)> = list_failed_ranges(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.from_block, r.to_block, r.last_error))
+            .collect();
+        let reindex = "reindex requested".to_string();
+        let timeout = "timeout".to_string();
+        assert_eq!(queued, [
+            (100, 199, reindex.clone()),
+            (200, 250, reindex),
+            (251, 279, timeout.clone()),
+            (300, 399, timeout),
+        ]);
+
+        // the other token keeps its rows and netflow
+        let tokens: Vec<String> = conn
+            .prepare("SELECT token_address FROM netflows")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(tokens, ["0xother"]);
+        assert_eq!(count(&conn), 1);
+        crate::aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        assert!(crate::aggregator::verify(&conn, NetflowUnits::Token).unwrap().iter().all(|d| !d.drift));
+
+        // nothing left to purge: no rescan queued
+        let again = purge_token(&mut conn, TOKEN, 100).unwrap();
+        assert_eq!((again.transfers_deleted, again.rescan_from_block), (0, None));
+    }
+
+    #[test]
+    fn sync_exchanges_reports_added_and_removed_wallets() {
+        let mut conn = open_in_memory();
+        let (a, b, c) = (Address::repeat_byte(0xaa), Address::repeat_byte(0xbb), Address::repeat_byte(0xcc));
+        let stored = |conn: &Connection| -> Vec<(String, String)> {
+            conn.prepare("SELECT address, label FROM exchanges ORDER BY lower(address)")
+                .unwrap()
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(Result::unwrap)
+                .collect()
+        };
+
+        let none = BTreeMap::new();
+        let diff = sync_exchanges(&mut conn, &[b, a].into(), &none, "Binance").unwrap();
+        assert_eq!(diff, ExchangeDiff { added: vec![a.to_string(), b.to_string()], removed: vec![] });
+        assert!(sync_exchanges(&mut conn, &[a, b].into(), &none, "Binance").unwrap().is_empty());
+
+        // relabelled by hand, and `a` also stored lowercase: still the same wallet
+        conn.execute("UPDATE exchanges SET label = 'Hot wallet' WHERE address = ?1", [b.to_string()]).unwrap();
+        conn.execute("UPDATE exchanges SET address = lower(address) WHERE address = ?1", [a.to_string()]).unwrap();
+
+        // `c` added with its own label, `b` dropped; `a`'s stored label wins over the file's
+        let labels = [(a, "Ignored".to_string()), (c, "Binance 14".to_string())].into();
+        let diff = sync_exchanges(&mut conn, &[a, c].into(), &labels, "Binance").unwrap();
+        assert_eq!(diff, ExchangeDiff { added: vec![c.to_string()], removed: vec![b.to_string()] });
+        assert_eq!(stored(&conn), [
+            (a.to_string().to_lowercase(), "Binance".to_string()),
+            (c.to_string(), "Binance 14".to_string()),
+        ]);
+
+        // removing every wallet empties the table
+        let diff = sync_exchanges(&mut conn, &HashSet::new(), &none, "Binance").unwrap();
+        assert_eq!(diff.removed.len(), 2);
+        assert!(stored(&conn).is_empty());
+    }
+
+    #[test]
+    fn reclassify_follows_exchange_set_changes() {
+        let mut conn = open_in_memory();
+        let (a, b, c) = (Address::repeat_byte(0xaa), Address::repeat_byte(0xbb), Address::repeat_byte(0xcc));
+        // indexed with INDEX_MODE=all while only `a` was an exchange
+        for (block, from, to, amount, direction, exchange) in [
+            (1, c, a, "10", "IN", Some(a)),
+            (2, b, c, "4", "NEUTRAL", None),
+            (3, c, b, "1", "NEUTRAL", None),
+        ] {
+            record_transfer(&conn, &NewTransfer {
+                block_number: block,
+                from: &from.to_string(),
+                to: &to.to_string(),
+                amount: Decimal::from_str(amount).unwrap(),
+                direction,
+                exchange_address: exchange.map(|e| e.to_string()).as_deref(),
+                ..new_transfer(&format!("0x{block}"), 0)
+            })
+            .unwrap();
+        }
+        let directions = |conn: &Connection| -> Vec<(String, Option<String>)> {
+            conn.prepare("SELECT direction, exchange_address FROM transfers ORDER BY block_number")
+                .unwrap()
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(Result::unwrap)
+                .collect()
+        };
+
+        // first run records the set; the rows already match it
+        assert_eq!(reclassify(&mut conn, &[a].into()).unwrap(), Some(0));
+        assert_eq!(reclassify(&mut conn, &[a].into()).unwrap(), None);
+
+        // `b` added, `a` dropped: order of the set doesn't matter
+        assert_eq!(reclassify(&mut conn, &[b, c].into()).unwrap(), Some(3));
+        assert_eq!(reclassify(&mut conn, &[c, b].into()).unwrap(), None);
+        assert_eq!(directions(&conn), [
+            ("OUT".to_string(), Some(c.to_string())),
+            ("IN".to_string(), Some(c.to_string())),
+            ("IN".to_string(), Some(b.to_string())),
+        ]);
+
+        // netflows follow once recomputed: 4 + 1 in, 10 out
+        let net = crate::aggregator::recompute_full(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(net, [(TOKEN.to_string(), Decimal::from(-5))]);
+    }
+
+    #[test]
+    fn transfer_writer_reuses_statement_within_transaction() {
+        let mut conn = open_in_memory();
+        let tx = conn.transaction().unwrap();
+        let mut writer = TransferWriter::new(&tx).unwrap();
+        for i in 0..100 {
+            writer.push(&new_transfer("0xa", i)).unwrap();
+        }
+        writer.push(&new_transfer("0xa", 0)).unwrap(); // duplicate → upsert
+        drop(writer);
+        tx.commit().unwrap();
+
+        assert_eq!(count(&conn), 100);
+    }
+
+    #[test]
+    fn parallel_writers_lose_no_inserts() {
+        const WRITERS: i64 = 8;
+        const BATCHES: i64 = 10;
+        const PER_BATCH: i64 = 25;
+
+        let path = std::env::temp_dir().join(format!("indexer-busy-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        run_migrations(&mut connect(&path).unwrap()).unwrap();
+
+        let handles: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let mut conn = connect(&path).unwrap();
+                    for b in 0..BATCHES {
+                        let tx_hash = format!("0x{:02x}{:04x}", w, b);
+                        let tx = conn.transaction().unwrap();
+                        let mut writer = TransferWriter::new(&tx).unwrap();
+                        for i in 0..PER_BATCH {
+                            let block_number = (w * BATCHES + b) as u64;
+                            writer
+                                .push(&NewTransfer { block_number, ..new_transfer(&tx_hash, i as u64) })
+                                .unwrap();
+                        }
+                        drop(writer);
+                        commit_with_retry(tx).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let total = count(&connect(&path).unwrap());
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+        assert_eq!(total, WRITERS * BATCHES * PER_BATCH);
+    }
+}
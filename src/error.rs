@@ -0,0 +1,129 @@
+// src/error.rs
+// Typed failures for the RPC, DB and indexer layers, so callers can decide
+// per class whether to retry, back off or give up
+use rusqlite::ErrorCode;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use thiserror::Error;
+
+/// Failures `RecentErrors` keeps; older ones are dropped
+pub const RECENT_ERRORS_CAP: usize = 20;
+
+#[derive(Debug, Error)]
+pub enum IndexerError {
+    /// Transport failure, non-OK HTTP status or a JSON-RPC error object
+    #[error("RPC error: {0}")]
+    Rpc(String),
+    /// Response or chain data that couldn't be decoded / represented
+    #[error("decode error: {0}")]
+    Decode(String),
+    /// SQLite failure
+    #[error("database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    /// Invalid configuration
+    #[error("config error: {0}")]
+    Config(String),
+    /// RPC circuit breaker is open; no request was sent
+    #[error("RPC circuit open, retry in {0:?}")]
+    CircuitOpen(std::time::Duration),
+}
+
+pub type Result<T, E = IndexerError> = std::result::Result<T, E>;
+
+impl From<reqwest::Error> for IndexerError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Rpc(e.to_string())
+    }
+}
+
+impl IndexerError {
+    /// Worth retrying later (provider hiccup, DB contention) rather than aborting
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Rpc(_) | Self::CircuitOpen(_) => true,
+            Self::Db(e) => is_busy(e),
+            Self::Decode(_) | Self::Config(_) => false,
+        }
+    }
+}
+
+/// The indexer's latest failures (a range given up on, a failed head or
+/// netflow refresh) with when they happened, for `/health`. Holds at most
+/// `RECENT_ERRORS_CAP`. Cheap to clone; clones share the buffer.
+#[derive(Debug, Clone, Default)]
+pub struct RecentErrors(Arc<Mutex<VecDeque<(Instant, String)>>>);
+
+impl RecentErrors {
+    pub fn push(&self, message: impl Into<String>) {
+        let mut errors = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if errors.len() == RECENT_ERRORS_CAP {
+            errors.pop_front();
+        }
+        errors.push_back((Instant::now(), message.into()));
+    }
+
+    /// Newest first
+    pub fn snapshot(&self) -> Vec<(Instant, String)> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).iter().rev().cloned().collect()
+    }
+}
+
+/// SQLITE_BUSY / SQLITE_LOCKED: another connection holds the lock
+pub(crate) fn is_busy(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sqlite_error(code: ErrorCode) -> rusqlite::Error {
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error { code, extended_code: 0 },
+            None,
+        )
+    }
+
+    #[test]
+    fn classifies_transient_errors() {
+        assert!(IndexerError::Rpc("timeout".into()).is_transient());
+        assert!(IndexerError::CircuitOpen(std::time::Duration::from_secs(1)).is_transient());
+        assert!(IndexerError::Db(sqlite_error(ErrorCode::DatabaseBusy)).is_transient());
+        assert!(IndexerError::Db(sqlite_error(ErrorCode::DatabaseLocked)).is_transient());
+
+        assert!(!IndexerError::Db(sqlite_error(ErrorCode::ConstraintViolation)).is_transient());
+        assert!(!IndexerError::Decode("bad hex".into()).is_transient());
+        assert!(!IndexerError::Config("missing".into()).is_transient());
+    }
+
+    #[test]
+    fn recent_errors_keep_the_newest_up_to_the_cap() {
+        let errors = RecentErrors::default();
+        for i in 0..RECENT_ERRORS_CAP + 5 {
+            errors.clone().push(format!("error {i}"));
+        }
+        let kept: Vec<String> = errors.snapshot().into_iter().map(|(_, e)| e).collect();
+        assert_eq!(kept.len(), RECENT_ERRORS_CAP);
+        assert_eq!(kept[0], format!("error {}", RECENT_ERRORS_CAP + 4));
+        assert_eq!(kept[RECENT_ERRORS_CAP - 1], "error 5");
+    }
+
+    #[test]
+    fn recent_errors_survive_a_poisoned_lock() {
+        let errors = RecentErrors::default();
+        let held = errors.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = held.0.lock().unwrap();
+            panic!("poison the buffer");
+        })
+        .join();
+        assert!(errors.0.is_poisoned());
+
+        errors.push("after the panic");
+        assert_eq!(errors.snapshot()[0].1, "after the panic");
+    }
+}
@@ -1,89 +1,914 @@
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, PoisonError};
 use rusqlite::{Connection, Transaction};
-use crate::{config::Config, aggregator, rpc, parser, db};
-use eyre::Result;
-use tokio::time::{sleep, Duration};
-use tracing::{info, warn, error};
+use crate::{config::{Config, IndexMode, NetflowUnits, SnapshotStart, MAX_DECIMAL_SCALE}, aggregator, alerts::Alerter, backoff::Backoff, cache::{NetflowCache, TransferCounts}, rpc, parser, db, models};
+use crate::error::{IndexerError, RecentErrors, Result};
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{debug, field, info, instrument, warn, error, Span};
 use rust_decimal::Decimal;
 use rust_decimal::prelude::FromPrimitive;
+use alloy::primitives::U256;
+use chrono::{DateTime, Utc};
 
-pub async fn run(cfg: Config, conn: Arc<Mutex<Connection>>) -> Result<()> {
-    let backfill: u64 = 5000;                // blocks to scan on startup
-    let lookback: u64 = 100;                 // blocks to scan per loop
-    let rpc_pause = Duration::from_millis(200); // pause between RPC requests
-    let mut retry_delay = 10;                // retry backoff in seconds
+/// Queued failed ranges retried per live loop, so a long outage's backlog
+/// doesn't starve new blocks
+const MAX_RANGE_RETRIES_PER_LOOP: usize = 10;
+/// Ranges the fetch stage may get ahead of the write stage before it waits
+const PIPELINE_DEPTH: usize = 4;
 
-    info!("Indexer started with lookback = {} blocks", lookback);
+/// Keeps cached netflows in step with committed transfers. A token is marked
+/// dirty before its writes and only cleared once `update_token_netflows` succeeds,
+/// so a refresh that fails after the commit is retried on the next round
+/// instead of leaving the netflow stale. The API's in-memory copy is
+/// reloaded under the same connection lock as the write, and its cached
+/// transfer counts are dropped for every token written.
+struct NetflowRefresh {
+    units: NetflowUnits,
+    alerts: Alerter, // ALERT_RULES → WEBHOOK_URL, checked after each refresh
+    cache: NetflowCache,
+    counts: TransferCounts,
+    errors: RecentErrors, // failed refreshes, for /health
+    dirty: Mutex<BTreeSet<String>>,
+}
 
-    // ---------------------------
-    // One-time backfill at startup
-    // ---------------------------
-    match rpc::get_block_number(&cfg.rpc_http_url).await {
-        Ok(latest_block) => {
-            retry_delay = 10; // reset after success
-            let target_block = latest_block.saturating_sub(cfg.confirmations);
-            let start_block = target_block.saturating_sub(backfill);
-
-            info!("Backfill: scanning {} → {}", start_block, target_block);
-
-            for token in &cfg.token_set {
-                match rpc::get_transfer_logs(&cfg.rpc_http_url, token, start_block, target_block).await {
-                    Ok(logs) => {
-                        let mut processed_count = 0;
-                        let mut db = conn.lock().unwrap();
-
-                        // batch writes
-                        let tx: Transaction = db.transaction()?;
-                        for log in logs {
-                            if let Some(transfer) = parser::decode_transfer(&log) {
-                                let amount = Decimal::from_u128(transfer.value_u128)
-                                    .unwrap_or(Decimal::ZERO)
-                                    / Decimal::from(10u64.pow(18));
-
-                                let direction = if cfg.exchange_set.contains(&transfer.to) {
-                                    Some("IN")
-                                } else if cfg.exchange_set.contains(&transfer.from) {
-                                    Some("OUT")
-                                } else {
-                                    None
-                                };
-
-                                if let Some(dir) = direction {
-                                    if let Err(e) = db::record_transfer(
-                                        &tx,
-                                        transfer.block_number as i64,
-                                        &transfer.tx_hash,
-                                        transfer.log_index as i64,
-                                        token,
-                                        &transfer.from.to_string(),
-                                        &transfer.to.to_string(),
-                                        amount,
-                                        dir,
-                                    ) {
-                                        error!("Backfill insert failed: {:?}", e);
-                                    } else {
-                                        processed_count += 1;
-                                    }
-                                }
-                            }
-                        }
-                        tx.commit()?; // commit batch
+impl NetflowRefresh {
+    fn new(cfg: &Config, cache: NetflowCache, counts: TransferCounts, errors: RecentErrors) -> Self {
+        Self { units: cfg.netflow_units, alerts: Alerter::new(cfg), cache, counts, errors, dirty: Mutex::default() }
+    }
 
-                        if let Err(e) = aggregator::update_netflows(&mut db) {
-                            error!("Aggregator failed (backfill): {:?}", e);
-                        }
+    fn mark(&self, token: &str) {
+        self.dirty.lock().unwrap_or_else(PoisonError::into_inner).insert(token.to_string());
+    }
+
+    fn is_dirty(&self) -> bool {
+        !self.dirty.lock().unwrap_or_else(PoisonError::into_inner).is_empty()
+    }
+
+    /// Recompute netflows if any token is dirty and check them against
+    /// ALERT_RULES. Returns false, keeping the tokens dirty, if it failed.
+    fn refresh(&self, conn: &Mutex<Connection>) -> bool {
+        let dirty: Vec<String> = self.dirty.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect();
+        if dirty.is_empty() {
+            return true;
+        }
+        let db = db::lock(conn);
+        let updated = aggregator::update_token_netflows(&db, self.units, &dirty);
+        if updated.is_ok() {
+            if let Err(e) = self.cache.reload(&db) {
+                // an empty cache only costs DB reads; a stale one would be wrong
+                error!("Netflow cache reload failed, serving /netflow from the DB: {:?}", e);
+                self.errors.push(format!("netflow cache reload failed: {}", e));
+                self.cache.clear();
+            }
+        }
+        drop(db);
+        match updated {
+            Ok(netflows) => {
+                let mut set = self.dirty.lock().unwrap_or_else(PoisonError::into_inner);
+                for token in &dirty {
+                    set.remove(token);
+                }
+                drop(set);
+                self.alerts.notify(&netflows);
+                true
+            }
+            Err(e) => {
+                error!("Aggregator failed, {} token(s) stay dirty until the next round: {:?}", dirty.len(), e);
+                self.errors.push(format!("netflow refresh failed: {}", e));
+                false
+            }
+        }
+    }
+}
+
+/// Bring the DB in line with `cfg`'s exchange wallets: sync the `exchanges`
+/// table, logging each wallet added or removed, then reclassify stored
+/// transfers and recompute netflows if the set differs from the one they were
+/// classified with. Run at startup and after an EXCHANGE_ADDRESSES_FILE
+/// reload. Each step takes the lock on its own, so API reads aren't held up
+/// for the whole reclassify. Returns whether netflows were recomputed.
+pub fn apply_exchange_set(conn: &Mutex<Connection>, cfg: &Config) -> Result<bool> {
+    let diff = db::sync_exchanges(&mut db::lock(conn), &cfg.exchange_set, &cfg.exchange_labels, &cfg.exchange_label)?;
+    for addr in &diff.added {
+        let label = addr.parse().map_or(cfg.exchange_label.as_str(), |a| cfg.exchange_label_for(&a));
+        info!("Exchange wallet added: {} ({})", addr, label);
+    }
+    for addr in &diff.removed {
+        info!("Exchange wallet removed: {}", addr);
+    }
+    if diff.is_empty() {
+        info!("Exchange wallets unchanged ({})", cfg.exchange_set.len());
+    }
+
+    // the set changed since stored directions were derived: re-derive them
+    let reclassified = db::reclassify(&mut db::lock(conn), &cfg.exchange_set)?;
+    match reclassified {
+        Some(changed) => {
+            info!("Exchange set changed: reclassified {} stored transfer(s)", changed);
+            if changed > 0 {
+                aggregator::recompute_full(&db::lock(conn), cfg.netflow_units)?;
+                return Ok(true);
+            }
+            Ok(false)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Re-read EXCHANGE_ADDRESSES_FILE between live rounds. When its wallets
+/// changed, apply them to the DB first and only then to `cfg`, so the next
+/// round classifies with the set the stored rows were reclassified to. A
+/// file or DB failure keeps the current set and is retried next time.
+fn reload_exchanges(cfg: &mut Config, conn: &Mutex<Connection>, netflows: &NetflowRefresh, errors: &RecentErrors) {
+    let (exchange_set, exchange_labels) = match cfg.reread_exchange_file() {
+        Ok(Some(next)) => next,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Exchange wallet reload failed, keeping the current set: {}", e);
+            errors.push(format!("exchange wallet reload failed: {}", e));
+            return;
+        }
+    };
+    let mut next = cfg.clone();
+    next.exchange_set = exchange_set;
+    next.exchange_labels = exchange_labels;
+
+    match apply_exchange_set(conn, &next) {
+        Ok(recomputed) => {
+            if recomputed {
+                netflows.counts.invalidate_all(); // directions may have changed
+                if let Err(e) = netflows.cache.reload(&db::lock(conn)) {
+                    error!("Netflow cache reload failed, serving /netflow from the DB: {:?}", e);
+                    netflows.cache.clear();
+                }
+            }
+            info!("Exchange wallets reloaded from {}: {} tracked",
+                next.exchange_addresses_file.as_deref().unwrap_or_default(), next.exchange_set.len());
+            *cfg = next;
+        }
+        Err(e) => {
+            warn!("Exchange wallet reload failed, keeping the current set: {:?}", e);
+            errors.push(format!("exchange wallet reload failed: {}", e));
+        }
+    }
+}
+
+/// Scale a raw 256-bit token amount down by `decimals` into a `Decimal`.
+/// Returns `None` when the whole-token part doesn't fit in a `Decimal` (~7.9e28)
+/// or `decimals` exceeds what a `Decimal` can represent.
+fn scale_amount(raw: U256, decimals: u32) -> Option<Decimal> {
+    if decimals > MAX_DECIMAL_SCALE {
+        return None;
+    }
+    let unit = U256::from(10u64).pow(U256::from(decimals));
+    let whole = u128::try_from(raw / unit).ok()?;
+    let frac = i128::try_from(raw % unit).ok()?;
+
+    Decimal::from_u128(whole)?
+        .checked_add(Decimal::from_i128_with_scale(frac, decimals))
+}
+
+/// Decimals for `token`, fetching and caching its metadata on first use.
+/// When the `eth_call`s fail, `fallback` (DEFAULT_DECIMALS) is recorded in
+/// `tokens` as assumed, and the fetch is retried each round until it succeeds;
+/// until then every transfer is scaled (and flagged) with the stored value.
+async fn token_decimals(
+    rpc: &rpc::RpcClient,
+    conn: &Mutex<Connection>,
+    token: &str,
+    fallback: u32,
+) -> db::TokenDecimals {
+    let stored = match db::token_decimals(&db::lock(conn), token) {
+        Ok(Some(stored)) if !stored.assumed => return stored,
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("Token metadata lookup failed for {}: {:?}", token, e);
+            None
+        }
+    };
+
+    match rpc.get_token_metadata(token).await {
+        Ok(meta) => {
+            info!("Token {}: {:?} ({:?}), {} decimals", token, meta.name, meta.symbol, meta.decimals);
+            if let Some(assumed) = stored.filter(|s| s.decimals != meta.decimals) {
+                warn!("Token {} has {} decimals, not the {} assumed so far: rescale its rows with decimals_assumed set",
+                    token, meta.decimals, assumed.decimals);
+            }
+            if let Err(e) = db::upsert_token(
+                &db::lock(conn),
+                token,
+                meta.name.as_deref(),
+                meta.symbol.as_deref(),
+                meta.decimals,
+            ) {
+                warn!("Caching token metadata failed for {}: {:?}", token, e);
+            }
+            meta.decimals.into()
+        }
+        Err(e) => {
+            if let Some(assumed) = stored {
+                debug!("Token metadata fetch failed again for {}, still assuming {} decimals: {:?}",
+                    token, assumed.decimals, e);
+                return assumed;
+            }
+            match db::assume_token_decimals(&db::lock(conn), token, fallback) {
+                Ok(()) => warn!("Token metadata fetch failed for {}, assuming {} decimals until it succeeds: {:?}",
+                    token, fallback, e),
+                Err(db_err) => warn!("Token metadata fetch failed for {} ({:?}) and recording the assumed {} decimals failed: {:?}",
+                    token, e, fallback, db_err),
+            }
+            db::TokenDecimals { decimals: fallback, assumed: true }
+        }
+    }
+}
+
+/// Refuse to write another chain's logs into this DB: wait (retrying with
+/// `backoff`) until the RPC answers `eth_chainId`, and fail on a mismatch
+async fn verify_chain_id(rpc: &rpc::RpcClient, cfg: &Config, backoff: &Backoff) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match rpc.get_chain_id().await {
+            Ok(id) if id == cfg.chain_id => {
+                info!("RPC chain id {} matches CHAIN_ID", id);
+                return Ok(());
+            }
+            Ok(id) => {
+                return Err(IndexerError::Config(format!(
+                    "RPC {} serves chain {} but CHAIN_ID is {}", cfg.rpc_http_url, id, cfg.chain_id
+                )))
+            }
+            Err(e) => {
+                let retry_in = backoff.delay(attempt);
+                warn!("Could not verify chain id, not indexing until it is (retry in {:?}): {:?}", retry_in, e);
+                sleep(retry_in).await;
+                attempt = attempt.saturating_add(1);
+            }
+        }
+    }
+}
+
+/// Compute the `[from, to]` range to scan ending at the confirmed head.
+/// Returns `None` while the confirmed head is still below `start_block`
+/// (fresh chains / test networks), so callers wait instead of scanning block 0.
+fn scan_range(latest: u64, confirmations: u64, span: u64, start_block: u64) -> Option<(u64, u64)> {
+    let target = latest.checked_sub(confirmations)?;
+    if target < start_block {
+        return None;
+    }
+    Some((target.saturating_sub(span).max(start_block), target))
+}
+
+/// The chain head to scan from: the BLOCK_TAG block as the provider resolves
+/// it, or the latest block (each token then stays its CONFIRMATIONS behind)
+async fn chain_head(rpc: &rpc::RpcClient, cfg: &Config) -> Result<u64> {
+    match cfg.block_tag {
+        Some(tag) => rpc.get_tagged_block_number(tag).await,
+        None => rpc.get_block_number().await,
+    }
+}
+
+/// Each token's `(token, from, to)` range of up to `span` blocks ending at
+/// `head` less that token's confirmations (`Config::confirmations_for`).
+/// Tokens whose confirmed head is still below START_BLOCK are left out.
+fn token_ranges(cfg: &Config, head: u64, span: u64) -> Vec<(String, u64, u64)> {
+    let mut ranges: Vec<_> = cfg
+        .token_set
+        .iter()
+        .filter_map(|token| {
+            let (from, to) = scan_range(head, cfg.confirmations_for(token), span, cfg.start_block)?;
+            Some((token.clone(), from, to))
+        })
+        .collect();
+    ranges.sort();
+    ranges
+}
+
+/// Build the API/WS representation of a just-recorded transfer, stamped
+/// like the row: its block's time, else the time it was indexed
+fn to_event(row: &db::NewTransfer) -> Result<models::Transfer> {
+    let timestamp = row
+        .block_timestamp
+        .and_then(|ts| DateTime::from_timestamp(i64::try_from(ts).ok()?, 0))
+        .unwrap_or_else(Utc::now);
+    Ok(models::Transfer {
+        tx_hash: row.tx_hash.to_string(),
+        block_number: db::to_sql_int(row.block_number)?,
+        from_address: row.from.to_string(),
+        to_address: row.to.to_string(),
+        token_address: row.token_address.to_string(),
+        amount: row.amount.to_string(),
+        direction: row.direction.to_string(),
+        timestamp: timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+        exchange_address: row.exchange_address.map(str::to_string),
+        raw_amount: Some(row.raw_amount.to_string()),
+        decimals: row.decimals,
+        chain_id: row.chain_id,
+    })
+}
+
+/// Decode one token's logs, write the matching transfers (and raw events) in
+/// `MAX_BATCH_ROWS`-row transactions, broadcast what was committed, then refresh
+/// netflows and check them against ALERT_RULES. Returns the number of transfers
+/// written; a failed refresh leaves the token dirty in `netflows` rather than failing the write.
+#[instrument(level = "debug", skip_all, fields(logs = logs.len()))]
+fn index_logs(
+    cfg: &Config,
+    conn: &Mutex<Connection>,
+    events: &broadcast::Sender<models::Transfer>,
+    netflows: &NetflowRefresh,
+    token: &str,
+    decimals: db::TokenDecimals,
+    logs: Vec<rpc::Log>,
+) -> Result<usize> {
+    // before the first commit, so even a write that fails halfway gets refreshed
+    netflows.mark(token);
+    let written = write_logs(cfg, conn, events, token, decimals, logs);
+    // even a failed write may have committed (or deleted) some batches
+    netflows.counts.invalidate(token);
+    let (processed_count, batches) = written?;
+    if batches > 1 {
+        info!("Wrote {} transfers for {} in {} transactions", processed_count, token, batches);
+    }
 
-                        info!("Backfilled {} transfers for token {}", processed_count, token);
+    // only once every batch is in, so netflows and alerts never reflect half a range
+    netflows.refresh(conn);
+
+    Ok(processed_count)
+}
+
+/// Write `logs` in transactions of at most `MAX_BATCH_ROWS` logs (so at most
+/// that many rows), releasing the DB lock between them so API reads aren't
+/// stalled by a huge chunk. Returns (transfers written, transactions
+/// committed). If a commit fails, earlier batches stay; re-indexing the range
+/// upserts on (chain, block, log index), so the retry only fills in what's missing.
+///
+/// Needs no RPC or netflow state, so `benches/pipeline.rs` drives it directly.
+pub fn write_logs(
+    cfg: &Config,
+    conn: &Mutex<Connection>,
+    events: &broadcast::Sender<models::Transfer>,
+    token: &str,
+    decimals: db::TokenDecimals,
+    logs: Vec<rpc::Log>,
+) -> Result<(usize, usize)> {
+    let batch_rows = cfg.max_batch_rows.max(1);
+    let mut logs = logs.into_iter().peekable();
+    let mut processed_count = 0;
+    let mut batches = 0;
+
+    while logs.peek().is_some() {
+        let batch: Vec<rpc::Log> = logs.by_ref().take(batch_rows).collect();
+        let mut db = db::lock(conn);
+        let tx: Transaction = db.transaction()?;
+        let committed = process_logs(&tx, cfg, token, decimals, batch)?;
+        db::commit_with_retry(tx)?; // commit batch
+        batches += 1;
+        drop(db);
+
+        // send only errors when nobody is subscribed; that's fine
+        processed_count += committed.len();
+        for event in committed {
+            let _ = events.send(event);
+        }
+    }
+
+    Ok((processed_count, batches))
+}
+
+/// Decode, classify and record one token's already-fetched `logs` inside
+/// `tx`: exchange transfers (and NEUTRAL ones under INDEX_MODE=all) go to
+/// `transfers`, other events to `events`, and removed logs delete their rows.
+/// NEUTRAL transfers are thinned to the token's SAMPLE_RATE (see `sampled`).
+/// A row that fails to insert is logged and skipped, but a removed log that
+/// can't be deleted fails the batch. Returns the transfers
+/// written, to broadcast once `tx` commits. No RPC, no netflow refresh.
+pub fn process_logs(
+    tx: &Transaction,
+    cfg: &Config,
+    token: &str,
+    decimals: db::TokenDecimals,
+    logs: Vec<rpc::Log>,
+) -> Result<Vec<models::Transfer>> {
+    let mut writer = db::TransferWriter::new(tx)?;
+    let mut written = Vec::new();
+    let layout = cfg.value_layout_for(token);
+    let sample_rate = cfg.sample_rate_for(token);
+
+    for log in logs {
+        if log.removed {
+            let Some((block, log_index)) = parser::log_position(&log) else { continue };
+            // a failed delete would leave the reorged-out row counted, so fail
+            // the whole batch and let the range be retried
+            let n = db::delete_log(tx, cfg.chain_id, token, block, &log.tx_hash, log_index)?;
+            if n > 0 {
+                info!("Reorg: dropped {} row(s) for removed log {}:{} (block {})", n, log.tx_hash, log_index, block);
+            }
+            continue;
+        }
+        let transfer = match parser::decode_log(&log, layout) {
+            Some(parser::Event::Transfer(transfer)) => transfer,
+            Some(parser::Event::Other(ev)) => {
+                if let Err(e) = db::record_event(tx, &db::NewEvent {
+                    block_number: ev.block_number,
+                    tx_hash: &ev.tx_hash,
+                    log_index: ev.log_index,
+                    contract_address: token,
+                    topic0: &ev.topic0,
+                    name: ev.name,
+                    topics: &ev.topics,
+                    data: &ev.data,
+                }) {
+                    error!("Event insert failed: {:?}", e);
+                }
+                continue;
+            }
+            None => continue,
+        };
+
+        let Some(amount) = scale_amount(transfer.value, decimals.decimals) else {
+            warn!("Amount {} ({} decimals) overflows Decimal (tx {}), skipping",
+                transfer.value, decimals.decimals, transfer.tx_hash);
+            continue;
+        };
+
+        if amount < cfg.min_transfer_amount {
+            continue; // dust
+        }
+
+        // (direction, matched exchange wallet)
+        let direction = if cfg.exchange_set.contains(&transfer.to) {
+            info!("Inflow {} POL → {:?} (block {})",
+                amount, transfer.to, transfer.block_number);
+            Some(("IN", Some(transfer.to)))
+        } else if cfg.exchange_set.contains(&transfer.from) {
+            info!("Outflow {} POL ← {:?} (block {})",
+                amount, transfer.from, transfer.block_number);
+            Some(("OUT", Some(transfer.from)))
+        } else if cfg.index_mode == IndexMode::All && sampled(&transfer.tx_hash, sample_rate) {
+            Some(("NEUTRAL", None))
+        } else {
+            None
+        };
+
+        if let Some((dir, exchange)) = direction {
+            let exchange = exchange.map(|a| a.to_string());
+            let row = db::NewTransfer {
+                chain_id: cfg.chain_id,
+                block_number: transfer.block_number,
+                tx_hash: &transfer.tx_hash,
+                log_index: transfer.log_index,
+                token_address: token,
+                from: &transfer.from.to_string(),
+                to: &transfer.to.to_string(),
+                amount,
+                direction: dir,
+                exchange_address: exchange.as_deref(),
+                raw_amount: &transfer.value.to_string(),
+                decimals: decimals.decimals,
+                decimals_assumed: decimals.assumed,
+                block_timestamp: log.block_timestamp,
+            };
+            match writer.push(&row).and_then(|()| to_event(&row)) {
+                Ok(event) => written.push(event),
+                Err(e) => error!("Insert failed: {:?}", e),
+            }
+        }
+    }
+
+    Ok(written)
+}
+
+/// Whether a NEUTRAL transfer in `tx_hash` is kept at `rate`. The hash is
+/// already uniform, so its leading 64 bits decide: the same transaction is
+/// kept or dropped on every run (and every log in it together). A hash that
+/// doesn't parse is kept.
+fn sampled(tx_hash: &str, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    let hex = tx_hash.trim_start_matches("0x");
+    match hex.get(..16).and_then(|h| u64::from_str_radix(h, 16).ok()) {
+        Some(bits) => (bits as f64) < rate * u64::MAX as f64,
+        None => true,
+    }
+}
+
+/// One token's logs for `[from_block, to_block]`, handed from the fetch stage
+/// to the write stage. `logs` holds the fetch error once every attempt failed.
+struct FetchedRange {
+    token: String,
+    from_block: u64,
+    to_block: u64,
+    logs: Result<(Vec<rpc::Log>, db::TokenDecimals)>, // logs and the token's decimals
+}
+
+/// Fetch stage of the indexer pipeline: fetch `(token, from, to)` ranges in
+/// order on a task of its own, trying each up to `attempts` times, and pass
+/// them to the write stage over a channel of `PIPELINE_DEPTH`. The next
+/// ranges' RPC round-trips overlap the current commit; once the writer falls
+/// `PIPELINE_DEPTH` behind, sending waits, which also caps the logs held in
+/// memory. Stops early when the receiver is dropped. Under
+/// EXCHANGE_TOPIC_FILTER only logs from/to an exchange wallet are fetched.
+/// Each range's block times are looked up in one batch as part of the fetch.
+fn spawn_fetcher(
+    cfg: &Config,
+    rpc: &rpc::RpcClient,
+    conn: &Arc<Mutex<Connection>>,
+    ranges: Vec<(String, u64, u64)>,
+    attempts: u32,
+) -> mpsc::Receiver<FetchedRange> {
+    let (tx, rx) = mpsc::channel(PIPELINE_DEPTH);
+    let rpc = rpc.clone();
+    let conn = Arc::clone(conn);
+    let topics: HashMap<String, Vec<String>> =
+        ranges.iter().map(|(token, ..)| (token.clone(), cfg.topics_for(token))).collect();
+    let exchange_topics = cfg.exchange_topics();
+    let exchange_topic_batch = cfg.exchange_topic_batch;
+    let rpc_pause = Duration::from_millis(cfg.rpc_pause_ms);
+    let default_decimals = cfg.default_decimals;
+    let backoff = Backoff::new(Duration::from_millis(cfg.retry_base_ms), Duration::from_secs(cfg.retry_cap_secs));
+
+    tokio::spawn(async move {
+        for (token, from_block, to_block) in ranges {
+            let mut attempt = 0;
+            let logs = loop {
+                let fetched = match &exchange_topics {
+                    Some(exchanges) => {
+                        rpc.get_address_filtered_logs(&token, &topics[&token], exchanges, exchange_topic_batch, from_block, to_block).await
+                    }
+                    None => rpc.get_transfer_logs(&token, &topics[&token], from_block, to_block).await,
+                };
+                // one batch round-trip for the range's block times
+                let fetched = match fetched {
+                    Ok(mut logs) => rpc.fill_block_timestamps(&mut logs).await.map(|()| logs),
+                    Err(e) => Err(e),
+                };
+                match fetched {
+                    Ok(logs) => break Ok(logs),
+                    Err(e) if attempt + 1 < attempts => {
+                        warn!("Fetch failed for {} ({} → {}), retrying: {:?}", token, from_block, to_block, e);
+                        sleep(backoff.delay(attempt)).await;
+                        attempt += 1;
                     }
-                    Err(e) => warn!("Backfill failed for {}: {:?}", token, e),
+                    Err(e) => break Err(e),
                 }
+            };
+            let logs = match logs {
+                Ok(logs) => Ok((logs, token_decimals(&rpc, &conn, &token, default_decimals).await)),
+                Err(e) => Err(e),
+            };
 
-                sleep(rpc_pause).await; // avoid hammering
+            if tx.send(FetchedRange { token, from_block, to_block, logs }).await.is_err() {
+                break; // the write stage stopped
+            }
+            sleep(rpc_pause).await; // avoid hammering
+        }
+    });
+    rx
+}
+
+/// Why a fetched range wasn't indexed. Callers back off on a failed fetch and
+/// queue or abort on a failed write, so the two are kept apart.
+#[derive(Debug)]
+enum RangeError {
+    Fetch(IndexerError),
+    Write(IndexerError),
+}
+
+/// Write stage: index a fetched range inside an `index_range` span carrying
+/// its token and blocks, so the write and netflow steps correlate
+#[instrument(
+    name = "index_range",
+    skip_all,
+    fields(token = %fetched.token, from_block = fetched.from_block, to_block = fetched.to_block, transfers = field::Empty)
+)]
+fn write_range(
+    cfg: &Config,
+    conn: &Mutex<Connection>,
+    events: &broadcast::Sender<models::Transfer>,
+    netflows: &NetflowRefresh,
+    fetched: FetchedRange,
+) -> std::result::Result<usize, RangeError> {
+    let (logs, decimals) = fetched.logs.map_err(RangeError::Fetch)?;
+    let written = index_logs(cfg, conn, events, netflows, &fetched.token, decimals, logs).map_err(RangeError::Write)?;
+    Span::current().record("transfers", written);
+    Ok(written)
+}
+
+/// Remember a range that couldn't be indexed so a later loop retries it
+/// (`failed_ranges` survives restarts), and report it in `errors`. Failing
+/// to record it is only logged.
+fn queue_failed_range(
+    conn: &Mutex<Connection>,
+    errors: &RecentErrors,
+    token: &str,
+    (from_block, to_block): (u64, u64),
+    error: &IndexerError,
+    retry_in: Duration,
+) {
+    errors.push(format!("{} → {} for {}: {}", from_block, to_block, token, error));
+    let db = db::lock(conn);
+    match db::record_failed_range(&db, token, from_block, to_block, &error.to_string(), retry_in) {
+        Ok(attempts) => warn!("Queued {} → {} for {} (attempt {}), retry in {:?}: {}",
+            from_block, to_block, token, attempts, retry_in, error),
+        Err(e) => error!("Could not queue failed range {} → {} for {}: {:?}",
+            from_block, to_block, token, e),
+    }
+}
+
+/// `queue_failed_range` for the live loop, whose lookback window overlaps the
+/// previous round's: only blocks not already queued are added
+fn queue_live_range(
+    conn: &Mutex<Connection>,
+    errors: &RecentErrors,
+    token: &str,
+    (from_block, to_block): (u64, u64),
+    error: &IndexerError,
+    retry_in: Duration,
+) {
+    errors.push(format!("{} → {} for {}: {}", from_block, to_block, token, error));
+    let db = db::lock(conn);
+    match db::record_uncovered_range(&db, token, from_block, to_block, &error.to_string(), retry_in) {
+        Ok(queued) if queued.is_empty() => debug!("{} → {} for {} already queued", from_block, to_block, token),
+        Ok(queued) => warn!("Queued {:?} for {}, retry in {:?}: {}", queued, token, retry_in, error),
+        Err(e) => error!("Could not queue failed range {} → {} for {}: {:?}",
+            from_block, to_block, token, e),
+    }
+}
+
+/// Retry queued ranges that are due, before the live loop scans new blocks
+async fn retry_failed_ranges(
+    cfg: &Config,
+    rpc: &rpc::RpcClient,
+    conn: &Arc<Mutex<Connection>>,
+    events: &broadcast::Sender<models::Transfer>,
+    netflows: &NetflowRefresh,
+    errors: &RecentErrors,
+    backoff: &Backoff,
+) -> Result<()> {
+    let due = db::due_failed_ranges(&db::lock(conn), MAX_RANGE_RETRIES_PER_LOOP);
+    let due = match due {
+        Ok(due) => due,
+        Err(e) => {
+            warn!("Could not read failed ranges: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    let ranges = due
+        .iter()
+        .map(|r| (r.token_address.clone(), r.from_block as u64, r.to_block as u64))
+        .collect();
+    let mut fetched = spawn_fetcher(cfg, rpc, conn, ranges, 1);
+
+    for range in due {
+        let Some(next) = fetched.recv().await else { break };
+        let token = range.token_address.as_str();
+        let (from_block, to_block) = (range.from_block as u64, range.to_block as u64);
+        info!("Retrying {} → {} for {} (attempt {})", from_block, to_block, token, range.attempts + 1);
+
+        match write_range(cfg, conn, events, netflows, next) {
+            Ok(n) => {
+                info!("Recovered {} → {} for {}: {} transfers", from_block, to_block, token, n);
+                if let Err(e) = db::clear_failed_range(&db::lock(conn), token, from_block, to_block) {
+                    warn!("Could not clear failed range for {}: {:?}", token, e);
+                }
+            }
+            // a non-transient write error is fatal here just like in the live loop
+            Err(RangeError::Write(e)) if matches!(e, IndexerError::Db(_)) && !e.is_transient() => return Err(e),
+            Err(RangeError::Fetch(e) | RangeError::Write(e)) => {
+                let retry_in = backoff.delay(range.attempts as u32);
+                queue_failed_range(conn, errors, token, (from_block, to_block), &e, retry_in);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `[from, to]` split into consecutive ranges of at most `size` blocks
+fn chunks(from: u64, to: u64, size: u64) -> impl Iterator<Item = (u64, u64)> {
+    let size = size.max(1);
+    let mut next = (from <= to).then_some(from);
+    std::iter::from_fn(move || {
+        let start = next?;
+        let end = start.saturating_add(size - 1).min(to);
+        next = end.checked_add(1).filter(|&n| n <= to);
+        Some((start, end))
+    })
+}
+
+/// Startup backfill of one token in `BACKFILL_CHUNK_BLOCKS` chunks, logging
+/// and recording progress (`/health/backfill`) after each chunk is written. A
+/// chunk that fails is queued in `failed_ranges` and the backfill moves on.
+/// After a crash the next start resumes at the first unrecorded chunk.
+#[allow(clippy::too_many_arguments)]
+#[instrument(name = "backfill", skip_all, fields(token = %token, from_block, to_block))]
+async fn backfill_token(
+    cfg: &Config,
+    rpc: &rpc::RpcClient,
+    conn: &Arc<Mutex<Connection>>,
+    events: &broadcast::Sender<models::Transfer>,
+    netflows: &NetflowRefresh,
+    errors: &RecentErrors,
+    backoff: &Backoff,
+    token: &str,
+    (from_block, to_block): (u64, u64),
+) -> Result<()> {
+    let total_blocks = to_block - from_block + 1;
+    let mut transfers = 0;
+    let resume_from = match db::start_backfill(&db::lock(conn), token, from_block, to_block) {
+        Ok(block) => block,
+        Err(e) => {
+            warn!("Could not record backfill start for {}: {:?}", token, e);
+            from_block
+        }
+    };
+    if resume_from > to_block {
+        info!("Backfill {}: already scanned through {} before the restart", token, to_block);
+        return Ok(());
+    }
+    if resume_from > from_block {
+        info!("Backfill {}: resuming at block {} after an interrupted run", token, resume_from);
+    }
+
+    let ranges = chunks(resume_from, to_block, cfg.backfill_chunk_blocks)
+        .map(|(start, end)| (token.to_string(), start, end))
+        .collect();
+    let mut fetched = spawn_fetcher(cfg, rpc, conn, ranges, 1);
+
+    while let Some(next) = fetched.recv().await {
+        let (chunk_start, chunk_end) = (next.from_block, next.to_block);
+        match write_range(cfg, conn, events, netflows, next) {
+            Ok(processed_count) => transfers += processed_count,
+            Err(RangeError::Write(e)) if e.is_transient() => {
+                warn!("Backfill write failed for {}: {}", token, e);
+                queue_failed_range(conn, errors, token, (chunk_start, chunk_end), &e, backoff.delay(0));
+            }
+            Err(RangeError::Write(e)) => return Err(e),
+            Err(RangeError::Fetch(e)) => {
+                warn!("Backfill failed for {} ({} → {}): {:?}", token, chunk_start, chunk_end, e);
+                queue_failed_range(conn, errors, token, (chunk_start, chunk_end), &e, backoff.delay(0));
+            }
+        }
+
+        if let Err(e) = db::record_backfill_progress(&db::lock(conn), token, chunk_end) {
+            warn!("Could not record backfill progress for {}: {:?}", token, e);
+        }
+        let scanned = chunk_end - from_block + 1;
+        info!("Backfill {}: scanned {} of {} blocks, {:.0}%",
+            token, scanned, total_blocks, scanned as f64 * 100.0 / total_blocks as f64);
+    }
+
+    info!("Backfilled {} transfers for token {}", transfers, token);
+    Ok(())
+}
+
+/// First block of a MODE=backfill snapshot. A FROM_DATE is binary-searched
+/// over block timestamps below `to_block` once, then served from `block_dates`.
+async fn resolve_snapshot_start(
+    rpc: &rpc::RpcClient,
+    conn: &Mutex<Connection>,
+    chain_id: u64,
+    start: SnapshotStart,
+    to_block: u64,
+) -> Result<u64> {
+    let date = match start {
+        SnapshotStart::Block(block) => return Ok(block),
+        SnapshotStart::Date(date) => date,
+    };
+    let timestamp = date.timestamp();
+    if let Some(block) = db::cached_block_for_timestamp(&db::lock(conn), chain_id, timestamp)? {
+        info!("FROM_DATE {} → block {} (cached)", date.to_rfc3339(), block);
+        return Ok(block);
+    }
+
+    info!("Resolving FROM_DATE {} to a block number...", date.to_rfc3339());
+    let block = rpc
+        .first_block_at_or_after(timestamp.max(0) as u64, to_block)
+        .await?
+        .ok_or_else(|| IndexerError::Config(format!(
+            "FROM_DATE {} is after TO_BLOCK {}", date.to_rfc3339(), to_block
+        )))?;
+    db::cache_block_for_timestamp(&db::lock(conn), chain_id, timestamp, block)?;
+    info!("FROM_DATE {} → block {}", date.to_rfc3339(), block);
+    Ok(block)
+}
+
+/// Snapshot mode (MODE=backfill): scan exactly `[from_block, to_block]` in
+/// `BACKFILL_BLOCKS`-sized chunks and return. A chunk that still fails after
+/// the retries aborts the job rather than leaving a silent gap.
+#[instrument(skip_all, fields(from_block, to_block))]
+async fn snapshot(
+    cfg: &Config,
+    rpc: &rpc::RpcClient,
+    conn: &Arc<Mutex<Connection>>,
+    events: &broadcast::Sender<models::Transfer>,
+    netflows: &NetflowRefresh,
+    from_block: u64,
+    to_block: u64,
+) -> Result<()> {
+    const ATTEMPTS: u32 = 5;
+    let chunk = cfg.backfill_blocks.max(1);
+    let total_blocks = to_block - from_block + 1;
+    let mut total = 0;
+
+    info!("Snapshot: scanning {} → {} in chunks of {} blocks", from_block, to_block, chunk);
+
+    // chunk by chunk, every token within a chunk
+    let tokens = cfg.token_set.len();
+    let ranges = chunks(from_block, to_block, chunk)
+        .flat_map(|(start, end)| cfg.token_set.iter().map(move |token| (token.clone(), start, end)))
+        .collect();
+    let mut fetched = spawn_fetcher(cfg, rpc, conn, ranges, ATTEMPTS);
+
+    let mut done = 0;
+    while let Some(next) = fetched.recv().await {
+        let (token, chunk_start, chunk_end) = (next.token.clone(), next.from_block, next.to_block);
+        let n = match write_range(cfg, conn, events, netflows, next) {
+            Ok(written) => written,
+            Err(RangeError::Write(e)) => return Err(e),
+            Err(RangeError::Fetch(e)) => {
+                error!("Snapshot fetch failed for {} ({} → {}), giving up", token, chunk_start, chunk_end);
+                return Err(e);
+            }
+        };
+        total += n;
+        info!("Snapshot {} → {}: {} transfers for {}", chunk_start, chunk_end, n, token);
+
+        done += 1;
+        if done % tokens == 0 {
+            let scanned = chunk_end - from_block + 1;
+            info!("Snapshot: scanned {} of {} blocks, {:.0}%",
+                scanned, total_blocks, scanned as f64 * 100.0 / total_blocks as f64);
+        }
+    }
+
+    if netflows.is_dirty() && !netflows.refresh(conn) {
+        warn!("Snapshot written but netflows are stale: the final refresh failed");
+    }
+    info!("Snapshot complete: {} → {}, {} transfers", from_block, to_block, total);
+    Ok(())
+}
+
+pub async fn run(
+    mut cfg: Config,
+    conn: Arc<Mutex<Connection>>,
+    events: broadcast::Sender<models::Transfer>,
+    breaker: rpc::CircuitBreaker,
+    cache: NetflowCache,
+    counts: TransferCounts,
+    errors: RecentErrors,
+) -> Result<()> {
+    let backfill = cfg.backfill_blocks;      // blocks to scan on startup
+    // blocks to scan per loop; the extra rescan_depth re-reads confirmed blocks
+    // so the ON CONFLICT upsert corrects late changes, and update_netflows
+    // recomputes from all rows so updated amounts are picked up too
+    let lookback = cfg.lookback_blocks.saturating_add(cfg.rescan_depth);
+    let poll_interval = Duration::from_secs(cfg.poll_interval_secs);
+    let retry_cap = Duration::from_secs(cfg.retry_cap_secs);
+    let backoff = Backoff::new(poll_interval, retry_cap);
+    let mut failures: u32 = 0;               // consecutive RPC failures
+    let exchange_reload = Duration::from_secs(cfg.exchange_reload_secs);
+    let mut exchanges_read_at = Instant::now(); // at startup, by config::load
+
+    let rpc = rpc::RpcClient::with_http_options(&cfg.rpc_http_url, &cfg.rpc_http_options())?
+        .with_backoff(Backoff::new(Duration::from_millis(cfg.retry_base_ms), retry_cap))
+        .with_batch_size(cfg.rpc_batch_size)
+        .with_breaker(breaker);
+    let netflows = NetflowRefresh::new(&cfg, cache, counts, errors.clone());
+
+    verify_chain_id(&rpc, &cfg, &backoff).await?;
+
+    // One-off historical range: no startup backfill, no live loop
+    if let Some((from, to_block)) = cfg.backfill_range {
+        let from_block = resolve_snapshot_start(&rpc, &conn, cfg.chain_id, from, to_block).await?;
+        return snapshot(&cfg, &rpc, &conn, &events, &netflows, from_block, to_block).await;
+    }
+
+    info!("Indexer started with lookback = {} blocks (rescan depth {})",
+        cfg.lookback_blocks, cfg.rescan_depth);
+
+    // ---------------------------
+    // One-time backfill at startup
+    // ---------------------------
+    match chain_head(&rpc, &cfg).await.map(|head| token_ranges(&cfg, head, backfill)) {
+        Ok(ranges) if ranges.is_empty() => {
+            info!("Backfill: confirmed head below START_BLOCK {}, skipping", cfg.start_block);
+        }
+        Ok(ranges) => {
+            failures = 0; // reset after success
+            for (token, start_block, target_block) in &ranges {
+                info!("Backfill {}: scanning {} → {} in chunks of {} blocks",
+                    token, start_block, target_block, cfg.backfill_chunk_blocks.max(1));
+                backfill_token(&cfg, &rpc, &conn, &events, &netflows, &errors, &backoff, token, (*start_block, *target_block)).await?;
             }
         }
         Err(e) => {
             warn!("Failed to get latest block for backfill: {:?}", e);
-            retry_delay = (retry_delay * 2).min(120);
+            errors.push(format!("backfill: chain head unavailable: {}", e));
+            failures += 1;
         }
     }
 
@@ -91,89 +916,700 @@ pub async fn run(cfg: Config, conn: Arc<Mutex<Connection>>) -> Result<()> {
     // Continuous live indexing
     // ---------------------------
     loop {
+        if cfg.exchange_addresses_file.is_some() && !exchange_reload.is_zero() && exchanges_read_at.elapsed() >= exchange_reload {
+            reload_exchanges(&mut cfg, &conn, &netflows, &errors);
+            exchanges_read_at = Instant::now();
+        }
+
+        // a refresh that failed last round, before anything else is written
+        if netflows.is_dirty() {
+            info!("Retrying netflow refresh");
+            netflows.refresh(&conn);
+        }
+
         info!("Checking latest block...");
 
-        match rpc::get_block_number(&cfg.rpc_http_url).await {
+        match chain_head(&rpc, &cfg).await {
             Ok(latest_block) => {
-                retry_delay = 10;
-                let target_block = latest_block.saturating_sub(cfg.confirmations);
+                let ranges = token_ranges(&cfg, latest_block, lookback);
+                let Some(target_block) = ranges.iter().map(|&(_, _, to)| to).max() else {
+                    failures = 0;
+                    info!("Live: block {} not yet confirmed past START_BLOCK {}, waiting",
+                        latest_block, cfg.start_block);
+                    sleep(poll_interval).await;
+                    continue;
+                };
                 info!("Live: Polygon block {} (up to {})", latest_block, target_block);
 
-                let mut total_transfers = 0;
+                // gaps from earlier failures first, then the new blocks
+                retry_failed_ranges(&cfg, &rpc, &conn, &events, &netflows, &errors, &backoff).await?;
 
-                for token in &cfg.token_set {
-                    match rpc::get_transfer_logs(
-                        &cfg.rpc_http_url,
-                        token,
-                        target_block.saturating_sub(lookback),
-                        target_block,
-                    ).await {
-                        Ok(logs) => {
-                            let mut processed_count = 0;
-                            let mut db = conn.lock().unwrap();
-
-                            let tx: Transaction = db.transaction()?;
-                            for log in logs {
-                                if let Some(transfer) = parser::decode_transfer(&log) {
-                                    let amount = Decimal::from_u128(transfer.value_u128)
-                                        .unwrap_or(Decimal::ZERO)
-                                        / Decimal::from(10u64.pow(18));
-
-                                    let direction = if cfg.exchange_set.contains(&transfer.to) {
-                                        info!("Inflow {} POL → {:?} (block {})",
-                                            amount, transfer.to, transfer.block_number);
-                                        Some("IN")
-                                    } else if cfg.exchange_set.contains(&transfer.from) {
-                                        info!("Outflow {} POL ← {:?} (block {})",
-                                            amount, transfer.from, transfer.block_number);
-                                        Some("OUT")
-                                    } else {
-                                        None
-                                    };
-
-                                    if let Some(dir) = direction {
-                                        if let Err(e) = db::record_transfer(
-                                            &tx,
-                                            transfer.block_number as i64,
-                                            &transfer.tx_hash,
-                                            transfer.log_index as i64,
-                                            token,
-                                            &transfer.from.to_string(),
-                                            &transfer.to.to_string(),
-                                            amount,
-                                            dir,
-                                        ) {
-                                            error!("Insert failed: {:?}", e);
-                                        } else {
-                                            processed_count += 1;
-                                            total_transfers += 1;
-                                        }
-                                    }
-                                }
-                            }
-                            tx.commit()?; // commit writes
-
-                            if let Err(e) = aggregator::update_netflows(&mut db) {
-                                error!("Aggregator failed: {:?}", e);
-                            }
+                let mut total_transfers = 0;
+                let mut rpc_failed = false;
+                let mut circuit_wait = None; // breaker opened mid-round
 
+                let mut fetched = spawn_fetcher(&cfg, &rpc, &conn, ranges, 1);
+                while let Some(next) = fetched.recv().await {
+                    let (token, range) = (next.token.clone(), (next.from_block, next.to_block));
+                    match write_range(&cfg, &conn, &events, &netflows, next) {
+                        Ok(processed_count) => {
+                            total_transfers += processed_count;
                             info!("Indexed block {} for {} → {} transfers",
-                                target_block, token, processed_count);
+                                range.1, token, processed_count);
+                        }
+                        // DB contention: queue the range so it's retried even
+                        // if the head moves past the lookback window
+                        Err(RangeError::Write(e)) if e.is_transient() => {
+                            warn!("Write failed for {}: {}", token, e);
+                            queue_live_range(&conn, &errors, &token, range, &e, backoff.delay(0));
+                        }
+                        Err(RangeError::Write(e)) => return Err(e),
+                        Err(RangeError::Fetch(e)) => {
+                            warn!("Fetch logs failed for {}: {:?}", token, e);
+                            rpc_failed |= matches!(e, IndexerError::Rpc(_));
+                            if let IndexerError::CircuitOpen(wait) = e {
+                                circuit_wait = Some(wait);
+                            }
+                            queue_live_range(&conn, &errors, &token, range, &e, backoff.delay(0));
                         }
-                        Err(e) => warn!("Fetch logs failed for {}: {:?}", token, e),
                     }
-
-                    sleep(rpc_pause).await;
                 }
 
                 info!("Completed block {} → {} transfers", target_block, total_transfers);
+
+                // provider errors on getLogs back off like a failed blockNumber;
+                // undecodable responses and DB contention keep the normal cadence
+                failures = if rpc_failed { failures + 1 } else { 0 };
+
+                if let Some(wait) = circuit_wait {
+                    info!("RPC circuit open, pausing {:?}", wait);
+                    sleep(wait).await;
+                    continue;
+                }
+            }
+            // provider known to be down: wait out the cooldown instead of polling
+            Err(IndexerError::CircuitOpen(wait)) => {
+                info!("RPC circuit open, pausing {:?}", wait);
+                sleep(wait).await;
+                continue;
             }
             Err(e) => {
                 warn!("RPC failed this round: {:?}", e);
-                retry_delay = (retry_delay * 2).min(120);
+                errors.push(format!("chain head unavailable: {}", e));
+                failures += 1;
             }
         }
 
-        sleep(Duration::from_secs(retry_delay)).await;
+        // jittered exponential backoff while the RPC is failing
+        let delay = if failures == 0 { poll_interval } else { backoff.delay(failures) };
+        sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_exchange_set, reload_exchanges, backfill_token, chunks, index_logs, process_logs, resolve_snapshot_start, sampled, scale_amount, scan_range, spawn_fetcher, token_decimals, token_ranges, verify_chain_id, write_logs, write_range,
+        IndexerError, NetflowCache, NetflowRefresh, RecentErrors, TransferCounts, PIPELINE_DEPTH,
+    };
+    use crate::config::SnapshotStart;
+    use crate::{db, rpc};
+    use alloy::primitives::U256;
+    use rust_decimal::Decimal;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn scale_amount_uses_token_decimals() {
+        let raw = U256::from(1_500_000u64);
+        assert_eq!(scale_amount(raw, 6), Some(Decimal::from_str("1.5").unwrap()));
+        assert_eq!(scale_amount(raw, 0), Some(Decimal::from(1_500_000)));
+        assert_eq!(
+            scale_amount(U256::from(1_500_000_000_000_000_000u128), 18),
+            Some(Decimal::from_str("1.5").unwrap())
+        );
+        assert_eq!(scale_amount(raw, 29), None);
+    }
+
+    #[test]
+    fn scan_range_waits_when_head_is_below_confirmations() {
+        assert_eq!(scan_range(1, 2, 5000, 0), None);
+    }
+
+    #[test]
+    fn token_ranges_use_each_tokens_confirmations() {
+        let mut cfg = test_config();
+        cfg.token_set = ["0xaaa", "0xbbb", "0xccc"].map(String::from).into();
+        cfg.start_block = 0;
+        cfg.confirmations = 2;
+        cfg.token_confirmations = [("0xaaa".to_string(), 10), ("0xccc".to_string(), 0)].into();
+        let range = |t: &str, from, to| (t.to_string(), from, to);
+
+        // 0xbbb isn't listed, so it gets the default
+        assert_eq!(token_ranges(&cfg, 1000, 100), [
+            range("0xaaa", 890, 990),
+            range("0xbbb", 898, 998),
+            range("0xccc", 900, 1000),
+        ]);
+
+        // a token whose confirmed head is still below START_BLOCK waits alone
+        cfg.start_block = 995;
+        assert_eq!(token_ranges(&cfg, 1000, 100), [range("0xbbb", 995, 998), range("0xccc", 995, 1000)]);
+
+        // BLOCK_TAG's block is already final: no token subtracts anything
+        cfg.start_block = 0;
+        cfg.block_tag = Some(rpc::BlockTag::Finalized);
+        assert!(token_ranges(&cfg, 1000, 100).iter().all(|&(_, _, to)| to == 1000));
+    }
+
+    #[test]
+    fn scan_range_waits_when_head_is_below_start_block() {
+        assert_eq!(scan_range(100, 2, 5000, 500), None);
+    }
+
+    #[test]
+    fn scan_range_clamps_to_start_block() {
+        assert_eq!(scan_range(100, 2, 5000, 0), Some((0, 98)));
+        assert_eq!(scan_range(100, 2, 5000, 50), Some((50, 98)));
+        assert_eq!(scan_range(10_000, 2, 100, 50), Some((9_898, 9_998)));
+    }
+
+    #[test]
+    fn chunks_cover_the_range_without_overlap() {
+        assert_eq!(chunks(100, 350, 100).collect::<Vec<_>>(), [(100, 199), (200, 299), (300, 350)]);
+        assert_eq!(chunks(5, 5, 1000).collect::<Vec<_>>(), [(5, 5)]);
+        assert_eq!(chunks(u64::MAX - 1, u64::MAX, 1).collect::<Vec<_>>(), [(u64::MAX - 1, u64::MAX - 1), (u64::MAX, u64::MAX)]);
+        assert_eq!(chunks(10, 9, 100).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn from_date_is_served_from_the_cache() {
+        let conn = Mutex::new(db::open_in_memory());
+        let date = chrono::DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z").unwrap().to_utc();
+        db::cache_block_for_timestamp(&conn.lock().unwrap(), 137, date.timestamp(), 54_000_000).unwrap();
+
+        // nothing listens here: any RPC call would fail
+        let rpc = rpc::RpcClient::new("http://127.0.0.1:9").unwrap();
+        let block = resolve_snapshot_start(&rpc, &conn, 137, SnapshotStart::Date(date), 60_000_000).await;
+        assert_eq!(block.unwrap(), 54_000_000);
+        assert!(resolve_snapshot_start(&rpc, &conn, 1, SnapshotStart::Date(date), 60_000_000).await.is_err());
+        assert_eq!(resolve_snapshot_start(&rpc, &conn, 1, SnapshotStart::Block(7), 60_000_000).await.unwrap(), 7);
+    }
+
+    const TOKEN: &str = "0x0000000000000000000000000000000000001010";
+    const EXCHANGE: &str = "0xe7804c37c13166fF0b37F5aE0BB07A3aEbb6e245";
+
+    /// Config with `EXCHANGE` as the only exchange wallet
+    fn test_config() -> crate::config::Config {
+        let mut cfg = crate::config::Config::defaults();
+        cfg.exchange_set = [EXCHANGE.parse().unwrap()].into_iter().collect();
+        cfg
+    }
+
+    /// Answer every batch of eth_getBlockByNumber calls (the fetcher's block
+    /// time lookup) with block `n` at `1_700_000_000 + 2n`
+    async fn mount_block_times(server: &wiremock::MockServer) {
+        struct BlockTimes;
+        impl wiremock::Respond for BlockTimes {
+            fn respond(&self, request: &wiremock::Request) -> wiremock::ResponseTemplate {
+                let calls: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+                let results: Vec<_> = calls
+                    .iter()
+                    .map(|call| {
+                        let block = u64::from_str_radix(call["params"][0].as_str().unwrap().trim_start_matches("0x"), 16).unwrap();
+                        let timestamp = format!("0x{:x}", 1_700_000_000 + 2 * block);
+                        serde_json::json!({ "jsonrpc": "2.0", "id": call["id"], "result": { "timestamp": timestamp } })
+                    })
+                    .collect();
+                wiremock::ResponseTemplate::new(200).set_body_json(results)
+            }
+        }
+        let is_batch = |request: &wiremock::Request| request.body.first() == Some(&b'[');
+        wiremock::Mock::given(wiremock::matchers::method("POST")).and(is_batch).respond_with(BlockTimes).mount(server).await;
+    }
+
+    /// `n` inflows of 1 token (18 decimals) into `EXCHANGE`, ten per block
+    fn inflow_logs(n: u64) -> Vec<rpc::Log> {
+        (0..n)
+            .map(|i| rpc::Log {
+                address: TOKEN.to_string(),
+                topics: vec![
+                    rpc::TRANSFER_TOPIC.to_string(),
+                    format!("0x{:064x}", 0xabcu64),
+                    format!("0x{:0>64}", EXCHANGE.trim_start_matches("0x")),
+                ],
+                data: format!("0x{:064x}", 1_000_000_000_000_000_000u128),
+                block_number_hex: format!("0x{:x}", 1_000 + i / 10),
+                tx_hash: format!("0x{:064x}", i),
+                log_index_hex: format!("0x{:x}", i % 10),
+                removed: false,
+                block_timestamp: Some(1_700_000_000 + i / 10 * 2),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn large_writes_commit_in_max_batch_rows_transactions() {
+        let mut cfg = test_config();
+        cfg.max_batch_rows = 10_000;
+
+        let conn = Mutex::new(db::open_in_memory());
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        assert_eq!(write_logs(&cfg, &conn, &events, TOKEN, 18.into(), inflow_logs(25_000)).unwrap(), (25_000, 3));
+
+        // a run that ends on a batch boundary doesn't commit an empty extra one
+        cfg.max_batch_rows = 12_500;
+        assert_eq!(write_logs(&cfg, &conn, &events, TOKEN, 18.into(), inflow_logs(25_000)).unwrap(), (25_000, 2));
+
+        // the re-write upserted the same rows, and the split batches add up to one net
+        let db = conn.lock().unwrap();
+        let rows: i64 = db.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0)).unwrap();
+        assert_eq!(rows, 25_000);
+        let netflows = crate::aggregator::update_netflows(&db, crate::config::NetflowUnits::Token).unwrap();
+        assert_eq!(netflows, [(TOKEN.to_string(), Decimal::from(25_000))]);
+    }
+
+    #[test]
+    fn process_logs_classifies_without_a_network() {
+        let mut cfg = test_config();
+        cfg.min_transfer_amount = Decimal::from_str("0.5").unwrap();
+        let exchange = format!("0x{:0>64}", EXCHANGE.trim_start_matches("0x"));
+        let other = format!("0x{:064x}", 0xdefu64);
+
+        let mut logs = inflow_logs(5);
+        logs[1].topics[1] = exchange.clone(); // exchange → other: OUT
+        logs[1].topics[2] = other.clone();
+        logs[2].topics[2] = other.clone(); // no exchange on either side
+        logs[3].data = format!("0x{:064x}", 100_000_000_000_000_000u128); // 0.1: dust
+        logs[4].topics[0] = rpc::APPROVAL_TOPIC.to_string();
+
+        let mut conn = db::open_in_memory();
+        let tx = conn.transaction().unwrap();
+        let written = process_logs(&tx, &cfg, TOKEN, 18.into(), logs.clone()).unwrap();
+        let directions: Vec<_> = written.iter().map(|t| (t.tx_hash.as_str(), t.direction.as_str())).collect();
+        assert_eq!(directions, [(logs[0].tx_hash.as_str(), "IN"), (logs[1].tx_hash.as_str(), "OUT")]);
+        let events: i64 = tx.query_row("SELECT COUNT(*) FROM events", [], |r| r.get(0)).unwrap();
+        assert_eq!(events, 1);
+
+        // INDEX_MODE=all keeps the exchange-free transfer as NEUTRAL; dust is still dropped
+        cfg.index_mode = crate::config::IndexMode::All;
+        let written = process_logs(&tx, &cfg, TOKEN, 18.into(), logs).unwrap();
+        assert_eq!(written.iter().map(|t| t.direction.as_str()).collect::<Vec<_>>(), ["IN", "OUT", "NEUTRAL"]);
+        tx.commit().unwrap();
+        let rows: i64 = conn.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0)).unwrap();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn sampling_thins_neutral_transfers_only() {
+        let mut cfg = test_config();
+        cfg.index_mode = crate::config::IndexMode::All;
+        cfg.sample_rates.insert(TOKEN.to_lowercase(), 0.1);
+        let other = format!("0x{:064x}", 0xdefu64);
+
+        let mut logs = inflow_logs(1000);
+        for (i, log) in logs.iter_mut().enumerate() {
+            log.tx_hash = alloy::primitives::keccak256(i.to_be_bytes()).to_string();
+            if i % 2 == 1 {
+                log.topics[2] = other.clone(); // no exchange on either side
+            }
+        }
+
+        let mut conn = db::open_in_memory();
+        let tx = conn.transaction().unwrap();
+        let written = process_logs(&tx, &cfg, TOKEN, 18.into(), logs.clone()).unwrap();
+        let inflows = written.iter().filter(|t| t.direction == "IN").count();
+        let neutral = written.iter().filter(|t| t.direction == "NEUTRAL").count();
+        assert_eq!(inflows, 500);
+        assert!((30..=70).contains(&neutral), "{neutral} of 500 sampled");
+
+        // deterministic: the same transactions are kept on a re-run
+        let kept: Vec<_> = written.iter().filter(|t| t.direction == "NEUTRAL").map(|t| t.tx_hash.clone()).collect();
+        let expected: Vec<_> = logs.iter().skip(1).step_by(2).map(|l| l.tx_hash.clone()).filter(|h| sampled(h, 0.1)).collect();
+        assert_eq!(kept, expected);
+
+        assert!(sampled("0xnot-a-hash", 0.1));
+        assert!(sampled(&format!("0x{}", "f".repeat(64)), 1.0));
+        assert!(!sampled(&format!("0x{}", "f".repeat(64)), 0.99));
+    }
+
+    #[test]
+    fn removed_logs_delete_their_transfers() {
+        let cfg = test_config();
+        let conn = Mutex::new(db::open_in_memory());
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let counts = TransferCounts::default();
+        let netflows = NetflowRefresh::new(&cfg, NetflowCache::default(), counts.clone(), RecentErrors::default());
+        assert_eq!(index_logs(&cfg, &conn, &events, &netflows, TOKEN, 18.into(), inflow_logs(3)).unwrap(), 3);
+        let before_reorg = counts.version(TOKEN);
+
+        // the node re-sends log 1 with `removed: true` after a reorg
+        let mut fixture = serde_json::json!({
+            "address": TOKEN,
+            "topics": inflow_logs(2)[1].topics,
+            "data": inflow_logs(2)[1].data,
+            "blockNumber": "0x3e8",
+            "transactionHash": format!("0x{:064x}", 1),
+            "logIndex": "0x1",
+            "removed": true,
+        });
+        let removed: rpc::Log = serde_json::from_value(fixture.clone()).unwrap();
+        assert!(removed.removed);
+        fixture.as_object_mut().unwrap().remove("removed");
+        assert!(!serde_json::from_value::<rpc::Log>(fixture).unwrap().removed);
+
+        assert_eq!(index_logs(&cfg, &conn, &events, &netflows, TOKEN, 18.into(), vec![removed]).unwrap(), 0);
+        // the delete invalidates the token's cached /transfers/count results
+        assert_ne!(counts.version(TOKEN), before_reorg);
+        let db = conn.lock().unwrap();
+        let left: Vec<i64> = db
+            .prepare("SELECT log_index FROM transfers ORDER BY log_index")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(left, vec![0, 2]);
+        let net: String = db
+            .query_row("SELECT cumulative_net FROM netflows WHERE token_address = ?1", [TOKEN], |r| r.get(0))
+            .unwrap();
+        assert_eq!(net, "2");
+    }
+
+    #[test]
+    fn failed_removed_log_delete_rolls_back_the_batch() {
+        let cfg = test_config();
+        let conn = Mutex::new(db::open_in_memory());
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let netflows = NetflowRefresh::new(&cfg, NetflowCache::default(), TransferCounts::default(), RecentErrors::default());
+        assert_eq!(index_logs(&cfg, &conn, &events, &netflows, TOKEN, 18.into(), inflow_logs(2)).unwrap(), 2);
+
+        // a new inflow and a removal of log 0 in one batch; the delete can't run
+        let mut removed = inflow_logs(1).remove(0);
+        removed.removed = true;
+        let fresh = inflow_logs(3).remove(2);
+        conn.lock().unwrap().execute_batch("DROP TABLE events").unwrap();
+        assert!(index_logs(&cfg, &conn, &events, &netflows, TOKEN, 18.into(), vec![fresh, removed]).is_err());
+
+        let db = conn.lock().unwrap();
+        let rows: i64 = db.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0)).unwrap();
+        assert_eq!(rows, 2);
+    }
+
+    #[test]
+    fn reloaded_exchange_file_reclassifies_stored_transfers() {
+        let path = std::env::temp_dir().join(format!("polygon-indexer-exchanges-{}.csv", std::process::id()));
+        let other = "0x0000000000000000000000000000000000000def";
+        std::fs::write(&path, format!("{},Hot wallet\n", EXCHANGE)).unwrap();
+        let mut cfg = test_config();
+        cfg.env_exchange_set = HashSet::new();
+        cfg.exchange_addresses_file = Some(path.to_string_lossy().into_owned());
+        // only the label is new: not a change, since stored labels are kept anyway
+        assert_eq!(cfg.reread_exchange_file().unwrap(), None);
+
+        let conn = Mutex::new(db::open_in_memory());
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let errors = RecentErrors::default();
+        let netflows = NetflowRefresh::new(&cfg, NetflowCache::default(), TransferCounts::default(), errors.clone());
+        apply_exchange_set(&conn, &cfg).unwrap();
+        assert_eq!(index_logs(&cfg, &conn, &events, &netflows, TOKEN, 18.into(), inflow_logs(3)).unwrap(), 3);
+
+        // the wallet moves to another address: its inflows are no longer exchange flows
+        std::fs::write(&path, format!("address,label\n{},Cold wallet\n", other)).unwrap();
+        reload_exchanges(&mut cfg, &conn, &netflows, &errors);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cfg.exchange_set, [other.parse().unwrap()].into());
+        let db = conn.lock().unwrap();
+        let stored: (String, String) = db.query_row("SELECT address, label FROM exchanges", [], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+        assert_eq!(stored, (other.parse::<alloy::primitives::Address>().unwrap().to_string(), "Cold wallet".to_string()));
+        let neutral: i64 = db.query_row("SELECT COUNT(*) FROM transfers WHERE direction = 'NEUTRAL'", [], |r| r.get(0)).unwrap();
+        assert_eq!(neutral, 3);
+        drop(db);
+
+        // a missing file keeps the current set and is reported
+        reload_exchanges(&mut cfg, &conn, &netflows, &errors);
+        assert_eq!(cfg.exchange_set.len(), 1);
+        assert!(errors.snapshot()[0].1.starts_with("exchange wallet reload failed"));
+    }
+
+    #[test]
+    fn failed_netflow_refresh_is_retried() {
+        let cfg = test_config();
+        let conn = Mutex::new(db::open_in_memory());
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let cache = NetflowCache::default();
+        let errors = RecentErrors::default();
+        let netflows = NetflowRefresh::new(&cfg, cache.clone(), TransferCounts::default(), errors.clone());
+        let cached = |conn: &Mutex<rusqlite::Connection>| -> Option<String> {
+            conn.lock()
+                .unwrap()
+                .query_row("SELECT cumulative_net FROM netflows WHERE token_address = ?1", [TOKEN], |r| r.get(0))
+                .ok()
+        };
+
+        // the write commits but the refresh can't reach netflows
+        conn.lock().unwrap().execute_batch("ALTER TABLE netflows RENAME TO netflows_moved").unwrap();
+        assert_eq!(index_logs(&cfg, &conn, &events, &netflows, TOKEN, 18.into(), inflow_logs(3)).unwrap(), 3);
+        assert!(netflows.is_dirty());
+
+        // still failing: stays dirty, and each failure is reported for /health
+        assert!(!netflows.refresh(&conn));
+        assert!(netflows.is_dirty());
+        let reported = errors.snapshot();
+        assert_eq!(reported.len(), 2);
+        assert!(reported[0].1.starts_with("netflow refresh failed: "), "{}", reported[0].1);
+
+        conn.lock().unwrap().execute_batch("ALTER TABLE netflows_moved RENAME TO netflows").unwrap();
+        assert_eq!(cached(&conn), None);
+        assert!(netflows.refresh(&conn));
+        assert!(!netflows.is_dirty());
+        assert_eq!(cached(&conn).as_deref(), Some("3"));
+        // the in-memory copy is reloaded in the same step
+        assert_eq!(cache.get(TOKEN).map(|nf| nf.cumulative_net), Some(Decimal::from(3)));
+    }
+
+    #[tokio::test]
+    async fn chain_id_is_verified_before_indexing() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": "0x89" })))
+            .mount(&server)
+            .await;
+        let rpc = rpc::RpcClient::new(&server.uri()).unwrap();
+        let backoff = crate::backoff::Backoff::new(Duration::from_millis(1), Duration::from_millis(1));
+
+        // an unreachable RPC is waited out rather than skipped
+        let mut cfg = test_config();
+        verify_chain_id(&rpc, &cfg, &backoff).await.unwrap();
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+        cfg.chain_id = 1;
+        assert!(matches!(verify_chain_id(&rpc, &cfg, &backoff).await, Err(IndexerError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn unknown_decimals_fall_back_to_default_decimals() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        let reverted = serde_json::json!({ "code": 3, "message": "execution reverted" });
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                { "jsonrpc": "2.0", "id": 0, "error": reverted },
+                { "jsonrpc": "2.0", "id": 1, "error": reverted },
+                { "jsonrpc": "2.0", "id": 2, "error": reverted },
+            ])))
+            .mount(&server)
+            .await;
+        let rpc = rpc::RpcClient::new(&server.uri()).unwrap();
+        let conn = Mutex::new(db::open_in_memory());
+        let token = "0x00000000000000000000000000000000000d0d0d";
+
+        let assumed = db::TokenDecimals { decimals: 6, assumed: true };
+        assert_eq!(token_decimals(&rpc, &conn, token, 6).await, assumed);
+        assert_eq!(db::token_decimals(&conn.lock().unwrap(), token).unwrap(), Some(assumed));
+        // the next round asks again, but keeps the stored scale whatever the fallback
+        assert_eq!(token_decimals(&rpc, &conn, token, 18).await, assumed);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+        let cfg = test_config();
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        write_logs(&cfg, &conn, &events, token, assumed, inflow_logs(1)).unwrap();
+        let flagged: i64 = conn.lock().unwrap()
+            .query_row("SELECT COUNT(*) FROM transfers WHERE decimals = 6 AND decimals_assumed = 1", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(flagged, 1);
+    }
+
+    #[tokio::test]
+    async fn interrupted_backfill_resumes_at_the_next_chunk() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": [] })))
+            .mount(&server)
+            .await;
+
+        let mut cfg = test_config();
+        cfg.rpc_pause_ms = 0;
+        cfg.backfill_chunk_blocks = 100;
+        let conn = Arc::new(Mutex::new(db::open_in_memory()));
+        db::upsert_token(&conn.lock().unwrap(), TOKEN, None, None, 18).unwrap();
+        // a backfill of five chunks that crashed after writing the second
+        {
+            let db = conn.lock().unwrap();
+            assert_eq!(db::start_backfill(&db, TOKEN, 1000, 1499).unwrap(), 1000);
+            db::record_backfill_progress(&db, TOKEN, 1099).unwrap();
+            db::record_backfill_progress(&db, TOKEN, 1199).unwrap();
+        }
+
+        let rpc = rpc::RpcClient::new(&server.uri()).unwrap();
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let backoff = crate::backoff::Backoff::new(Duration::from_millis(1), Duration::from_millis(1));
+        let netflows = NetflowRefresh::new(&cfg, NetflowCache::default(), TransferCounts::default(), RecentErrors::default());
+        backfill_token(&cfg, &rpc, &conn, &events, &netflows, &RecentErrors::default(), &backoff, TOKEN, (1000, 1499))
+            .await
+            .unwrap();
+
+        // only chunks 3–5 are fetched
+        let starts: Vec<String> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| r.body_json::<serde_json::Value>().unwrap()["params"][0]["fromBlock"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(starts, [1200, 1300, 1400].map(|b: u64| format!("0x{b:x}")));
+
+        let status = db::backfill_status(&conn.lock().unwrap()).unwrap();
+        assert_eq!((status[0].from_block, status[0].scanned_blocks, status[0].complete), (Some(1000), 500, true));
+
+        // a completed backfill starts over on the next start
+        assert_eq!(db::start_backfill(&conn.lock().unwrap(), TOKEN, 1000, 1499).unwrap(), 1000);
+    }
+
+    #[tokio::test]
+    async fn fetcher_runs_ahead_of_the_writer_up_to_the_pipeline_depth() {
+        use wiremock::{matchers::method, Mock, MockServer, Request, Respond, ResponseTemplate};
+
+        /// Empty eth_getLogs result, reporting each request as it's answered
+        struct Signal(tokio::sync::mpsc::UnboundedSender<()>);
+        impl Respond for Signal {
+            fn respond(&self, _: &Request) -> ResponseTemplate {
+                let _ = self.0.send(());
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": [] }))
+            }
+        }
+
+        let server = MockServer::start().await;
+        let (signal, mut requests) = tokio::sync::mpsc::unbounded_channel();
+        Mock::given(method("POST")).respond_with(Signal(signal)).mount(&server).await;
+
+        let mut cfg = test_config();
+        cfg.rpc_pause_ms = 0;
+        let conn = Arc::new(Mutex::new(db::open_in_memory()));
+        // decimals already known, so every request is an eth_getLogs
+        db::upsert_token(&conn.lock().unwrap(), TOKEN, None, None, 18).unwrap();
+        let rpc = rpc::RpcClient::new(&server.uri()).unwrap();
+        let ranges = (0..10).map(|i| (TOKEN.to_string(), i * 100, i * 100 + 99)).collect();
+        let mut fetched = spawn_fetcher(&cfg, &rpc, &conn, ranges, 1);
+
+        // with no writer draining it: PIPELINE_DEPTH ranges queued plus one
+        // waiting to be sent. Range k is only fetched once range k - 1 is in
+        // the channel, so no further request can have been answered yet.
+        for _ in 0..=PIPELINE_DEPTH {
+            requests.recv().await.unwrap();
+        }
+        assert!(requests.try_recv().is_err());
+
+        // each range taken frees a slot for one more fetch; everything arrives in order
+        let mut starts = Vec::new();
+        let mut answered = PIPELINE_DEPTH + 1;
+        while let Some(next) = fetched.recv().await {
+            assert!(next.logs.unwrap().0.is_empty());
+            starts.push(next.from_block);
+            while requests.try_recv().is_ok() {
+                answered += 1;
+            }
+            assert!(answered <= starts.len() + PIPELINE_DEPTH + 1, "{answered} fetched after {} taken", starts.len());
+        }
+        assert_eq!(starts, (0..10).map(|i| i * 100).collect::<Vec<_>>());
+        assert_eq!(server.received_requests().await.unwrap().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn exchange_topic_filter_asks_only_for_exchange_transfers() {
+        use wiremock::{matchers::{body_partial_json, method}, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        let exchange = format!("0x{:0>64}", EXCHANGE.trim_start_matches("0x").to_lowercase());
+        let logs = inflow_logs(1);
+        let log = serde_json::json!({
+            "address": TOKEN,
+            "topics": logs[0].topics,
+            "data": logs[0].data,
+            "blockNumber": logs[0].block_number_hex,
+            "transactionHash": logs[0].tx_hash,
+            "logIndex": logs[0].log_index_hex,
+        });
+        for (topics, result) in [
+            (serde_json::json!([[rpc::TRANSFER_TOPIC], [exchange]]), serde_json::json!([])),
+            (serde_json::json!([[rpc::TRANSFER_TOPIC], null, [exchange]]), serde_json::json!([log])),
+        ] {
+            Mock::given(method("POST"))
+                .and(body_partial_json(serde_json::json!({ "method": "eth_getLogs", "params": [{ "topics": topics }] })))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": result })))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        mount_block_times(&server).await;
+
+        let mut cfg = test_config();
+        cfg.exchange_topic_filter = true;
+        assert_eq!(cfg.exchange_topics(), Some(vec![exchange.clone()]));
+        let conn = Arc::new(Mutex::new(db::open_in_memory()));
+        db::upsert_token(&conn.lock().unwrap(), TOKEN, None, None, 18).unwrap();
+        let rpc = rpc::RpcClient::new(&server.uri()).unwrap();
+        let mut fetched = spawn_fetcher(&cfg, &rpc, &conn, vec![(TOKEN.to_string(), 1_000, 1_099)], 1);
+        let (logs, _) = fetched.recv().await.unwrap().logs.unwrap();
+        assert_eq!(logs.iter().map(|l| l.tx_hash.as_str()).collect::<Vec<_>>(), [log["transactionHash"].as_str().unwrap()]);
+
+        // INDEX_MODE=all needs every transfer, so the filter is ignored
+        cfg.index_mode = crate::config::IndexMode::All;
+        assert_eq!(cfg.exchange_topics(), None);
+    }
+
+    #[tokio::test]
+    async fn logs_without_an_index_are_indexed_at_their_position() {
+        use wiremock::{matchers::{body_partial_json, method}, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        // the node reports log 0 of block 1000 but leaves out the next two indexes
+        let logs = inflow_logs(1);
+        let log = |tx: u64, index: Option<&str>| serde_json::json!({
+            "address": TOKEN,
+            "topics": logs[0].topics,
+            "data": logs[0].data,
+            "blockNumber": "0x3e8",
+            "transactionHash": format!("0x{:064x}", tx),
+            "logIndex": index,
+        });
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({ "method": "eth_getLogs" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0", "id": 1, "result": [log(1, Some("0x0")), log(2, None), log(3, None)]
+            })))
+            .mount(&server)
+            .await;
+        mount_block_times(&server).await;
+
+        let cfg = test_config();
+        let conn = Arc::new(Mutex::new(db::open_in_memory()));
+        db::upsert_token(&conn.lock().unwrap(), TOKEN, None, None, 18).unwrap();
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let netflows = NetflowRefresh::new(&cfg, NetflowCache::default(), TransferCounts::default(), RecentErrors::default());
+        let rpc = rpc::RpcClient::new(&server.uri()).unwrap();
+        let mut fetched = spawn_fetcher(&cfg, &rpc, &conn, vec![(TOKEN.to_string(), 1_000, 1_000)], 1);
+        assert_eq!(write_range(&cfg, &conn, &events, &netflows, fetched.recv().await.unwrap()).unwrap(), 3);
+
+        // stamped with block 1000's time, not the time it was indexed
+        let rows: Vec<(String, i64, String)> = conn
+            .lock()
+            .unwrap()
+            .prepare("SELECT tx_hash, log_index, timestamp FROM transfers ORDER BY log_index")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let expected: Vec<_> = (0..3).map(|i| (format!("0x{:064x}", i + 1), i, "2023-11-14 22:46:40".to_string())).collect();
+        assert_eq!(rows, expected);
     }
-}
\ No newline at end of file
+}
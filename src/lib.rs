@@ -0,0 +1,17 @@
+// src/lib.rs
+// Shared modules for the indexer binary and the operational tools in src/bin
+pub mod config;
+pub mod db;
+pub mod api;
+pub mod indexer;
+pub mod models;
+pub mod aggregator;
+pub mod rpc;
+pub mod parser;
+pub mod check;
+pub mod backoff;
+pub mod error;
+pub mod retention;
+pub mod alerts;
+pub mod build_info;
+pub mod cache;
@@ -1,18 +1,17 @@
-mod config;
-mod db;
-mod api;
-mod indexer;
-mod models;
-mod aggregator;
-mod rpc;
-mod parser;
-
+use polygon_indexer::{aggregator, api, build_info, cache, check, config, db, error, indexer, models, retention};
+use rusqlite::Connection;
 use std::sync::{Arc, Mutex};
-use tokio::signal;
-use tracing::{error, info};
+use std::time::Duration;
+use tokio::{signal, sync::broadcast};
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
+    if std::env::args().any(|a| a == "--version" || a == "-V") {
+        println!("{}", build_info::describe());
+        return Ok(());
+    }
+
     // Force logging to stdout with DEBUG level for visibility
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)  // show everything (INFO, DEBUG, WARN, ERROR)
@@ -22,39 +21,215 @@ async fn main() -> eyre::Result<()> {
 
     println!("Logger initialized (DEBUG mode)");
 
-    info!("Polygon Indexer starting...");
+    info!("Polygon Indexer starting... ({})", build_info::describe());
 
     // Load configuration
     let cfg = config::load()?;
     info!("Loaded config:");
-    info!("  RPC URL: {}", cfg.rpc_http_url);
+    info!("  RPC URL: {}", config::redact_url(&cfg.rpc_http_url));
+    info!("  Chain id: {}", cfg.chain_id);
     info!("  DB Path: {}", cfg.db_path);
-    info!("  Port: {}", cfg.port);
-    info!("  Confirmations: {}", cfg.confirmations);
+    match &cfg.unix_socket {
+        Some(path) => info!("  Unix socket: {} (BIND_ADDR/PORT unused)", path),
+        None => {
+            info!("  Bind address: {}", cfg.bind_addr);
+            info!("  Port: {}", cfg.port);
+        }
+    }
+    info!("  API timeout: {}s, max concurrency: {}, max body: {} bytes",
+        cfg.api_timeout_secs, cfg.api_max_concurrency, cfg.api_max_body_bytes);
+    info!("  API cache max-age: {}s", cfg.api_cache_max_age_secs);
+    if cfg.api_keys.0.is_empty() {
+        info!("  API auth: disabled (set API_KEYS to require a bearer token)");
+        if cfg.unix_socket.is_none() && !cfg.bind_addr.is_loopback() {
+            warn!("API is bound to {} without API_KEYS: anyone who can reach it can query it", cfg.bind_addr);
+        }
+    } else {
+        info!("  API auth: bearer token required ({} key(s))", cfg.api_keys.0.len());
+    }
+    if !cfg.allowed_origins.is_empty() {
+        info!("  CORS origins: {:?}", cfg.allowed_origins);
+    } else if cfg.dev_mode {
+        warn!("  CORS: any origin (DEV_MODE); set ALLOWED_ORIGINS for production");
+    } else {
+        info!("  CORS: disabled (set ALLOWED_ORIGINS to allow browser dashboards)");
+    }
+    info!("  Request id header: {}", cfg.request_id_header);
+    match cfg.block_tag {
+        Some(tag) => info!("  Scan head: {} block (CONFIRMATIONS not used)", tag.as_str()),
+        None if cfg.token_confirmations.is_empty() => info!("  Confirmations: {}", cfg.confirmations),
+        None => info!("  Confirmations: {} (per token: {:?})", cfg.confirmations, cfg.token_confirmations),
+    }
+    info!("  Start block: {}", cfg.start_block);
+    info!("  Backfill blocks: {} (chunks of {})", cfg.backfill_blocks, cfg.backfill_chunk_blocks);
+    info!("  Max rows per write transaction: {}", cfg.max_batch_rows);
+    if let Some((from, to)) = cfg.backfill_range {
+        info!("  Snapshot range: {} → {} (MODE=backfill)", from, to);
+    }
+    info!("  Lookback blocks: {}", cfg.lookback_blocks);
+    info!("  Rescan depth: {}", cfg.rescan_depth);
+    info!("  RPC pause: {}ms", cfg.rpc_pause_ms);
+    info!("  RPC batch size: {}", cfg.rpc_batch_size);
+    if cfg.rpc_breaker_threshold > 0 {
+        info!("  RPC circuit breaker: open after {} failures, {}s cooldown",
+            cfg.rpc_breaker_threshold, cfg.rpc_breaker_cooldown_secs);
+    } else {
+        info!("  RPC circuit breaker: disabled");
+    }
+    info!("  RPC connections: {:?}", cfg.rpc_http_options());
+    info!("  Poll interval: {}s", cfg.poll_interval_secs);
+    info!("  Retry backoff: base {}ms, cap {}s (full jitter)", cfg.retry_base_ms, cfg.retry_cap_secs);
+    info!("  Min transfer amount: {}", cfg.min_transfer_amount);
+    info!("  Default decimals (when decimals() fails): {}", cfg.default_decimals);
+    info!("  Index mode: {:?}", cfg.index_mode);
+    info!("  Netflow units: {}", cfg.netflow_units.as_str());
     info!("  Tokens tracked: {:?}", cfg.token_set);
+    info!("  Token stale after: {}s", cfg.token_stale_secs);
+    if cfg.retention_days > 0 {
+        info!("  Retention: prune transfers older than {} day(s)", cfg.retention_days);
+    } else {
+        info!("  Retention: disabled (keep all transfers)");
+    }
+    if cfg.wal_checkpoint_secs > 0 {
+        info!("  WAL checkpoint: every {}s and on shutdown", cfg.wal_checkpoint_secs);
+    } else {
+        info!("  WAL checkpoint: on shutdown only");
+    }
+    if cfg.alert_rules.is_empty() {
+        info!("  Alerts: none (set ALERT_RULES=token:threshold)");
+    } else {
+        info!("  Alerts: {:?} → {}, cooldown {}s", cfg.alert_rules,
+            cfg.webhook_url.as_deref().map_or("log only".to_string(), config::redact_url), cfg.alert_cooldown_secs);
+    }
     info!("  Exchanges tracked: {:?}", cfg.exchange_set);
+    if let Some(path) = &cfg.exchange_addresses_file {
+        match cfg.exchange_reload_secs {
+            0 => info!("  Exchange wallets file: {} (read at startup)", path),
+            secs => info!("  Exchange wallets file: {} (re-read every {}s)", path, secs),
+        }
+    }
+    info!("  Event topics (overrides): {:?}", cfg.event_topics);
+    if !cfg.sample_rates.is_empty() {
+        if cfg.index_mode == config::IndexMode::All {
+            info!("  Sampled NEUTRAL transfers (share kept): {:?}", cfg.sample_rates);
+        } else {
+            warn!("SAMPLE_RATE only applies with INDEX_MODE=all; ignored");
+        }
+    }
+    if cfg.exchange_topic_filter {
+        if cfg.index_mode == config::IndexMode::ExchangeOnly {
+            info!("  eth_getLogs filtered to exchange from/to topics ({} wallet(s) per query)", cfg.exchange_topic_batch);
+        } else {
+            warn!("EXCHANGE_TOPIC_FILTER only applies with INDEX_MODE=exchange_only; ignored");
+        }
+    }
+    if !cfg.fee_on_transfer.is_empty() {
+        info!("  Fee-on-transfer tokens (best-effort netflows): {:?}", cfg.fee_on_transfer);
+    }
+
+    // Dry-run: validate config + connectivity, then exit
+    let check_mode = std::env::args().any(|a| a == "--check")
+        || std::env::var("MODE").map(|m| m == "check").unwrap_or(false);
+    if check_mode {
+        let ok = check::run(&cfg).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
 
     // Run DB migrations once at startup
     {
-        let conn = db::connect(&cfg.db_path)?;
-        db::run_migrations(&conn)?;
+        let mut conn = db::connect(&cfg.db_path)?;
+        db::run_migrations(&mut conn)?;
+        // sync `exchanges`; if the wallets changed since the last run, re-derive stored directions
+        let conn = Mutex::new(conn);
+        indexer::apply_exchange_set(&conn, &cfg)?;
+        let conn = conn.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // baselines and the API scale tokens with no known decimals like the indexer does
+        db::record_default_decimals(&conn, cfg.default_decimals)?;
+        db::record_chain_id(&conn, cfg.chain_id)?;
+
+        // NETFLOW_UNITS or CHAIN_ID changed since the cache was written: rebuild it
+        let stale = aggregator::stale_units(&conn, cfg.netflow_units)?;
+        if stale > 0 {
+            info!("Recomputing {} netflow(s) in {} units for chain {}", stale, cfg.netflow_units.as_str(), cfg.chain_id);
+            aggregator::recompute_full(&conn, cfg.netflow_units)?;
+        }
+
+        // Self-check: cached netflows vs exact recomputation (drift is logged)
+        let report = aggregator::verify(&conn, cfg.netflow_units)?;
+        let drifted = report.iter().filter(|d| d.drift).count();
+        if drifted > 0 {
+            warn!("{} token(s) have netflow drift; run `cargo run --bin rebuild_netflows` to repair", drifted);
+        } else {
+            info!("Netflow cache verified for {} token(s)", report.len());
+        }
     }
 
     // Shared DB connection
     let shared_conn = Arc::new(Mutex::new(db::connect(&cfg.db_path)?));
 
+    // Live transfer feed (indexer → /ws subscribers); lagging clients drop events
+    let (events, _) = broadcast::channel::<models::Transfer>(1024);
+
+    // RPC circuit breaker shared by the indexer's client and /health
+    let breaker = cfg.rpc_breaker();
+
+    // In-memory netflows: the indexer reloads it with each netflows write, /netflow reads it
+    let netflows = cache::NetflowCache::default();
+    let loaded = netflows.reload(&db::lock(&shared_conn))?;
+    info!("Netflow cache warmed with {} token(s)", loaded);
+
+    // Cached /transfers/count results: every write path to `transfers` drops them
+    let counts = cache::TransferCounts::default();
+
+    // Recent indexer failures: the indexer pushes them, /health lists them
+    let errors = error::RecentErrors::default();
+
+    // Snapshot mode: index the fixed range without the API, then exit
+    if cfg.backfill_range.is_some() {
+        tokio::select! {
+            res = indexer::run(cfg.clone(), Arc::clone(&shared_conn), events, breaker, netflows, counts, errors) => match res {
+                Ok(()) => info!("Backfill finished"),
+                Err(e) => {
+                    error!("Backfill error: {:?}", e);
+                    return Err(e.into());
+                }
+            },
+            _ = signal::ctrl_c() => {
+                info!("Shutdown signal received, stopping...");
+            }
+        }
+        checkpoint_on_exit(&shared_conn);
+        info!("Polygon Indexer stopped.");
+        return Ok(());
+    }
+
     // Spawn API task
     let api_handle = tokio::spawn({
         let cfg = cfg.clone();
         let conn = Arc::clone(&shared_conn);
-        async move { api::serve(cfg, conn).await }
+        let events = events.clone();
+        let breaker = breaker.clone();
+        let netflows = netflows.clone();
+        let counts = counts.clone();
+        let errors = errors.clone();
+        async move { api::serve(cfg, conn, events, breaker, netflows, counts, errors).await }
     });
 
+    // Spawn retention task (runs until the process exits)
+    if cfg.retention_days > 0 {
+        tokio::spawn(retention::run(Arc::clone(&shared_conn), counts.clone(), cfg.retention_days));
+    }
+
+    // Spawn WAL checkpoint task (runs until the process exits)
+    if cfg.wal_checkpoint_secs > 0 {
+        tokio::spawn(db::run_checkpoints(Arc::clone(&shared_conn), Duration::from_secs(cfg.wal_checkpoint_secs)));
+    }
+
     // Spawn Indexer task
     let indexer_handle = tokio::spawn({
         let cfg = cfg.clone();
         let conn = Arc::clone(&shared_conn);
-        async move { indexer::run(cfg, conn).await }
+        async move { indexer::run(cfg, conn, events, breaker, netflows, counts, errors).await }
     });
 
     // Graceful shutdown
@@ -74,6 +249,19 @@ async fn main() -> eyre::Result<()> {
         }
     }
 
+    checkpoint_on_exit(&shared_conn);
     info!("Polygon Indexer stopped.");
     Ok(())
+}
+
+/// Fold the WAL back into the database before exiting, so the next start
+/// doesn't have to replay a large `-wal` file. Waits for whoever holds the
+/// connection (e.g. a write in progress) to finish first.
+fn checkpoint_on_exit(conn: &Mutex<Connection>) {
+    let conn = db::lock(conn);
+    match db::checkpoint(&conn) {
+        Ok(true) => info!("WAL checkpointed on exit"),
+        Ok(false) => {} // already warned
+        Err(e) => error!("WAL checkpoint on exit failed: {}", e),
+    }
 }
\ No newline at end of file
@@ -1,10 +1,11 @@
 // src/models.rs
 use serde::Serialize;
+use utoipa::ToSchema;
 use rust_decimal::Decimal;
 use chrono::{DateTime, Utc};
 
 /// Represents a single ERC20 transfer involving Binance
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct Transfer {
     pub tx_hash: String,
     pub block_number: i64,
@@ -12,16 +13,200 @@ pub struct Transfer {
     pub to_address: String,
     pub token_address: String,
     pub amount: String,        // keep as String (safe for DB + API)
-    pub direction: String,     // "IN" or "OUT"
-    pub timestamp: String,     // store + return as RFC3339 string
+    pub direction: String,     // "IN", "OUT" or "NEUTRAL" (INDEX_MODE=all)
+    pub exchange_address: Option<String>, // matched exchange wallet, None for NEUTRAL
+    pub raw_amount: Option<String>, // on-chain integer (None for rows indexed before it was stored)
+    pub decimals: u32,         // amount = raw_amount / 10^decimals
+    pub chain_id: u64,         // EVM chain the transfer was indexed from (CHAIN_ID)
+    pub timestamp: String,     // block time (indexing time for older rows), as stored
 }
 
 /// Represents aggregated netflows for a token
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct NetFlow {
     pub token_address: String,
     pub cumulative_net: Decimal,   // keep Decimal (math friendly)
+    pub inflow: Decimal,           // gross cumulative IN volume (netflows.inflow_total)
+    pub outflow: Decimal,          // gross cumulative OUT volume (netflows.outflow_total)
     pub last_block: i64,
     pub updated_at: DateTime<Utc>, // DateTime for consistency
+    pub units: crate::config::NetflowUnits, // NETFLOW_UNITS the figures are in
+    pub best_effort: bool,         // FEE_ON_TRANSFER_TOKENS: built from sent, not received, amounts
 }
 
+/// One token's netflow through a single exchange wallet (`netflows_by_exchange`)
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ExchangeNetFlow {
+    pub token_address: String,
+    pub exchange_address: String,
+    pub label: Option<String>,     // from `exchanges`, if the wallet is listed there
+    pub cumulative_net: Decimal,
+    pub inflow: Decimal,
+    pub outflow: Decimal,
+    pub transfers: i64,            // retained IN/OUT transfers through this wallet
+    pub last_block: i64,
+    pub updated_at: DateTime<Utc>,
+    pub units: crate::config::NetflowUnits,
+    pub best_effort: bool,
+}
+
+/// Net exchange flow of one token over a trailing window (`GET /netflow/window`)
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NetFlowWindow {
+    pub token_address: String,
+    pub hours: u32,
+    pub since: DateTime<Utc>, // window start; transfers indexed at or after it count
+    pub inflow: Decimal,
+    pub outflow: Decimal,
+    pub net: Decimal,         // inflow - outflow
+    pub transfers: i64,       // IN and OUT transfers in the window
+    pub units: crate::config::NetflowUnits,
+    pub best_effort: bool,    // FEE_ON_TRANSFER_TOKENS, as on NetFlow
+}
+
+/// Cached vs exactly recomputed net for one token (`GET /debug/verify`)
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NetFlowDrift {
+    pub token_address: String,
+    pub cached: Option<Decimal>, // None when transfers exist but no netflows row does, or it doesn't parse
+    pub recomputed: Decimal,
+    pub diff: Decimal,           // cached - recomputed
+    pub drift: bool,             // |diff| above aggregator::VERIFY_EPSILON
+}
+
+/// Webhook payload sent when a token's net outflow crosses its ALERT_RULES threshold
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NetFlowAlert {
+    pub token_address: String,
+    pub cumulative_net: Decimal, // negative: more left exchanges than arrived
+    pub net_outflow: Decimal,    // -cumulative_net
+    pub threshold: Decimal,
+    pub triggered_at: DateTime<Utc>,
+}
+
+/// An exchange wallet the indexer classifies IN/OUT against
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Exchange {
+    pub address: String,
+    pub label: String,
+}
+
+/// Block range covered by indexed transfers for a token
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Coverage {
+    pub token_address: String,
+    pub min_block: i64,
+    pub max_block: i64,
+    pub transfer_count: i64,
+    pub last_updated: String, // latest transfer write time
+}
+
+/// Number of transfers matching a `/transfers` filter, for pagination
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TransferCount {
+    pub token_address: String,
+    pub count: i64,
+    pub cached: bool, // served from the per-filter cache (reset on new transfers)
+}
+
+/// A distinct address on a token's transfers and how often it appears (`GET /addresses`)
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AddressActivity {
+    pub address: String,
+    pub transfers: i64, // transfers it sent and/or received, depending on `role`
+}
+
+/// A block range queued for retry after its fetch or write failed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FailedRange {
+    pub token_address: String,
+    pub from_block: i64,
+    pub to_block: i64,
+    pub attempts: i64,
+    pub last_error: String,
+    pub first_failed_at: String,
+    pub next_retry_at: String,
+}
+
+/// Startup backfill progress for one token (`GET /health/backfill`);
+/// netflows aren't reliable until `complete`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BackfillStatus {
+    pub token_address: String,
+    pub from_block: Option<i64>, // None: backfill hasn't started for this token
+    pub to_block: Option<i64>,
+    pub scanned_blocks: i64,
+    pub total_blocks: i64,
+    pub percent: f64,
+    pub pending_retries: i64,    // chunks in `failed_ranges` still to be recovered
+    pub complete: bool,
+    pub started_at: Option<String>,
+    pub completed_at: Option<String>,
+}
+
+/// Result of `POST /admin/reindex`: what was deleted and which blocks were
+/// queued for re-indexing (none if the token had no transfers)
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PurgedToken {
+    pub token_address: String,
+    pub transfers_deleted: usize,
+    pub rescan_from_block: Option<i64>,
+    pub rescan_to_block: Option<i64>,
+}
+
+/// ERC20 metadata cached from `name()` / `symbol()` / `decimals()`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Token {
+    pub address: String,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: u32,
+    pub decimals_assumed: bool, // DEFAULT_DECIMALS until `decimals()` can be read
+    pub fetched_at: String,
+}
+
+/// Per-token feed freshness for `/health/tokens`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TokenHealth {
+    pub token_address: String,
+    pub last_transfer_at: Option<String>, // newest transfer write time (UTC)
+    pub last_block: Option<i64>,
+    pub stale: bool, // nothing recorded within TOKEN_STALE_SECS
+}
+
+/// `GET /health`: overall status plus the RPC circuit breaker
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Health {
+    pub status: &'static str, // "ok", or "degraded" while the breaker isn't closed
+    pub rpc: crate::rpc::BreakerHealth,
+    pub malformed_topic_logs: u64, // Transfer logs dropped for bad address topics since startup
+    pub last_error: Option<RecentError>, // newest of `recent_errors`
+    pub recent_errors: Vec<RecentError>, // newest first, at most RECENT_ERRORS_CAP, since startup
+}
+
+/// An indexer failure reported by `/health`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RecentError {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// Body of every API error response: `{"error": {"code", "message"}}`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    pub code: &'static str, // snake_case status, e.g. "not_found", "internal"
+    pub message: String,
+}
+
+/// `GET /version`: what's deployed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BuildInfo {
+    pub version: &'static str,    // crate version
+    pub git_commit: &'static str, // short hash, "-dirty" if built with local changes, or "unknown"
+    pub built_at: String,         // RFC3339
+}
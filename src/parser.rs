@@ -1,53 +1,400 @@
 // src/parser.rs
-use alloy::primitives::Address;
-use crate::rpc::Log;
+use alloy::primitives::{Address, U256};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{debug, warn};
+use crate::rpc::{Log, APPROVAL_TOPIC, DEPOSIT_TOPIC, TRANSFER_TOPIC, WITHDRAWAL_TOPIC};
 
-/// A decoded ERC20 Transfer
+/// Transfer logs dropped because an address topic wasn't valid hex, since startup
+static MALFORMED_TOPIC_LOGS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of Transfer logs dropped for malformed address topics (`/health`)
+pub fn malformed_topic_logs() -> u64 {
+    MALFORMED_TOPIC_LOGS.load(Ordering::Relaxed)
+}
+
+/// A decoded ERC20 Transfer. WETH-style Deposit/Withdrawal logs decode to
+/// one too: a mint from (or burn to) the zero address.
 #[derive(Debug, Clone)]
 pub struct Transfer {
     pub from: Address,
     pub to: Address,
-    pub value_u128: u128,    // raw token units (assumes < 2^128 for simplicity)
+    pub value: U256,         // raw token units (full 256-bit range)
     pub block_number: u64,
     pub tx_hash: String,
     pub log_index: u64,      //  added for uniqueness
 }
 
-fn topic_to_address(topic: &str) -> Option<Address> {
-    // topics[1] and topics[2] are 32-byte (padded) hex strings; address is the last 20 bytes
-    let s = topic.trim_start_matches("0x");
-    let bytes = hex::decode(s).ok()?;
-    if bytes.len() != 32 {
+/// Where a Transfer log carries its uint256 value (TRANSFER_VALUE_LAYOUT)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueLayout {
+    /// `data` when it holds a word; empty data with a 4th topic reads that topic
+    #[default]
+    Auto,
+    /// Standard ERC20: `data` only, so an ERC-721 log (empty data) is skipped
+    Data,
+    /// Indexed value: `topics[3]`, whatever `data` holds
+    Topic,
+}
+
+impl ValueLayout {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Data => "data",
+            Self::Topic => "topic",
+        }
+    }
+}
+
+/// A configured non-Transfer event (Approval or custom), kept undecoded
+#[derive(Debug, Clone)]
+pub struct RawEvent {
+    pub name: Option<&'static str>, // known signature name, if any
+    pub topic0: String,
+    pub topics: Vec<String>,        // indexed args (topics[1..])
+    pub data: String,
+    pub block_number: u64,
+    pub tx_hash: String,
+    pub log_index: u64,
+}
+
+/// A log routed by its event signature (topics[0])
+#[derive(Debug, Clone)]
+pub enum Event {
+    Transfer(Transfer),
+    Other(RawEvent),
+}
+
+/// Human-readable name for a known event signature
+pub fn event_name(topic0: &str) -> Option<&'static str> {
+    if topic0.eq_ignore_ascii_case(TRANSFER_TOPIC) {
+        Some("Transfer")
+    } else if topic0.eq_ignore_ascii_case(APPROVAL_TOPIC) {
+        Some("Approval")
+    } else if topic0.eq_ignore_ascii_case(DEPOSIT_TOPIC) {
+        Some("Deposit")
+    } else if topic0.eq_ignore_ascii_case(WITHDRAWAL_TOPIC) {
+        Some("Withdrawal")
+    } else {
+        None
+    }
+}
+
+fn hex_to_u64(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+/// Parse a log's `data` as its single uint256 word. Empty data ("0x") is not
+/// a zero value but an indexed one (ERC-721's tokenId, or an indexed-value
+/// ERC20, see `ValueLayout`), so it is None, as is non-hex data; "0x00…0" is
+/// a real zero.
+fn data_to_u256(data: &str) -> Option<U256> {
+    let hex = strip_hex_prefix(data);
+    if hex.is_empty() {
         return None;
     }
-    let addr_bytes: [u8; 20] = bytes[12..32].try_into().ok()?;
-    Some(Address::from(addr_bytes))
+    U256::from_str_radix(hex, 16).ok()
 }
 
-/// Decode a single log into `Transfer`
+fn strip_hex_prefix(s: &str) -> &str {
+    let s = s.trim();
+    s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s)
+}
+
+/// A Transfer's value, read where `layout` says
+fn transfer_value(log: &Log, layout: ValueLayout) -> Option<U256> {
+    let indexed = || log.topics.get(3).and_then(|t| normalize_topic(t)).map(U256::from_be_bytes);
+    match layout {
+        ValueLayout::Data => data_to_u256(&log.data),
+        ValueLayout::Topic => indexed(),
+        ValueLayout::Auto if log.topics.len() == 4 && strip_hex_prefix(&log.data).is_empty() => indexed(),
+        ValueLayout::Auto => data_to_u256(&log.data),
+    }
+}
+
+/// Parse an indexed topic as a 32-byte word. Some providers drop the
+/// leading-zero padding or send `0X`, so the `0x` prefix is optional in either
+/// case and shorter values are left-padded; non-hex or over-long ones are None.
+fn normalize_topic(topic: &str) -> Option<[u8; 32]> {
+    let topic = topic.trim();
+    let s = topic
+        .strip_prefix("0x")
+        .or_else(|| topic.strip_prefix("0X"))
+        .unwrap_or(topic);
+    if s.is_empty() || s.len() > 64 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes = hex::decode(format!("{:0>64}", s)).ok()?;
+    bytes.try_into().ok()
+}
+
+/// Address in an indexed topic (topics[1]/[2]): the last 20 bytes of the word
+pub fn topic_to_address(topic: &str) -> Option<Address> {
+    let word = normalize_topic(topic)?;
+    Some(Address::from_slice(&word[12..]))
+}
+
+/// Decode a single log into `Transfer`, detecting where its value is
 pub fn decode_transfer(log: &Log) -> Option<Transfer> {
+    decode_transfer_as(log, ValueLayout::Auto)
+}
+
+/// Decode a single log into `Transfer`, reading its value as `layout` says
+pub fn decode_transfer_as(log: &Log, layout: ValueLayout) -> Option<Transfer> {
     if log.topics.len() < 3 {
         return None;
     }
 
-    let from = topic_to_address(&log.topics[1])?;
-    let to = topic_to_address(&log.topics[2])?;
+    let (Some(from), Some(to)) = (topic_to_address(&log.topics[1]), topic_to_address(&log.topics[2])) else {
+        MALFORMED_TOPIC_LOGS.fetch_add(1, Ordering::Relaxed);
+        warn!("Dropping Transfer log {}#{}: malformed address topics {:?} / {:?}",
+            log.tx_hash, log.log_index_hex, log.topics[1], log.topics[2]);
+        return None;
+    };
 
-    let value_hex = log.data.trim_start_matches("0x");
-    let value_u128 = u128::from_str_radix(value_hex, 16).unwrap_or(0);
+    let Some(value) = transfer_value(log, layout) else {
+        debug!("Skipping Transfer log {}#{}: no uint256 value ({} layout) in data {:?} / topics {:?}",
+            log.tx_hash, log.log_index_hex, layout.as_str(), log.data, &log.topics[3.min(log.topics.len())..]);
+        return None;
+    };
 
     let block_number =
         u64::from_str_radix(log.block_number_hex.trim_start_matches("0x"), 16).ok()?;
 
-    let log_index =
-        u64::from_str_radix(log.log_index_hex.trim_start_matches("0x"), 16).unwrap_or(0);
+    // `RpcClient` fills in any logIndex the provider left out
+    let log_index = hex_to_u64(&log.log_index_hex)?;
 
     Some(Transfer {
         from,
         to,
-        value_u128,
+        value,
         block_number,
         tx_hash: log.tx_hash.clone(),
         log_index, // ✅ included
     })
 }
+
+/// Decode a wrapped-native `Deposit(address indexed dst, uint256 wad)` as a
+/// mint to `dst`, or `Withdrawal(address indexed src, uint256 wad)` as a burn
+/// from `src`, so an exchange wrapping counts as inflow and unwrapping as outflow
+pub fn decode_wrap(log: &Log) -> Option<Transfer> {
+    let deposit = log.topics.first()?.eq_ignore_ascii_case(DEPOSIT_TOPIC);
+    if log.topics.len() < 2 {
+        return None;
+    }
+
+    let Some(holder) = topic_to_address(&log.topics[1]) else {
+        MALFORMED_TOPIC_LOGS.fetch_add(1, Ordering::Relaxed);
+        warn!("Dropping {} log {}#{}: malformed address topic {:?}",
+            if deposit { "Deposit" } else { "Withdrawal" }, log.tx_hash, log.log_index_hex, log.topics[1]);
+        return None;
+    };
+    let (from, to) = if deposit { (Address::ZERO, holder) } else { (holder, Address::ZERO) };
+
+    Some(Transfer {
+        from,
+        to,
+        value: data_to_u256(&log.data)?,
+        block_number: hex_to_u64(&log.block_number_hex)?,
+        tx_hash: log.tx_hash.clone(),
+        log_index: hex_to_u64(&log.log_index_hex)?,
+    })
+}
+
+/// (block number, log index) of any log, e.g. one flagged `removed`
+pub fn log_position(log: &Log) -> Option<(u64, u64)> {
+    Some((hex_to_u64(&log.block_number_hex)?, hex_to_u64(&log.log_index_hex)?))
+}
+
+/// Decode a log, routing on its signature: Transfer (and, when configured,
+/// Deposit/Withdrawal) logs are decoded, a Transfer's value per the token's
+/// `layout`; anything else is returned as a `RawEvent`
+pub fn decode_log(log: &Log, layout: ValueLayout) -> Option<Event> {
+    let topic0 = log.topics.first()?;
+    if topic0.eq_ignore_ascii_case(TRANSFER_TOPIC) {
+        return decode_transfer_as(log, layout).map(Event::Transfer);
+    }
+    if topic0.eq_ignore_ascii_case(DEPOSIT_TOPIC) || topic0.eq_ignore_ascii_case(WITHDRAWAL_TOPIC) {
+        return decode_wrap(log).map(Event::Transfer);
+    }
+
+    Some(Event::Other(RawEvent {
+        name: event_name(topic0),
+        topic0: topic0.clone(),
+        topics: log.topics[1..].to_vec(),
+        data: log.data.clone(),
+        block_number: hex_to_u64(&log.block_number_hex)?,
+        tx_hash: log.tx_hash.clone(),
+        log_index: hex_to_u64(&log.log_index_hex)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FROM: &str = "0x000000000000000000000000f977814e90da44bfa03b6295a0616a897441acec";
+    const TO: &str = "0x000000000000000000000000e7804c37c13166ff0b37f5ae0bb07a3aebb6e245";
+
+    fn log(topics: &[&str], data: &str) -> Log {
+        Log {
+            address: "0x0000000000000000000000000000000000001010".to_string(),
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            data: data.to_string(),
+            block_number_hex: "0x48ef3a0".to_string(),
+            tx_hash: "0xfa119d9c8e0a3b4c1c3ec2a1b5e0d3f1a2b3c4d5e6f708192a3b4c5d6e7f8091".to_string(),
+            log_index_hex: "0x1b".to_string(),
+            removed: false,
+            block_timestamp: None,
+        }
+    }
+
+    #[test]
+    fn decodes_standard_transfer() {
+        // 1.5 tokens (18 decimals)
+        let l = log(
+            &[TRANSFER_TOPIC, FROM, TO],
+            "0x00000000000000000000000000000000000000000000000014d1120d7b160000",
+        );
+        let t = decode_transfer(&l).unwrap();
+
+        assert_eq!(t.from, "0xF977814e90dA44bFA03b6295A0616a897441aceC".parse::<Address>().unwrap());
+        assert_eq!(t.to, "0xe7804c37c13166fF0b37F5aE0BB07A3aEbb6e245".parse::<Address>().unwrap());
+        assert_eq!(t.value, U256::from(1_500_000_000_000_000_000u128));
+        assert_eq!(t.block_number, 76_477_344);
+        assert_eq!(t.log_index, 27);
+        assert_eq!(t.tx_hash, l.tx_hash);
+    }
+
+    #[test]
+    fn decode_log_routes_by_topic() {
+        let transfer = log(&[TRANSFER_TOPIC, FROM, TO], "0x01");
+        assert!(matches!(decode_log(&transfer, ValueLayout::Auto), Some(Event::Transfer(_))));
+
+        let approval = log(&[APPROVAL_TOPIC, FROM, TO], "0x01");
+        let Some(Event::Other(ev)) = decode_log(&approval, ValueLayout::Auto) else { panic!("expected raw event") };
+        assert_eq!(ev.name, Some("Approval"));
+        assert_eq!(ev.topics, [FROM, TO]);
+        assert_eq!(ev.block_number, 76_477_344);
+
+        let custom = "0x1111111111111111111111111111111111111111111111111111111111111111";
+        let Some(Event::Other(ev)) = decode_log(&log(&[custom], "0x"), ValueLayout::Auto) else { panic!("expected raw event") };
+        assert_eq!(ev.name, None);
+        assert_eq!(ev.topic0, custom);
+
+        assert!(decode_log(&log(&[], "0x"), ValueLayout::Auto).is_none());
+    }
+
+    #[test]
+    fn wrap_events_decode_as_mint_and_burn() {
+        let holder: Address = "0xF977814e90dA44bFA03b6295A0616a897441aceC".parse().unwrap();
+        let wad = "0x00000000000000000000000000000000000000000000000014d1120d7b160000";
+
+        let Some(Event::Transfer(t)) = decode_log(&log(&[DEPOSIT_TOPIC, FROM], wad), ValueLayout::Auto) else { panic!("expected transfer") };
+        assert_eq!((t.from, t.to), (Address::ZERO, holder));
+        assert_eq!(t.value, U256::from(1_500_000_000_000_000_000u128));
+        assert_eq!((t.block_number, t.log_index), (76_477_344, 27));
+
+        let Some(Event::Transfer(t)) = decode_log(&log(&[WITHDRAWAL_TOPIC, FROM], wad), ValueLayout::Auto) else { panic!("expected transfer") };
+        assert_eq!((t.from, t.to), (holder, Address::ZERO));
+
+        assert!(decode_wrap(&log(&[DEPOSIT_TOPIC], wad)).is_none());
+        assert!(decode_wrap(&log(&[DEPOSIT_TOPIC, "0xnot-hex"], wad)).is_none());
+    }
+
+    #[test]
+    fn rejects_log_with_two_topics() {
+        let l = log(&[TRANSFER_TOPIC, FROM], "0x01");
+        assert!(decode_transfer(&l).is_none());
+    }
+
+    #[test]
+    fn under_padded_topics_are_left_padded() {
+        let from: Address = "0xF977814e90dA44bFA03b6295A0616a897441aceC".parse().unwrap();
+        for topic in [
+            "0xf977814e90da44bfa03b6295a0616a897441acec", // bare 20-byte address
+            "0XF977814E90DA44BFA03B6295A0616A897441ACEC",
+            "f977814e90da44bfa03b6295a0616a897441acec",   // no prefix
+            "0x0000f977814e90da44bfa03b6295a0616a897441acec",
+        ] {
+            let t = decode_transfer(&log(&[TRANSFER_TOPIC, topic, TO], "0x01")).unwrap();
+            assert_eq!(t.from, from, "{topic}");
+        }
+        // odd length: a nibble of padding missing too
+        assert_eq!(topic_to_address("0x1"), Some(Address::from_slice(&[&[0u8; 19][..], &[1]].concat())));
+    }
+
+    #[test]
+    fn malformed_topics_are_counted_and_dropped() {
+        let before = malformed_topic_logs();
+        for bad in ["0xnot-hex", "0x", &format!("0x{}", "ab".repeat(33))] {
+            assert!(decode_transfer(&log(&[TRANSFER_TOPIC, FROM, bad], "0x01")).is_none(), "{bad}");
+        }
+        assert!(malformed_topic_logs() >= before + 3);
+    }
+
+    #[test]
+    fn empty_data_is_not_a_zero_value_transfer() {
+        // ERC-721 Transfer: tokenId is the 4th indexed topic, data is empty
+        let token_id = "0x0000000000000000000000000000000000000000000000000000000000000457";
+        for data in ["0x", "", " 0x "] {
+            assert!(decode_transfer_as(&log(&[TRANSFER_TOPIC, FROM, TO, token_id], data), ValueLayout::Data).is_none(), "{data:?}");
+            assert!(decode_transfer(&log(&[TRANSFER_TOPIC, FROM, TO], data)).is_none(), "{data:?}");
+        }
+        assert!(decode_transfer(&log(&[TRANSFER_TOPIC, FROM, TO], "0xnot-hex")).is_none());
+        assert!(decode_wrap(&log(&[DEPOSIT_TOPIC, TO], "0x")).is_none());
+    }
+
+    #[test]
+    fn indexed_value_transfers_read_the_fourth_topic() {
+        let value = "0x00000000000000000000000000000000000000000000000000000000000f4240";
+        let indexed = log(&[TRANSFER_TOPIC, FROM, TO, value], "0x");
+
+        // auto: three indexed topics and empty data
+        let t = decode_transfer(&indexed).unwrap();
+        assert_eq!((t.value, t.log_index), (U256::from(1_000_000u64), 27));
+        let Some(Event::Transfer(t)) = decode_log(&indexed, ValueLayout::Auto) else { panic!("expected transfer") };
+        assert_eq!(t.value, U256::from(1_000_000u64));
+
+        // auto keeps using data when there is some, even with a 4th topic
+        let both = log(&[TRANSFER_TOPIC, FROM, TO, value], "0x2a");
+        assert_eq!(decode_transfer(&both).unwrap().value, U256::from(42u64));
+
+        // forced per token
+        assert_eq!(decode_transfer_as(&both, ValueLayout::Topic).unwrap().value, U256::from(1_000_000u64));
+        assert!(decode_log(&indexed, ValueLayout::Data).is_none());
+        assert!(decode_transfer_as(&log(&[TRANSFER_TOPIC, FROM, TO], "0x2a"), ValueLayout::Topic).is_none());
+        assert!(decode_transfer_as(&log(&[TRANSFER_TOPIC, FROM, TO, "0xzz"], "0x"), ValueLayout::Topic).is_none());
+    }
+
+    #[test]
+    fn zero_word_data_is_a_zero_value_transfer() {
+        let l = log(&[TRANSFER_TOPIC, FROM, TO], &format!("0x{}", "0".repeat(64)));
+        let t = decode_transfer(&l).unwrap();
+
+        assert_eq!(t.from, "0xF977814e90dA44bFA03b6295A0616a897441aceC".parse::<Address>().unwrap());
+        assert_eq!(t.to, "0xe7804c37c13166fF0b37F5aE0BB07A3aEbb6e245".parse::<Address>().unwrap());
+        assert_eq!(t.value, U256::ZERO);
+        assert_eq!(t.log_index, 27);
+        assert_eq!(decode_transfer(&log(&[TRANSFER_TOPIC, FROM, TO], "0x0")).unwrap().value, U256::ZERO);
+    }
+
+    #[test]
+    fn odd_length_data_is_parsed_as_a_number() {
+        let l = log(&[TRANSFER_TOPIC, FROM, TO], "0xabc");
+        let t = decode_transfer(&l).unwrap();
+        assert_eq!(t.value, U256::from(0xabcu64));
+    }
+
+    #[test]
+    fn values_above_u128_are_preserved() {
+        let l = log(
+            &[TRANSFER_TOPIC, FROM, TO],
+            "0x0000000000000000000000000000000100000000000000000000000000000000",
+        );
+        let t = decode_transfer(&l).unwrap();
+        assert_eq!(t.value, U256::from(1u8) << 128);
+    }
+}
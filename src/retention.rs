@@ -0,0 +1,288 @@
+// src/retention.rs
+// Periodically deletes old transfers (RETENTION_DAYS) without losing their
+// contribution to the cumulative netflow: pruned amounts are folded into
+// `netflow_baselines` in the same transaction as the delete
+use crate::cache::TransferCounts;
+use crate::db::{self, commit_with_retry, to_sql_int};
+use crate::error::{IndexerError, Result};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use rust_decimal::prelude::FromStr;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task;
+use tracing::{error, info};
+
+/// How often the retention task wakes up
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Transfers of the aggregated chain (`?2`) older than the window whose
+/// block is already folded into `netflows` (at or below the token's
+/// `last_block`). Other chains' rows have no netflow to carry them.
+const PRUNABLE: &str = "
+    FROM transfers t
+    WHERE julianday(t.timestamp) < julianday('now', '-' || ?1 || ' days')
+      AND t.chain_id = ?2
+      AND t.block_number <= (
+          SELECT n.last_block FROM netflows n WHERE n.token_address = t.token_address AND n.chain_id = ?2
+      )";
+
+/// Number of transfers the next prune would delete
+pub fn count_prunable(conn: &Connection, retention_days: u64) -> Result<usize> {
+    let n: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) {PRUNABLE}"),
+        [retention_days as i64, to_sql_int(db::chain_id(conn)?)?],
+        |r| r.get(0),
+    )?;
+    Ok(n as usize)
+}
+
+/// Delete prunable transfers, adding their inflow/outflow to each token's
+/// baseline (and each exchange's, for `netflows_by_exchange`) so
+/// `update_netflows` keeps producing the same totals.
+/// Returns the number of rows deleted.
+pub fn prune(conn: &mut Connection, retention_days: u64) -> Result<usize> {
+    let days = retention_days as i64;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let chain = to_sql_int(db::chain_id(&tx)?)?;
+
+    // token → (inflow, outflow, rows, max block)
+    let mut pruned: BTreeMap<String, (Decimal, Decimal, i64, i64)> = BTreeMap::new();
+    // (token, exchange) → (inflow, outflow), for `netflows_by_exchange`
+    let mut by_exchange: BTreeMap<(String, String), (Decimal, Decimal)> = BTreeMap::new();
+    {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT t.token_address, t.direction, t.amount, t.block_number, t.exchange_address {PRUNABLE}"
+        ))?;
+        let rows = stmt.query_map([days, chain], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, i64>(3)?,
+                r.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        for row in rows {
+            let (token, direction, amount, block, exchange) = row?;
+            let amount = Decimal::from_str(&amount).unwrap_or(Decimal::ZERO);
+            let entry = pruned.entry(token.clone()).or_default();
+            match direction.as_str() {
+                "IN" => entry.0 += amount,
+                "OUT" => entry.1 += amount,
+                _ => {}
+            }
+            entry.2 += 1;
+            entry.3 = entry.3.max(block);
+
+            if let (Some(exchange), "IN" | "OUT") = (exchange, direction.as_str()) {
+                let entry = by_exchange.entry((token, exchange)).or_default();
+                if direction == "IN" {
+                    entry.0 += amount;
+                } else {
+                    entry.1 += amount;
+                }
+            }
+        }
+    }
+
+    for (token, (inflow, outflow, rows, block)) in &pruned {
+        let (base_in, base_out): (String, String) = tx
+            .query_row(
+                "SELECT inflow, outflow FROM netflow_baselines WHERE chain_id = ?1 AND token_address = ?2",
+                params![chain, token],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?
+            .unwrap_or_else(|| ("0".to_string(), "0".to_string()));
+        let inflow = stored_amount(&base_in)? + inflow;
+        let outflow = stored_amount(&base_out)? + outflow;
+
+        tx.execute(
+            "INSERT INTO netflow_baselines (chain_id, token_address, inflow, outflow, pruned_rows, pruned_through_block)
+             VALUES (?6, ?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(chain_id, token_address) DO UPDATE SET
+                inflow = excluded.inflow,
+                outflow = excluded.outflow,
+                pruned_rows = pruned_rows + excluded.pruned_rows,
+                pruned_through_block = MAX(pruned_through_block, excluded.pruned_through_block)",
+            params![token, inflow.to_string(), outflow.to_string(), rows, block, chain],
+        )?;
+    }
+
+    for ((token, exchange), (inflow, outflow)) in &by_exchange {
+        let (base_in, base_out): (String, String) = tx
+            .query_row(
+                "SELECT inflow, outflow FROM netflow_exchange_baselines
+                 WHERE chain_id = ?1 AND token_address = ?2 AND exchange_address = ?3",
+                params![chain, token, exchange],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?
+            .unwrap_or_else(|| ("0".to_string(), "0".to_string()));
+        let inflow = stored_amount(&base_in)? + inflow;
+        let outflow = stored_amount(&base_out)? + outflow;
+
+        tx.execute(
+            "INSERT INTO netflow_exchange_baselines (chain_id, token_address, exchange_address, inflow, outflow)
+             VALUES (?5, ?1, ?2, ?3, ?4)
+             ON CONFLICT(chain_id, token_address, exchange_address) DO UPDATE SET
+                inflow = excluded.inflow,
+                outflow = excluded.outflow",
+            params![token, exchange, inflow.to_string(), outflow.to_string(), chain],
+        )?;
+    }
+
+    let deleted = tx.execute(&format!("DELETE FROM transfers WHERE id IN (SELECT t.id {PRUNABLE})"), [days, chain])?;
+    commit_with_retry(tx)?;
+    Ok(deleted)
+}
+
+/// A stored baseline amount; one that doesn't parse fails the prune rather
+/// than restarting the baseline from zero
+fn stored_amount(stored: &str) -> Result<Decimal> {
+    Decimal::from_str(stored).map_err(|e| IndexerError::Decode(format!("netflow baseline {:?}: {}", stored, e)))
+}
+
+/// Background loop: log the dry-run count, then prune, every `PRUNE_INTERVAL`
+pub async fn run(conn: Arc<Mutex<Connection>>, counts: TransferCounts, retention_days: u64) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        ticker.tick().await;
+
+        let conn = Arc::clone(&conn);
+        let counts = counts.clone();
+        let res = task::spawn_blocking(move || -> Result<usize> {
+            let mut db = crate::db::lock(&conn);
+            let candidates = count_prunable(&db, retention_days)?;
+            info!("🧹 Retention: {} transfer(s) older than {} day(s) eligible for pruning", candidates, retention_days);
+            if candidates == 0 {
+                return Ok(0);
+            }
+            let pruned = prune(&mut db, retention_days)?;
+            counts.invalidate_all();
+            Ok(pruned)
+        })
+        .await;
+
+        match res {
+            Ok(Ok(0)) => {}
+            Ok(Ok(n)) => info!("🧹 Retention: pruned {} transfer(s)", n),
+            Ok(Err(e)) => error!("Retention prune failed: {}", e),
+            Err(e) => error!("Retention task panicked: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetflowUnits;
+    use crate::{aggregator, db};
+
+    fn insert(conn: &Connection, block: i64, direction: &str, amount: &str, days_ago: i64) {
+        conn.execute(
+            "INSERT INTO transfers (block_number, tx_hash, log_index, token_address, from_address,
+                                    to_address, amount, direction, timestamp)
+             VALUES (?1, ?2, 0, '0xtoken', '0xa', '0xb', ?3, ?4, datetime('now', ?5))",
+            params![block, format!("0x{block}"), amount, direction, format!("-{days_ago} days")],
+        )
+        .unwrap();
+    }
+
+    fn netflow(conn: &Connection) -> (String, String, String) {
+        conn.query_row(
+            "SELECT cumulative_net, inflow_total, outflow_total FROM netflows WHERE token_address = '0xtoken'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn prune_preserves_netflow() {
+        let mut conn = db::open_in_memory();
+        insert(&conn, 1, "IN", "10", 40);
+        insert(&conn, 2, "OUT", "4", 35);
+        insert(&conn, 3, "IN", "1", 1);
+        // another chain's row: not in this chain's netflow, so never pruned into it
+        insert(&conn, 5, "IN", "100", 40);
+        conn.execute("UPDATE transfers SET chain_id = 1 WHERE block_number = 5", []).unwrap();
+        aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        let before = netflow(&conn);
+
+        // not yet reflected in netflows (block above last_block): must survive
+        insert(&conn, 4, "IN", "7", 50);
+
+        assert_eq!(count_prunable(&conn, 30).unwrap(), 2);
+        assert_eq!(prune(&mut conn, 30).unwrap(), 2);
+        assert_eq!(count_prunable(&conn, 30).unwrap(), 0);
+
+        let left: Vec<i64> = conn
+            .prepare("SELECT block_number FROM transfers ORDER BY block_number")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(left, vec![3, 4, 5]);
+
+        // the re-aggregated totals include block 4 but nothing is lost from 1–2
+        aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        let (net, inflow, outflow) = netflow(&conn);
+        assert_eq!(before, ("7".into(), "11".into(), "4".into()));
+        assert_eq!((net.as_str(), inflow.as_str(), outflow.as_str()), ("14", "18", "4"));
+
+        let full = aggregator::recompute_full(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(full, vec![("0xtoken".to_string(), Decimal::from(14))]);
+
+        // the baseline is kept in token units and scaled for NETFLOW_UNITS=raw
+        let raw = aggregator::recompute_full(&conn, NetflowUnits::Raw).unwrap();
+        assert_eq!(raw, vec![("0xtoken".to_string(), Decimal::from(14) * Decimal::from(1_000_000_000_000_000_000i64))]);
+    }
+
+    #[test]
+    fn prune_preserves_netflows_by_exchange() {
+        let mut conn = db::open_in_memory();
+        insert(&conn, 1, "IN", "10", 40);
+        insert(&conn, 2, "OUT", "4", 35);
+        insert(&conn, 3, "IN", "1", 1);
+        conn.execute("UPDATE transfers SET exchange_address = '0xb' WHERE direction = 'IN'", []).unwrap();
+        conn.execute("UPDATE transfers SET exchange_address = '0xa' WHERE direction = 'OUT'", []).unwrap();
+        let by_exchange = |conn: &Connection| -> Vec<(String, String, i64)> {
+            conn.prepare("SELECT exchange_address, cumulative_net, transfers FROM netflows_by_exchange ORDER BY exchange_address")
+                .unwrap()
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        };
+        aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        assert_eq!(by_exchange(&conn), [("0xa".into(), "-4".into(), 1), ("0xb".into(), "11".into(), 2)]);
+
+        assert_eq!(prune(&mut conn, 30).unwrap(), 2);
+        aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        // same nets; only the retained row is still counted
+        assert_eq!(by_exchange(&conn), [("0xa".into(), "-4".into(), 0), ("0xb".into(), "11".into(), 1)]);
+    }
+
+    #[test]
+    fn an_unreadable_baseline_fails_the_prune() {
+        let mut conn = db::open_in_memory();
+        insert(&conn, 1, "IN", "10", 40);
+        aggregator::update_netflows(&conn, NetflowUnits::Token).unwrap();
+        conn.execute(
+            "INSERT INTO netflow_baselines (chain_id, token_address, inflow, outflow, pruned_rows, pruned_through_block)
+             VALUES (137, '0xtoken', 'garbage', '0', 1, 0)",
+            [],
+        )
+        .unwrap();
+
+        // rather than overwriting the baseline with just this prune's flow
+        assert!(prune(&mut conn, 30).is_err());
+        let inflow: String = conn.query_row("SELECT inflow FROM netflow_baselines", [], |r| r.get(0)).unwrap();
+        assert_eq!(inflow, "garbage");
+        assert_eq!(count_prunable(&conn, 30).unwrap(), 1);
+    }
+}
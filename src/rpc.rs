@@ -1,10 +1,20 @@
 // src/rpc.rs
-use eyre::{eyre, Result};
 use reqwest::{Client, StatusCode};
-use serde::Deserialize;
-use serde_json::json;
-use std::time::Duration;
-use tracing::info;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tracing::{field, info, instrument, warn, Span};
+use utoipa::ToSchema;
+use alloy::primitives::U256;
+use crate::backoff::Backoff;
+use crate::error::{IndexerError, Result};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Log {
@@ -19,100 +29,1362 @@ pub struct Log {
     #[serde(rename = "transactionHash")]
     pub tx_hash: String,
 
-    #[serde(rename = "logIndex")] // ✅ Needed for uniqueness in parser
+    // ✅ Needed for uniqueness in parser; empty when the provider omits it,
+    // see `fill_missing_log_indexes`
+    #[serde(rename = "logIndex", default, deserialize_with = "null_as_empty")]
     pub log_index_hex: String,
+
+    #[serde(default)] // ✅ true when the log's block was reorged out
+    pub removed: bool,
+
+    // not part of eth_getLogs: unix seconds of the log's block, set by
+    // `fill_block_timestamps`
+    #[serde(skip)]
+    pub block_timestamp: Option<u64>,
+}
+
+/// A missing or `null` string field as ""
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(d: D) -> std::result::Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(d)?.unwrap_or_default())
+}
+
+/// Give logs without a usable `logIndex` (some archive nodes omit it) a
+/// synthesized one: their position among the block's logs in this response,
+/// counted on from the highest real index in that block so they can't collide
+/// with it. Logs a warning when any were filled.
+fn fill_missing_log_indexes(logs: &mut [Log], token_address: &str, from_block: BlockId, to_block: BlockId) {
+    let mut next: HashMap<String, u64> = HashMap::new();
+    for log in logs.iter() {
+        if let Some(i) = hex_u64(&log.log_index_hex) {
+            let next = next.entry(log.block_number_hex.clone()).or_default();
+            *next = (*next).max(i.saturating_add(1));
+        }
+    }
+
+    let mut filled = 0;
+    for log in logs.iter_mut().filter(|log| hex_u64(&log.log_index_hex).is_none()) {
+        let next = next.entry(log.block_number_hex.clone()).or_default();
+        log.log_index_hex = format!("0x{:x}", next);
+        *next += 1;
+        filled += 1;
+    }
+    if filled > 0 {
+        warn!("{} log(s) for {} ({} → {}) had no logIndex; synthesized from their position",
+            filled, token_address, from_block, to_block);
+    }
+}
+
+/// Provider messages for an eth_getLogs range that matched more logs (or a
+/// bigger response) than it will return; a smaller range would succeed.
+/// Matched on wording since codes are shared with rate limiting (-32005).
+const RESULT_CAP_MESSAGES: &[&str] = &[
+    "query returned more than",   // Infura, geth-style nodes
+    "too many results",
+    "response size exceeded",     // Alchemy
+    "exceeds max results",
+    "range is too wide",          // Ankr
+    "block range too large",
+];
+
+/// Whether `e` is the provider asking for a smaller eth_getLogs range
+fn is_result_cap(e: &IndexerError) -> bool {
+    let IndexerError::Rpc(message) = e else { return false };
+    let message = message.to_lowercase();
+    RESULT_CAP_MESSAGES.iter().any(|m| message.contains(m))
+}
+
+/// A hex quantity, or `None` when empty or malformed
+fn hex_u64(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex.trim_start_matches("0x"), 16).ok()
+}
+
+/// Concatenate the responses of several eth_getLogs queries, dropping logs
+/// an earlier response already returned, ordered by block. Logs without a
+/// logIndex are compared by content instead, before any index is synthesized,
+/// since each response would number them differently.
+fn merge_logs(responses: Vec<Vec<Log>>) -> Vec<Log> {
+    let key = |log: &Log| {
+        let content = if log.log_index_hex.is_empty() { (log.topics.clone(), log.data.clone()) } else { Default::default() };
+        (log.block_number_hex.clone(), log.tx_hash.clone(), log.log_index_hex.clone(), content)
+    };
+    let mut seen = HashSet::new();
+    let mut merged = Vec::new();
+    for logs in responses {
+        let keys: Vec<_> = logs.iter().map(key).collect();
+        merged.extend(logs.into_iter().zip(&keys).filter(|(_, k)| !seen.contains(*k)).map(|(log, _)| log));
+        seen.extend(keys);
+    }
+    merged.sort_by_key(|log| hex_u64(&log.block_number_hex));
+    merged
 }
 
 #[derive(Debug, Deserialize)]
-struct RpcResponse<T> {
-    #[allow(dead_code)]
-    jsonrpc: String,
-    #[allow(dead_code)]
+#[serde(untagged)]
+enum RpcResponse<T> {
+    Success { result: T },
+    Error { error: RpcError },
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// One entry of a JSON-RPC batch response
+#[derive(Debug, Deserialize)]
+struct BatchItem {
     id: u64,
-    result: T,
+    #[serde(default)]
+    result: Value,
+    error: Option<RpcError>,
+}
+
+/// Parse a JSON-RPC response body, surfacing provider error objects
+fn parse_response<T: DeserializeOwned>(text: &str) -> Result<T> {
+    match serde_json::from_str::<RpcResponse<T>>(text) {
+        Ok(RpcResponse::Success { result }) => Ok(result),
+        Ok(RpcResponse::Error { error }) => {
+            Err(IndexerError::Rpc(format!("{}: {}", error.code, error.message)))
+        }
+        Err(e) => Err(IndexerError::Decode(format!("unexpected RPC response ({}): {}", e, text))),
+    }
+}
+
+/// Block the provider resolves by name. `safe` and `finalized` follow the
+/// chain's own finality, so they need no fixed confirmation count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockTag {
+    Latest,
+    Safe,
+    Finalized,
+}
+
+impl BlockTag {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Latest => "latest",
+            Self::Safe => "safe",
+            Self::Finalized => "finalized",
+        }
+    }
+}
+
+/// `fromBlock` / `toBlock` of `eth_getLogs`: a number or a tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockId {
+    Number(u64),
+    Tag(BlockTag),
+}
+
+impl BlockId {
+    /// As a JSON-RPC parameter: hex quantity or tag name
+    fn to_param(self) -> String {
+        match self {
+            Self::Number(n) => format!("0x{:x}", n),
+            Self::Tag(tag) => tag.as_str().to_string(),
+        }
+    }
+}
+
+impl From<u64> for BlockId {
+    fn from(n: u64) -> Self {
+        Self::Number(n)
+    }
+}
+
+impl From<BlockTag> for BlockId {
+    fn from(tag: BlockTag) -> Self {
+        Self::Tag(tag)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Tag(tag) => f.write_str(tag.as_str()),
+        }
+    }
 }
 
 /// ERC20 Transfer event topic keccak256("Transfer(address,address,uint256)")
 pub const TRANSFER_TOPIC: &str =
     "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
 
-/// Get the latest block number with retries and timeout
-pub async fn get_block_number(rpc_url: &str) -> Result<u64> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(10))
-        .build()?;
+/// ERC20 Approval event topic keccak256("Approval(address,address,uint256)")
+pub const APPROVAL_TOPIC: &str =
+    "0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925";
+
+/// WETH9-style wrap event topic keccak256("Deposit(address,uint256)")
+pub const DEPOSIT_TOPIC: &str =
+    "0xe1fffcc4923d04b559f4d29a8bfc6cda04eb5b0d3c460751c2402c5c5cc9109c";
+
+/// WETH9-style unwrap event topic keccak256("Withdrawal(address,uint256)")
+pub const WITHDRAWAL_TOPIC: &str =
+    "0x7fcf532c15f0a6db0bd6d0e038bea71d30d808c7d98cb3bf7268a95bf5081b65";
+
+/// ERC20 view selectors: first 4 bytes of keccak256 of the signature
+pub const NAME_SELECTOR: &str = "0x06fdde03";     // name()
+pub const SYMBOL_SELECTOR: &str = "0x95d89b41";   // symbol()
+pub const DECIMALS_SELECTOR: &str = "0x313ce567"; // decimals()
+
+/// ERC20 metadata read via `eth_call`. `name`/`symbol` are optional in the
+/// standard, so a revert there yields `None`; `decimals` is required.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenMetadata {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: u32,
+}
+
+/// Decode an ABI `string` return value. Falls back to `bytes32` (used by a
+/// few older tokens, e.g. MKR) when the result is a single word.
+fn decode_abi_string(hex_str: &str) -> Option<String> {
+    let bytes = hex::decode(hex_str.trim_start_matches("0x")).ok()?;
+    let raw = if bytes.len() == 32 {
+        bytes.split(|b| *b == 0).next()?.to_vec()
+    } else {
+        let offset = usize::try_from(U256::from_be_slice(bytes.get(..32)?)).ok()?;
+        let len_end = offset.checked_add(32)?;
+        let len = usize::try_from(U256::from_be_slice(bytes.get(offset..len_end)?)).ok()?;
+        bytes.get(len_end..len_end.checked_add(len)?)?.to_vec()
+    };
+    String::from_utf8(raw).ok().filter(|s| !s.is_empty())
+}
+
+/// Decode an ABI `uint8` (or any uint that fits a `u32`) return value
+fn decode_abi_uint(hex_str: &str) -> Option<u32> {
+    let bytes = hex::decode(hex_str.trim_start_matches("0x")).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    u32::try_from(U256::from_be_slice(&bytes)).ok()
+}
+
+/// Timeout for `eth_blockNumber`; cheap call, fail fast
+const BLOCK_NUMBER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default timeout for everything else (`eth_getLogs` can be slow)
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Calls per batch request unless overridden (providers cap batch size)
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// Connection settings for the RPC HTTP client. The defaults keep a few
+/// warm connections per provider and probe idle ones so NATs and load
+/// balancers don't drop them silently between polls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpOptions {
+    /// Idle connections kept open per host
+    pub pool_max_idle_per_host: usize,
+    /// Idle connections are closed after this long; `None` keeps them
+    pub pool_idle_timeout: Option<Duration>,
+    /// TCP keepalive probe interval; `None` disables it
+    pub tcp_keepalive: Option<Duration>,
+    /// Talk HTTP/2 from the first byte (h2c) instead of negotiating it.
+    /// Only for providers known to speak HTTP/2; over TLS, ALPN already
+    /// upgrades when the provider supports it.
+    pub http2_prior_knowledge: bool,
+    /// HTTP/2 PING interval, also while idle; `None` disables it
+    pub http2_keep_alive_interval: Option<Duration>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 16,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            http2_prior_knowledge: false,
+            http2_keep_alive_interval: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Circuit breaker state as reported by `/health`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Calls go through
+    Closed,
+    /// Too many consecutive failures: calls fail fast with `CircuitOpen`
+    Open,
+    /// Cooldown elapsed: calls go through as probes; one failure re-opens
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BreakerHealth {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub retry_in_secs: Option<u64>, // while open
+}
+
+#[derive(Debug, Default)]
+struct BreakerInner {
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Stops calling a provider that keeps failing: after `threshold`
+/// consecutive errors every call returns `IndexerError::CircuitOpen` until
+/// `cooldown` has passed, then calls are let through to probe it. Clones
+/// share state, so the API can report what the indexer's client sees.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    inner: Arc<Mutex<BreakerInner>>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// `threshold == 0` disables the breaker
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self { inner: Arc::default(), threshold, cooldown }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    pub fn health(&self) -> BreakerHealth {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let (state, retry_in) = match inner.opened_at {
+            None => (BreakerState::Closed, None),
+            Some(at) => match self.cooldown.checked_sub(at.elapsed()) {
+                Some(left) if !left.is_zero() => (BreakerState::Open, Some(left)),
+                _ => (BreakerState::HalfOpen, None),
+            },
+        };
+        BreakerHealth {
+            state,
+            consecutive_failures: inner.failures,
+            retry_in_secs: retry_in.map(|d| d.as_secs_f64().ceil() as u64),
+        }
+    }
+
+    /// Fail fast while open
+    fn check(&self) -> Result<()> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match inner.opened_at.and_then(|at| self.cooldown.checked_sub(at.elapsed())) {
+            Some(left) if !left.is_zero() => Err(IndexerError::CircuitOpen(left)),
+            _ => Ok(()),
+        }
+    }
+
+    fn record<T>(&self, result: &Result<T>) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match result {
+            Ok(_) => {
+                if inner.opened_at.is_some() {
+                    info!("RPC circuit closed after successful probe");
+                }
+                *inner = BreakerInner::default();
+            }
+            Err(IndexerError::CircuitOpen(_)) => {}
+            // answered, just not for that big a range: not a provider failure
+            Err(e) if is_result_cap(e) => {}
+            Err(e) => {
+                inner.failures += 1;
+                // a failed half-open probe re-opens straight away
+                if inner.opened_at.is_some() || inner.failures >= self.threshold {
+                    warn!("RPC circuit open for {:?} after {} consecutive failures: {}",
+                        self.cooldown, inner.failures, e);
+                    inner.opened_at = Some(Instant::now());
+                }
+            }
+        }
+    }
+}
+
+/// JSON-RPC client holding one pooled `reqwest::Client` for an endpoint.
+/// Cheap to clone; clones share the same connection pool.
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    url: String,
+    client: Client,
+    backoff: Backoff,
+    batch_size: usize,
+    breaker: CircuitBreaker,
+}
+
+impl RpcClient {
+    pub fn new(url: &str) -> Result<Self> {
+        Self::with_http_options(url, &HttpOptions::default())
+    }
+
+    /// Client whose connection pool and protocol follow `http`
+    pub fn with_http_options(url: &str, http: &HttpOptions) -> Result<Self> {
+        let mut builder = Client::builder()
+            .timeout(DEFAULT_TIMEOUT)
+            .pool_max_idle_per_host(http.pool_max_idle_per_host)
+            .pool_idle_timeout(http.pool_idle_timeout)
+            .tcp_keepalive(http.tcp_keepalive)
+            .http2_keep_alive_interval(http.http2_keep_alive_interval)
+            .http2_keep_alive_while_idle(true);
+        if http.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        let client = builder.build()?;
+        Ok(Self {
+            url: url.to_string(),
+            client,
+            backoff: Backoff::new(Duration::from_secs(2), Duration::from_secs(120)),
+            batch_size: DEFAULT_BATCH_SIZE,
+            breaker: CircuitBreaker::disabled(),
+        })
+    }
+
+    /// Guard every request with `breaker` (shared with whoever else holds a clone)
+    pub fn with_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    /// Run one request through the circuit breaker
+    async fn guarded<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        self.breaker.check()?;
+        let result = call.await;
+        self.breaker.record(&result);
+        result
+    }
+
+    /// Override the max calls sent per JSON-RPC batch request
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Override the jittered backoff used between retries
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Get the latest block number with retries and timeout
+    #[instrument(level = "debug", skip_all)]
+    pub async fn get_block_number(&self) -> Result<u64> {
+        self.guarded(self.fetch_block_number()).await
+    }
+
+    async fn fetch_block_number(&self) -> Result<u64> {
+        for attempt in 1..=3 {
+            let payload = json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "eth_blockNumber",
+                "params": []
+            });
+
+            info!("📡 Sending eth_blockNumber → {}", self.url);
 
-    for attempt in 1..=3 {
+            let res = self
+                .client
+                .post(&self.url)
+                .timeout(BLOCK_NUMBER_TIMEOUT)
+                .json(&payload)
+                .send()
+                .await;
+
+            match res {
+                Ok(resp) if resp.status().is_server_error() && attempt < 3 => {
+                    warn!(
+                        "⚠️ RPC returned HTTP {} (attempt {}). Retrying...",
+                        resp.status(), attempt
+                    );
+                    tokio::time::sleep(self.backoff.delay(attempt - 1)).await;
+                }
+                Ok(resp) => {
+                    if resp.status() != StatusCode::OK {
+                        return Err(IndexerError::Rpc(format!("HTTP {}", resp.status())));
+                    }
+                    let text = resp.text().await?;
+                    info!("📩 Raw blockNumber response: {}", text);
+
+                    let result: String = parse_response(&text)?;
+                    let block_number = u64::from_str_radix(result.trim_start_matches("0x"), 16)
+                        .map_err(|e| IndexerError::Decode(format!("block number {:?}: {}", result, e)))?;
+                    return Ok(block_number);
+                }
+                Err(e) if attempt < 3 => {
+                    warn!(
+                        "⚠️ RPC request failed (attempt {}): {}. Retrying...",
+                        attempt, e
+                    );
+                    tokio::time::sleep(self.backoff.delay(attempt - 1)).await;
+                }
+                Err(e) => return Err(IndexerError::Rpc(format!("❌ request failed after 3 retries: {}", e))),
+            }
+        }
+
+        Err(IndexerError::Rpc("unreachable: retries exhausted".to_string()))
+    }
+
+    /// Number of the block the provider resolves `tag` to, e.g. the
+    /// finalized head (`eth_getBlockByNumber`)
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_tagged_block_number(&self, tag: BlockTag) -> Result<u64> {
+        self.guarded(self.fetch_tagged_block_number(tag)).await
+    }
+
+    async fn fetch_tagged_block_number(&self, tag: BlockTag) -> Result<u64> {
         let payload = json!({
             "jsonrpc": "2.0",
             "id": 1,
-            "method": "eth_blockNumber",
+            "method": "eth_getBlockByNumber",
+            "params": [tag.as_str(), false]
+        });
+
+        info!("📡 Sending eth_getBlockByNumber({}) → {}", tag.as_str(), self.url);
+
+        let resp = self.client.post(&self.url).timeout(BLOCK_NUMBER_TIMEOUT).json(&payload).send().await?;
+        if resp.status() != StatusCode::OK {
+            return Err(IndexerError::Rpc(format!("HTTP {}", resp.status())));
+        }
+        // `null` when the provider has no such block yet
+        let block: Option<Value> = parse_response(&resp.text().await?)?;
+        let number = block
+            .as_ref()
+            .and_then(|b| b.get("number"))
+            .and_then(Value::as_str)
+            .ok_or_else(|| IndexerError::Rpc(format!("no {} block from the provider", tag.as_str())))?;
+        u64::from_str_radix(number.trim_start_matches("0x"), 16)
+            .map_err(|e| IndexerError::Decode(format!("{} block number {:?}: {}", tag.as_str(), number, e)))
+    }
+
+    /// Chain id the provider is serving (`eth_chainId`)
+    #[instrument(level = "debug", skip_all)]
+    pub async fn get_chain_id(&self) -> Result<u64> {
+        self.guarded(self.fetch_chain_id()).await
+    }
+
+    async fn fetch_chain_id(&self) -> Result<u64> {
+        let payload = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_chainId",
             "params": []
         });
 
-        info!("📡 Sending eth_blockNumber → {}", rpc_url);
+        info!("📡 Sending eth_chainId → {}", self.url);
 
-        let res = client.post(rpc_url).json(&payload).send().await;
+        let resp = self.client.post(&self.url).json(&payload).send().await?;
+        if resp.status() != StatusCode::OK {
+            return Err(IndexerError::Rpc(format!("HTTP {}", resp.status())));
+        }
+        let result: String = parse_response(&resp.text().await?)?;
+        u64::from_str_radix(result.trim_start_matches("0x"), 16)
+            .map_err(|e| IndexerError::Decode(format!("chain id {:?}: {}", result, e)))
+    }
+
+    /// Fetch logs for a token in a block range whose first topic is any of
+    /// `topics`. Either end may be a block number or a tag (`finalized`, ...).
+    #[instrument(level = "debug", skip_all, fields(token = %token_address, from_block, to_block, logs = field::Empty))]
+    pub async fn get_transfer_logs(
+        &self,
+        token_address: &str,
+        topics: &[String],
+        from_block: impl Into<BlockId>,
+        to_block: impl Into<BlockId>,
+    ) -> Result<Vec<Log>> {
+        let (from_block, to_block) = (from_block.into(), to_block.into());
+        Span::current().record("from_block", field::display(from_block)).record("to_block", field::display(to_block));
+        // OR-match on the event signature
+        let filter = json!([topics]);
+        let mut logs = self.fetch_logs_bisecting(token_address, &filter, from_block, to_block).await?;
+        fill_missing_log_indexes(&mut logs, token_address, from_block, to_block);
+        Span::current().record("logs", logs.len());
+        Ok(logs)
+    }
+
+    /// Like `get_transfer_logs`, but only logs whose `from` (topic 1) or `to`
+    /// (topic 2) is one of `address_topics`, e.g. the exchange wallets. Topic
+    /// positions AND together, so that is one query per side, and each side is
+    /// split into queries of at most `batch` addresses. Logs matching both
+    /// sides are returned once, in chain order. No addresses, no queries.
+    #[instrument(level = "debug", skip_all, fields(token = %token_address, from_block, to_block, queries = field::Empty, logs = field::Empty))]
+    pub async fn get_address_filtered_logs(
+        &self,
+        token_address: &str,
+        topics: &[String],
+        address_topics: &[String],
+        batch: usize,
+        from_block: impl Into<BlockId>,
+        to_block: impl Into<BlockId>,
+    ) -> Result<Vec<Log>> {
+        let (from_block, to_block) = (from_block.into(), to_block.into());
+        Span::current().record("from_block", field::display(from_block)).record("to_block", field::display(to_block));
 
-        match res {
-            Ok(resp) => {
-                if resp.status() != StatusCode::OK {
-                    return Err(eyre!("RPC error: HTTP {}", resp.status()));
+        let mut responses = Vec::new();
+        for chunk in address_topics.chunks(batch.max(1)) {
+            for filter in [json!([topics, chunk]), json!([topics, null, chunk])] {
+                responses.push(self.fetch_logs_bisecting(token_address, &filter, from_block, to_block).await?);
+            }
+        }
+        Span::current().record("queries", responses.len());
+
+        let mut logs = merge_logs(responses);
+        fill_missing_log_indexes(&mut logs, token_address, from_block, to_block);
+        logs.sort_by_key(|log| (hex_u64(&log.block_number_hex), hex_u64(&log.log_index_hex)));
+        Span::current().record("logs", logs.len());
+        Ok(logs)
+    }
+
+    /// eth_getLogs over `[from_block, to_block]`, halving any range the
+    /// provider refuses for returning too many results (`is_result_cap`) and
+    /// concatenating the halves in block order. Goes down to single blocks; a
+    /// block that still hits the cap fails. A range with a tag end can't be
+    /// split and is sent as is.
+    async fn fetch_logs_bisecting(
+        &self,
+        token_address: &str,
+        topics: &Value,
+        from_block: BlockId,
+        to_block: BlockId,
+    ) -> Result<Vec<Log>> {
+        let (BlockId::Number(from_block), BlockId::Number(to_block)) = (from_block, to_block) else {
+            return self.guarded(self.fetch_transfer_logs(token_address, topics, from_block, to_block)).await;
+        };
+
+        let mut logs = Vec::new();
+        let mut pending = vec![(from_block, to_block)]; // lower half on top, so results stay in order
+        while let Some((from, to)) = pending.pop() {
+            match self.guarded(self.fetch_transfer_logs(token_address, topics, from.into(), to.into())).await {
+                Ok(mut page) => logs.append(&mut page),
+                Err(e) if from < to && is_result_cap(&e) => {
+                    let mid = from + (to - from) / 2;
+                    warn!("eth_getLogs {} → {} for {} exceeds the provider's result cap, splitting at {}",
+                        from, to, token_address, mid);
+                    pending.push((mid + 1, to));
+                    pending.push((from, mid));
                 }
-                let text = resp.text().await?;
-                info!("📩 Raw blockNumber response: {}", text);
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(logs)
+    }
+
+    /// One eth_getLogs call with `topics` as the filter's topic list, logs as
+    /// returned (missing logIndexes not yet filled)
+    async fn fetch_transfer_logs(
+        &self,
+        token_address: &str,
+        topics: &Value,
+        from_block: BlockId,
+        to_block: BlockId,
+    ) -> Result<Vec<Log>> {
+        let payload = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getLogs",
+            "params": [{
+                "fromBlock": from_block.to_param(),
+                "toBlock": to_block.to_param(),
+                "address": token_address,
+                "topics": topics
+            }]
+        });
+
+        info!(
+            "📡 Sending eth_getLogs → {} (range {} → {}, token {})",
+            self.url, from_block, to_block, token_address
+        );
+
+        let resp = self.client.post(&self.url).json(&payload).send().await?;
+        let text = resp.text().await?;
+        info!("📩 Raw getLogs response: {}", text);
+
+        parse_response(&text)
+    }
+
+    /// Send `calls` as JSON-RPC 2.0 batch requests (chunked by the batch size)
+    /// and return one result per call, in order. Transport failures fail the
+    /// whole batch; a provider error on a single call only fails that entry.
+    #[instrument(level = "debug", skip_all, fields(calls = calls.len()))]
+    pub async fn batch_call(&self, calls: Vec<(&str, Value)>) -> Result<Vec<Result<Value>>> {
+        self.guarded(self.send_batch(calls)).await
+    }
+
+    async fn send_batch(&self, calls: Vec<(&str, Value)>) -> Result<Vec<Result<Value>>> {
+        let mut results = Vec::with_capacity(calls.len());
+
+        for chunk in calls.chunks(self.batch_size) {
+            let payload: Vec<Value> = chunk
+                .iter()
+                .enumerate()
+                .map(|(id, (method, params))| json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": method,
+                    "params": params
+                }))
+                .collect();
+
+            info!("📡 Sending batch of {} calls → {}", chunk.len(), self.url);
 
-                let parsed: RpcResponse<String> = serde_json::from_str(&text)?;
-                let block_number =
-                    u64::from_str_radix(parsed.result.trim_start_matches("0x"), 16)?;
-                return Ok(block_number);
+            let resp = self.client.post(&self.url).json(&payload).send().await?;
+            if resp.status() != StatusCode::OK {
+                return Err(IndexerError::Rpc(format!("HTTP {}", resp.status())));
             }
-            Err(e) if attempt < 3 => {
-                eprintln!(
-                    "⚠️ RPC request failed (attempt {}): {}. Retrying...",
-                    attempt, e
-                );
-                tokio::time::sleep(Duration::from_secs(2)).await;
+            let text = resp.text().await?;
+
+            // providers without batch support answer with a single error object
+            let items: Vec<BatchItem> = match serde_json::from_str(&text) {
+                Ok(items) => items,
+                Err(_) => {
+                    parse_response::<Value>(&text)?;
+                    return Err(IndexerError::Decode(format!("expected a batch response: {}", text)));
+                }
+            };
+
+            // responses may arrive in any order; match them back up by id
+            let mut slots: Vec<Option<Result<Value>>> = (0..chunk.len()).map(|_| None).collect();
+            for item in items {
+                let Some(slot) = slots.get_mut(item.id as usize) else { continue };
+                *slot = Some(match item.error {
+                    Some(error) => Err(IndexerError::Rpc(format!("{}: {}", error.code, error.message))),
+                    None => Ok(item.result),
+                });
+            }
+            results.extend(slots.into_iter().enumerate().map(|(id, slot)| {
+                slot.unwrap_or_else(|| Err(IndexerError::Rpc(format!("no response for batch id {}", id))))
+            }));
+        }
+
+        Ok(results)
+    }
+
+    /// Read `name()`, `symbol()` and `decimals()` for an ERC20 token in one batch
+    #[instrument(level = "debug", skip_all, fields(token = %token_address))]
+    pub async fn get_token_metadata(&self, token_address: &str) -> Result<TokenMetadata> {
+        info!("📡 Fetching token metadata → {} (token {})", self.url, token_address);
+
+        let call = |selector: &str| ("eth_call", json!([{ "to": token_address, "data": selector }, "latest"]));
+        let mut results = self
+            .batch_call(vec![call(DECIMALS_SELECTOR), call(NAME_SELECTOR), call(SYMBOL_SELECTOR)])
+            .await?
+            .into_iter()
+            .map(|r| r.ok().and_then(|v| v.as_str().map(str::to_string)));
+        let (decimals, name, symbol) = (results.next().flatten(), results.next().flatten(), results.next().flatten());
+
+        let decimals = decimals
+            .as_deref()
+            .and_then(decode_abi_uint)
+            .ok_or_else(|| IndexerError::Decode(format!("decimals() result for {}: {:?}", token_address, decimals)))?;
+
+        Ok(TokenMetadata {
+            name: name.as_deref().and_then(decode_abi_string),
+            symbol: symbol.as_deref().and_then(decode_abi_string),
+            decimals,
+        })
+    }
+
+    /// Block timestamps (unix seconds) for `blocks`, fetched in one batch
+    /// round-trip; blocks the provider doesn't know are left out
+    pub async fn get_block_timestamps(&self, blocks: &[u64]) -> Result<HashMap<u64, u64>> {
+        let calls = blocks
+            .iter()
+            .map(|b| ("eth_getBlockByNumber", json!([format!("0x{:x}", b), false])))
+            .collect();
+
+        let mut timestamps = HashMap::new();
+        for (block, result) in blocks.iter().zip(self.batch_call(calls).await?) {
+            let ts = result?
+                .get("timestamp")
+                .and_then(Value::as_str)
+                .and_then(|hex| u64::from_str_radix(hex.trim_start_matches("0x"), 16).ok());
+            if let Some(ts) = ts {
+                timestamps.insert(*block, ts);
+            }
+        }
+        Ok(timestamps)
+    }
+
+    /// Set each log's `block_timestamp`, looking its blocks up together in
+    /// one batch round-trip. Removed logs are skipped. A block the provider
+    /// doesn't know is an error, so the range is retried rather than stored
+    /// without its time.
+    #[instrument(level = "debug", skip_all, fields(logs = logs.len()))]
+    pub async fn fill_block_timestamps(&self, logs: &mut [Log]) -> Result<()> {
+        let mut blocks: Vec<u64> = logs.iter().filter(|l| !l.removed).filter_map(|l| hex_u64(&l.block_number_hex)).collect();
+        blocks.sort_unstable();
+        blocks.dedup();
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let timestamps = self.get_block_timestamps(&blocks).await?;
+        for log in logs.iter_mut().filter(|l| !l.removed) {
+            let Some(block) = hex_u64(&log.block_number_hex) else { continue };
+            let ts = timestamps.get(&block).copied();
+            log.block_timestamp = Some(ts.ok_or_else(|| IndexerError::Decode(format!("no timestamp for block {}", block)))?);
+        }
+        Ok(())
+    }
+
+    /// Binary-search `[0, hi]` for the first block whose timestamp is at or
+    /// after `timestamp` (unix seconds); `None` if even block `hi` is older.
+    /// About log2(hi) single-block requests.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn first_block_at_or_after(&self, timestamp: u64, hi: u64) -> Result<Option<u64>> {
+        if self.block_timestamp(hi).await? < timestamp {
+            return Ok(None);
+        }
+        let (mut lo, mut hi) = (0, hi);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.block_timestamp(mid).await? >= timestamp {
+                hi = mid;
+            } else {
+                lo = mid + 1;
             }
-            Err(e) => return Err(eyre!("❌ RPC request failed after 3 retries: {}", e)),
-        }
-    }
-
-    Err(eyre!("Unreachable: retries exhausted"))
-}
-
-/// Fetch ERC20 Transfer logs for a token in a block range
-pub async fn get_transfer_logs(
-    rpc_url: &str,
-    token_address: &str,
-    from_block: u64,
-    to_block: u64,
-) -> Result<Vec<Log>> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(15))
-        .build()?;
-
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": 1,
-        "method": "eth_getLogs",
-        "params": [{
-            "fromBlock": format!("0x{:x}", from_block),
-            "toBlock": format!("0x{:x}", to_block),
-            "address": token_address,
-            "topics": [TRANSFER_TOPIC]
-        }]
-    });
-
-    info!(
-        "📡 Sending eth_getLogs → {} (range {} → {}, token {})",
-        rpc_url, from_block, to_block, token_address
-    );
-
-    let resp = client.post(rpc_url).json(&payload).send().await?;
-    let text = resp.text().await?;
-    info!("📩 Raw getLogs response: {}", text);
-
-    let parsed: RpcResponse<Vec<Log>> = serde_json::from_str(&text)?;
-    Ok(parsed.result)
+        }
+        Ok(Some(lo))
+    }
+
+    async fn block_timestamp(&self, block: u64) -> Result<u64> {
+        self.get_block_timestamps(&[block])
+            .await?
+            .remove(&block)
+            .ok_or_else(|| IndexerError::Decode(format!("no timestamp for block {}", block)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockBuilder, MockServer, ResponseTemplate};
+
+    fn rpc_ok(result: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({ "jsonrpc": "2.0", "id": 1, "result": result }))
+    }
+
+    fn topics() -> Vec<String> {
+        vec![TRANSFER_TOPIC.to_string()]
+    }
+
+    fn rpc_method(name: &str) -> MockBuilder {
+        Mock::given(method("POST")).and(body_partial_json(json!({ "method": name })))
+    }
+
+    /// First 24 bytes a client built from `http` sends: the HTTP/2 connection
+    /// preface is exactly that long, an HTTP/1.1 request line is longer
+    async fn first_bytes(http: HttpOptions) -> Vec<u8> {
+        use tokio::io::AsyncReadExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 24];
+            socket.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let rpc = RpcClient::with_http_options(&url, &http).unwrap();
+        assert!(rpc.get_chain_id().await.is_err()); // hung up without answering
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_uses_http2_only_when_configured() {
+        let http1 = first_bytes(HttpOptions::default()).await;
+        assert!(http1.starts_with(b"POST / HTTP/1.1"), "{}", String::from_utf8_lossy(&http1));
+
+        let http2 = first_bytes(HttpOptions { http2_prior_knowledge: true, ..HttpOptions::default() }).await;
+        assert_eq!(http2, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn get_block_number_parses_hex() {
+        let server = MockServer::start().await;
+        rpc_method("eth_blockNumber")
+            .respond_with(rpc_ok(json!("0x4a817c8")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        assert_eq!(rpc.get_block_number().await.unwrap(), 78_125_000);
+    }
+
+    #[tokio::test]
+    async fn get_chain_id_parses_hex() {
+        let server = MockServer::start().await;
+        rpc_method("eth_chainId")
+            .respond_with(rpc_ok(json!("0x89")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        assert_eq!(rpc.get_chain_id().await.unwrap(), 137);
+    }
+
+    #[tokio::test]
+    async fn tagged_block_number_and_log_range_use_the_tag() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "eth_getBlockByNumber", "params": ["finalized", false] })))
+            .respond_with(rpc_ok(json!({ "number": "0x4a817c8", "hash": "0xabc" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "eth_getBlockByNumber", "params": ["safe", false] })))
+            .respond_with(rpc_ok(json!(null)))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "eth_getLogs", "params": [{ "fromBlock": "0x10", "toBlock": "finalized" }] })))
+            .respond_with(rpc_ok(json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        assert_eq!(rpc.get_tagged_block_number(BlockTag::Finalized).await.unwrap(), 78_125_000);
+        // a provider without the block yet (or the tag) is an RPC error, not block 0
+        assert!(matches!(rpc.get_tagged_block_number(BlockTag::Safe).await, Err(IndexerError::Rpc(_))));
+
+        let token = "0x0000000000000000000000000000000000001010";
+        assert!(rpc.get_transfer_logs(token, &topics(), 16, BlockTag::Finalized).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_block_number_retries_on_server_error() {
+        let server = MockServer::start().await;
+        rpc_method("eth_blockNumber")
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        rpc_method("eth_blockNumber")
+            .respond_with(rpc_ok(json!("0x10")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        assert_eq!(rpc.get_block_number().await.unwrap(), 16);
+    }
+
+    #[tokio::test]
+    async fn get_transfer_logs_returns_logs() {
+        let server = MockServer::start().await;
+        rpc_method("eth_getLogs")
+            .respond_with(rpc_ok(json!([{
+                "address": "0x0000000000000000000000000000000000001010",
+                "topics": [
+                    TRANSFER_TOPIC,
+                    "0x000000000000000000000000f977814e90da44bfa03b6295a0616a897441acec",
+                    "0x000000000000000000000000e7804c37c13166ff0b37f5ae0bb07a3aebb6e245"
+                ],
+                "data": "0x0000000000000000000000000000000000000000000000000de0b6b3a7640000",
+                "blockNumber": "0x10",
+                "transactionHash": "0xabc",
+                "logIndex": "0x2"
+            }])))
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let logs = rpc.get_transfer_logs("0x0000000000000000000000000000000000001010", &topics(), 0, 16).await.unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].block_number_hex, "0x10");
+        assert_eq!(logs[0].log_index_hex, "0x2");
+    }
+
+    #[tokio::test]
+    async fn logs_without_log_index_get_a_synthesized_one() {
+        let server = MockServer::start().await;
+        let log = |block: &str, tx: &str| {
+            json!({
+                "address": "0x0000000000000000000000000000000000001010",
+                "topics": [
+                    TRANSFER_TOPIC,
+                    "0x000000000000000000000000f977814e90da44bfa03b6295a0616a897441acec",
+                    "0x000000000000000000000000e7804c37c13166ff0b37f5ae0bb07a3aebb6e245"
+                ],
+                "data": "0x0000000000000000000000000000000000000000000000000de0b6b3a7640000",
+                "blockNumber": block,
+                "transactionHash": tx
+            })
+        };
+        let mut indexed = log("0x10", "0xa");
+        indexed["logIndex"] = json!("0x4");
+        let mut null_index = log("0x11", "0xd");
+        null_index["logIndex"] = json!(null);
+        rpc_method("eth_getLogs")
+            .respond_with(rpc_ok(json!([indexed, log("0x10", "0xb"), log("0x10", "0xc"), null_index])))
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let logs = rpc.get_transfer_logs("0x0000000000000000000000000000000000001010", &topics(), 0, 17).await.unwrap();
+        let indexes: Vec<&str> = logs.iter().map(|l| l.log_index_hex.as_str()).collect();
+        // counted on past the block's real index, and per block
+        assert_eq!(indexes, ["0x4", "0x5", "0x6", "0x0"]);
+    }
+
+    #[tokio::test]
+    async fn get_transfer_logs_sends_topic_list() {
+        let server = MockServer::start().await;
+        rpc_method("eth_getLogs")
+            .and(body_partial_json(json!({
+                "params": [{ "topics": [[TRANSFER_TOPIC, APPROVAL_TOPIC]] }]
+            })))
+            .respond_with(rpc_ok(json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let topics = [TRANSFER_TOPIC.to_string(), APPROVAL_TOPIC.to_string()];
+        let logs = rpc.get_transfer_logs("0x0000000000000000000000000000000000001010", &topics, 0, 16).await.unwrap();
+        assert!(logs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn address_filtered_logs_query_each_side_in_batches() {
+        let server = MockServer::start().await;
+        let [a, b, c] = [1, 2, 3].map(|n| format!("0x{:064x}", n));
+        let log = |block: &str, tx: &str, index: &str, from: &str, to: &str| {
+            json!({
+                "address": "0x0000000000000000000000000000000000001010",
+                "topics": [TRANSFER_TOPIC, from, to],
+                "data": "0x01",
+                "blockNumber": block,
+                "transactionHash": tx,
+                "logIndex": index,
+            })
+        };
+        let both = log("0x11", "0xaa", "0x1", &a, &b); // exchange → exchange
+        let responses = [
+            (json!([[TRANSFER_TOPIC], [a, b]]), json!([both, log("0x10", "0xbb", "0x0", &a, "0x05")])),
+            (json!([[TRANSFER_TOPIC], null, [a, b]]), json!([both])),
+            (json!([[TRANSFER_TOPIC], [c]]), json!([])),
+            (json!([[TRANSFER_TOPIC], null, [c]]), json!([log("0x10", "0xcc", "0x1", "0x06", &c)])),
+        ];
+        for (topics, logs) in responses {
+            rpc_method("eth_getLogs")
+                .and(body_partial_json(json!({ "params": [{ "topics": topics }] })))
+                .respond_with(rpc_ok(logs))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let logs = rpc
+            .get_address_filtered_logs("0x0000000000000000000000000000000000001010", &topics(), &[a, b, c], 2, 0, 17)
+            .await
+            .unwrap();
+        let order: Vec<_> = logs.iter().map(|l| (l.tx_hash.as_str(), l.block_number_hex.as_str(), l.log_index_hex.as_str())).collect();
+        assert_eq!(order, [("0xbb", "0x10", "0x0"), ("0xcc", "0x10", "0x1"), ("0xaa", "0x11", "0x1")]);
+
+        // no wallets, no queries
+        assert!(rpc.get_address_filtered_logs("0x0000000000000000000000000000000000001010", &topics(), &[], 2, 0, 17)
+            .await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_transfer_logs_surfaces_error_envelope() {
+        let server = MockServer::start().await;
+        rpc_method("eth_getLogs")
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": { "code": -32005, "message": "query returned more than 10000 results" }
+            })))
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let err = rpc.get_transfer_logs("0x0000000000000000000000000000000000001010", &topics(), 0, 16).await.unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("-32005"), "{}", msg);
+        assert!(msg.contains("more than 10000 results"), "{}", msg);
+    }
+
+    /// Answers `eth_getLogs` with one log per block, but refuses ranges of
+    /// more than `max_blocks` blocks like a provider's result cap
+    struct CappedLogs {
+        max_blocks: u64,
+    }
+
+    impl wiremock::Respond for CappedLogs {
+        fn respond(&self, req: &wiremock::Request) -> ResponseTemplate {
+            let call: Value = serde_json::from_slice(&req.body).unwrap();
+            let block = |key: &str| u64::from_str_radix(call["params"][0][key].as_str().unwrap().trim_start_matches("0x"), 16).unwrap();
+            let (from, to) = (block("fromBlock"), block("toBlock"));
+            if to - from + 1 > self.max_blocks {
+                return ResponseTemplate::new(200).set_body_json(json!({
+                    "jsonrpc": "2.0", "id": call["id"],
+                    "error": { "code": -32005, "message": "query returned more than 10000 results" }
+                }));
+            }
+            let logs: Vec<Value> = (from..=to)
+                .map(|b| json!({
+                    "address": "0x0000000000000000000000000000000000001010",
+                    "topics": [TRANSFER_TOPIC],
+                    "data": "0x01",
+                    "blockNumber": format!("0x{:x}", b),
+                    "transactionHash": format!("0x{:064x}", b),
+                    "logIndex": "0x0",
+                }))
+                .collect();
+            rpc_ok(json!(logs))
+        }
+    }
+
+    #[tokio::test]
+    async fn result_capped_ranges_are_bisected_down_to_single_blocks() {
+        let server = MockServer::start().await;
+        rpc_method("eth_getLogs").respond_with(CappedLogs { max_blocks: 3 }).mount(&server).await;
+        // a threshold-1 breaker: the refusals must not count as failures
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        let rpc = RpcClient::new(&server.uri()).unwrap().with_breaker(breaker.clone());
+
+        let logs = rpc.get_transfer_logs("0x0000000000000000000000000000000000001010", &topics(), 100, 109).await.unwrap();
+        let blocks: Vec<u64> = logs.iter().map(|l| u64::from_str_radix(&l.block_number_hex[2..], 16).unwrap()).collect();
+        assert_eq!(blocks, (100..=109).collect::<Vec<_>>());
+        // 100–109 → 100–104 → (100–102, 103–104), 105–109 → (105–107, 108–109)
+        assert_eq!(server.received_requests().await.unwrap().len(), 7);
+        assert_eq!(breaker.health().state, BreakerState::Closed);
+
+        // the address-filtered queries are split the same way
+        let logs = rpc
+            .get_address_filtered_logs("0x0000000000000000000000000000000000001010", &topics(), &[format!("0x{:064x}", 1)], 10, 0, 4)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 5); // both sides return every block; merged once
+
+        // a single block over the cap can't be split further
+        server.reset().await;
+        rpc_method("eth_getLogs").respond_with(CappedLogs { max_blocks: 0 }).mount(&server).await;
+        let err = rpc.get_transfer_logs("0x0000000000000000000000000000000000001010", &topics(), 7, 8).await.unwrap_err();
+        assert!(is_result_cap(&err), "{err}");
+        assert_eq!(server.received_requests().await.unwrap().len(), 2); // 7–8, then 7
+    }
+
+    #[test]
+    fn recognises_result_cap_errors() {
+        for capped in [
+            "-32005: query returned more than 10000 results",
+            "-32602: Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range",
+            "-32000: block range is too wide",
+        ] {
+            assert!(is_result_cap(&IndexerError::Rpc(capped.into())), "{capped}");
+        }
+        assert!(!is_result_cap(&IndexerError::Rpc("-32005: daily request count exceeded, request rate limited".into())));
+        assert!(!is_result_cap(&IndexerError::Decode("too many results".into())));
+    }
+
+    /// ABI encoding of a dynamic `string` return value
+    fn abi_string(s: &str) -> String {
+        let mut out = format!("{:064x}{:064x}", 32, s.len());
+        let mut data = hex::encode(s);
+        data.push_str(&"0".repeat((64 - data.len() % 64) % 64));
+        out.push_str(&data);
+        format!("0x{}", out)
+    }
+
+    /// Any POST whose body is a JSON array
+    struct IsBatch;
+
+    impl wiremock::Match for IsBatch {
+        fn matches(&self, request: &wiremock::Request) -> bool {
+            serde_json::from_slice::<Value>(&request.body).map(|v| v.is_array()).unwrap_or(false)
+        }
+    }
+
+    fn batch() -> MockBuilder {
+        Mock::given(method("POST")).and(IsBatch)
+    }
+
+    #[tokio::test]
+    async fn batch_call_matches_out_of_order_responses_by_id() {
+        let server = MockServer::start().await;
+        batch()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "jsonrpc": "2.0", "id": 2, "result": "0xc" },
+                { "jsonrpc": "2.0", "id": 0, "result": "0xa" },
+                { "jsonrpc": "2.0", "id": 1, "error": { "code": -32000, "message": "header not found" } }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let results = rpc
+            .batch_call(vec![("a", json!([])), ("b", json!([])), ("c", json!([]))])
+            .await
+            .unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), "0xa");
+        assert!(results[1].as_ref().unwrap_err().to_string().contains("header not found"));
+        assert_eq!(results[2].as_ref().unwrap(), "0xc");
+    }
+
+    #[tokio::test]
+    async fn batch_call_chunks_by_batch_size() {
+        let server = MockServer::start().await;
+        batch()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "jsonrpc": "2.0", "id": 0, "result": "0x1" },
+                { "jsonrpc": "2.0", "id": 1, "result": "0x1" }
+            ])))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap().with_batch_size(2);
+        let calls = (0..4).map(|_| ("eth_chainId", json!([]))).collect();
+        let results = rpc.batch_call(calls).await.unwrap();
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|r| r.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn get_token_metadata_uses_one_batch() {
+        let server = MockServer::start().await;
+        batch()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "jsonrpc": "2.0", "id": 0, "result": format!("0x{:064x}", 6) },
+                { "jsonrpc": "2.0", "id": 1, "result": abi_string("USD Coin (PoS)") },
+                { "jsonrpc": "2.0", "id": 2, "result": abi_string("USDC") }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let meta = rpc.get_token_metadata("0x2791bca1f2de4661ed88a30c99a7a9449aa84174").await.unwrap();
+        assert_eq!(meta, TokenMetadata {
+            name: Some("USD Coin (PoS)".to_string()),
+            symbol: Some("USDC".to_string()),
+            decimals: 6,
+        });
+    }
+
+    #[tokio::test]
+    async fn get_token_metadata_tolerates_missing_name_and_symbol() {
+        let server = MockServer::start().await;
+        let reverted = json!({ "code": 3, "message": "execution reverted" });
+        batch()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "jsonrpc": "2.0", "id": 0, "result": format!("0x{:064x}", 18) },
+                { "jsonrpc": "2.0", "id": 1, "error": reverted },
+                { "jsonrpc": "2.0", "id": 2, "error": reverted }
+            ])))
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let meta = rpc.get_token_metadata("0x0000000000000000000000000000000000001010").await.unwrap();
+        assert_eq!(meta, TokenMetadata { name: None, symbol: None, decimals: 18 });
+    }
+
+    #[tokio::test]
+    async fn get_block_timestamps_batches_blocks() {
+        let server = MockServer::start().await;
+        batch()
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "jsonrpc": "2.0", "id": 0, "result": { "number": "0x10", "timestamp": "0x65000000" } },
+                { "jsonrpc": "2.0", "id": 1, "result": null }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+        let ts = rpc.get_block_timestamps(&[16, 17]).await.unwrap();
+        assert_eq!(ts, HashMap::from([(16, 0x65000000)]));
+    }
+
+    /// Answers `eth_getBlockByNumber` batches for a chain with a block every
+    /// 2s starting at unix time 1000
+    struct TwoSecondBlocks;
+
+    impl wiremock::Respond for TwoSecondBlocks {
+        fn respond(&self, req: &wiremock::Request) -> ResponseTemplate {
+            let calls: Vec<Value> = serde_json::from_slice(&req.body).unwrap();
+            let results: Vec<Value> = calls
+                .iter()
+                .map(|c| {
+                    let block = u64::from_str_radix(c["params"][0].as_str().unwrap().trim_start_matches("0x"), 16).unwrap();
+                    json!({ "jsonrpc": "2.0", "id": c["id"], "result": { "timestamp": format!("0x{:x}", 1000 + 2 * block) } })
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(results)
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_searches_block_by_timestamp() {
+        let server = MockServer::start().await;
+        batch().respond_with(TwoSecondBlocks).mount(&server).await;
+        let rpc = RpcClient::new(&server.uri()).unwrap();
+
+        assert_eq!(rpc.first_block_at_or_after(1000, 1_000_000).await.unwrap(), Some(0));
+        assert_eq!(rpc.first_block_at_or_after(1200, 1_000_000).await.unwrap(), Some(100));
+        // between blocks: the next one
+        assert_eq!(rpc.first_block_at_or_after(1201, 1_000_000).await.unwrap(), Some(101));
+        assert_eq!(rpc.first_block_at_or_after(1000 + 2 * 500, 500).await.unwrap(), Some(500));
+        assert_eq!(rpc.first_block_at_or_after(1000 + 2 * 501, 500).await.unwrap(), None);
+    }
+
+    #[test]
+    fn event_topics_match_their_signatures() {
+        for (topic, signature) in [
+            (TRANSFER_TOPIC, "Transfer(address,address,uint256)"),
+            (APPROVAL_TOPIC, "Approval(address,address,uint256)"),
+            (DEPOSIT_TOPIC, "Deposit(address,uint256)"),
+            (WITHDRAWAL_TOPIC, "Withdrawal(address,uint256)"),
+        ] {
+            assert_eq!(topic, alloy::primitives::keccak256(signature).to_string(), "{signature}");
+        }
+    }
+
+    #[test]
+    fn decodes_bytes32_string() {
+        let word = format!("0x{}{}", hex::encode("MKR"), "0".repeat(58));
+        assert_eq!(decode_abi_string(&word).as_deref(), Some("MKR"));
+        assert_eq!(decode_abi_string("0x"), None);
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_after_consecutive_failures_then_probes() {
+        let server = MockServer::start().await;
+        rpc_method("eth_chainId")
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2) // the third call must not reach the provider
+            .mount(&server)
+            .await;
+        rpc_method("eth_chainId")
+            .respond_with(rpc_ok(json!("0x89")))
+            .mount(&server)
+            .await;
+
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(200));
+        let rpc = RpcClient::new(&server.uri()).unwrap().with_breaker(breaker.clone());
+
+        assert!(matches!(rpc.get_chain_id().await, Err(IndexerError::Rpc(_))));
+        assert_eq!(breaker.health().state, BreakerState::Closed);
+        assert!(matches!(rpc.get_chain_id().await, Err(IndexerError::Rpc(_))));
+        assert_eq!(breaker.health().state, BreakerState::Open);
+        assert!(matches!(rpc.get_chain_id().await, Err(IndexerError::CircuitOpen(_))));
+
+        // after the cooldown one probe goes through and closes the circuit
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(breaker.health().state, BreakerState::HalfOpen);
+        assert_eq!(rpc.get_chain_id().await.unwrap(), 137);
+        let health = breaker.health();
+        assert_eq!((health.state, health.consecutive_failures), (BreakerState::Closed, 0));
+    }
 }